@@ -0,0 +1,160 @@
+use std::collections::{BTreeSet, HashSet};
+
+use serde::Serialize;
+
+use crate::api::data::DepartmentMember;
+use crate::api::dump::Dump;
+
+/// Data issues or permission gaps found by cross-checking members, departments and tags
+#[derive(Serialize, Debug, Default)]
+pub struct Anomalies {
+  /// Members whose `main_department` is not in their `department`
+  pub main_department_mismatch: Vec<MemberAnomaly>,
+  /// Members referencing departments which are not in the department listing
+  pub unknown_departments: Vec<MemberAnomaly>,
+  /// Tag members not found in any fetched department
+  pub unknown_tag_members: Vec<TagAnomaly>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MemberAnomaly {
+  #[serde(rename = "userid")]
+  pub user_id: String,
+  pub name: String,
+  /// The department ids in question
+  pub departments: Vec<u32>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct TagAnomaly {
+  #[serde(rename = "tagid")]
+  pub tag_id: u32,
+  #[serde(rename = "tagname")]
+  pub tag_name: String,
+  #[serde(rename = "userid")]
+  pub user_id: String,
+}
+
+impl Anomalies {
+  /// Cross-check a dump, unknown tag members are only reported when the members of
+  /// every department were fetched, since they could be in a failed one otherwise
+  pub fn find(dump: &Dump) -> Anomalies {
+    let mut anomalies = Anomalies::default();
+    let departments: HashSet<u32> = dump.departments.iter().map(|x| x.id).collect();
+    let mut seen = HashSet::new();
+    let members = dump
+      .members_by_department
+      .values()
+      .flatten()
+      .filter(|x| seen.insert(&*x.user_id));
+    for member in members {
+      if let Some(main) = member.main_department {
+        if !member.department.contains(&main) {
+          anomalies
+            .main_department_mismatch
+            .push(MemberAnomaly::new(member, vec![main]));
+        }
+      }
+      let unknown: BTreeSet<u32> = member
+        .department
+        .iter()
+        .chain(&member.main_department)
+        .filter(|id| !departments.contains(id))
+        .copied()
+        .collect();
+      if !unknown.is_empty() {
+        let unknown = unknown.into_iter().collect();
+        anomalies
+          .unknown_departments
+          .push(MemberAnomaly::new(member, unknown));
+      }
+    }
+
+    if dump.members_by_department.len() == dump.departments.len() {
+      for tag in &dump.tags {
+        let tag_members = dump.tag_members.get(&tag.id).into_iter().flatten();
+        for member in tag_members.filter(|x| !seen.contains(&*x.id)) {
+          anomalies.unknown_tag_members.push(TagAnomaly {
+            tag_id: tag.id,
+            tag_name: tag.name.clone(),
+            user_id: member.id.clone(),
+          });
+        }
+      }
+    }
+    anomalies
+  }
+}
+
+impl MemberAnomaly {
+  fn new(member: &DepartmentMember, departments: Vec<u32>) -> MemberAnomaly {
+    MemberAnomaly {
+      user_id: member.user_id.clone(),
+      name: member.name.clone(),
+      departments,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::anomalies::{Anomalies, MemberAnomaly};
+  use crate::api::data::{Department, DepartmentMember, Tag, TagMember};
+  use crate::api::dump::Dump;
+
+  fn member(user_id: &str, department: Vec<u32>, main_department: u32) -> DepartmentMember {
+    let overrides = json!({"department": department, "main_department": main_department});
+    DepartmentMember::test(user_id, overrides)
+  }
+
+  #[test]
+  fn find_anomalies_test() {
+    let department = Department {
+      id: 1,
+      name: "Company".to_string(),
+      parent_id: Some(0),
+      order: 0,
+      name_en: None,
+      department_leader: None,
+    };
+    let dump = Dump {
+      departments: vec![department],
+      members_by_department: BTreeMap::from([(
+        1,
+        vec![member("a", vec![1], 1), member("b", vec![1, 9], 2)]
+          .into_iter()
+          .map(Arc::new)
+          .collect(),
+      )]),
+      tags: vec![Tag {
+        id: 5,
+        name: "Tag".to_string(),
+      }],
+      tag_members: BTreeMap::from([(
+        5,
+        vec![TagMember {
+          id: "c".to_string(),
+          name: "C".to_string(),
+        }],
+      )]),
+      ..Default::default()
+    };
+    let anomalies = Anomalies::find(&dump);
+    assert_eq!(
+      anomalies.main_department_mismatch,
+      vec![MemberAnomaly {
+        user_id: "b".to_string(),
+        name: "b".to_string(),
+        departments: vec![2],
+      }]
+    );
+    assert_eq!(anomalies.unknown_departments[0].departments, vec![2, 9]);
+    assert_eq!(anomalies.unknown_tag_members.len(), 1);
+    assert_eq!(anomalies.unknown_tag_members[0].user_id, "c");
+  }
+}
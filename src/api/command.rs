@@ -0,0 +1,52 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use tokio::process::Command;
+
+/// Run `command` in the shell and read the access token from its stdout,
+/// stderr of the command is forwarded to logs
+pub async fn run_token_command(command: &str) -> Result<String> {
+  let output = shell(command)
+    .output()
+    .await
+    .with_context(|| format!("Failed to run token command: {command}"))?;
+  for line in String::from_utf8_lossy(&output.stderr).lines() {
+    info!("Token command: {line}");
+  }
+  if !output.status.success() {
+    bail!("Token command exited with {}", output.status);
+  }
+  let token = String::from_utf8(output.stdout).context("Token command printed invalid UTF-8")?;
+  let token = token.trim();
+  if token.is_empty() {
+    bail!("Token command printed an empty token");
+  }
+  Ok(token.to_string())
+}
+
+/// `command` run by the shell of the platform
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+  let mut shell = Command::new("sh");
+  shell.arg("-c").arg(command);
+  shell
+}
+
+#[cfg(windows)]
+pub fn shell(command: &str) -> Command {
+  let mut shell = Command::new("cmd");
+  shell.arg("/C").arg(command);
+  shell
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use crate::api::command::run_token_command;
+
+  #[tokio::test]
+  async fn run_token_command_test() {
+    let token = run_token_command("echo message >&2; echo ' abc '").await;
+    assert_eq!(token.unwrap(), "abc");
+    assert!(run_token_command("echo abc; exit 1").await.is_err());
+    assert!(run_token_command("true").await.is_err());
+  }
+}
@@ -1,12 +1,149 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 
-use serde::{Deserialize, Serialize};
+use itertools::Itertools;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
 
 pub trait Success {
   fn is_success(&self) -> bool;
 }
 
+/// Common part of every response, to check `errcode` before deserializing the whole body
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorResp {
+  #[serde(rename = "errcode")]
+  pub code: Option<i32>,
+  #[serde(rename = "errmsg")]
+  pub msg: Option<String>,
+}
+
+/// A response with non-zero `errcode`
+#[derive(Debug, Clone)]
+pub struct ApiError {
+  pub code: i32,
+  pub msg: String,
+}
+
+impl Display for ApiError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "errcode {}: {}", self.code, self.msg)
+  }
+}
+
+impl Error for ApiError {}
+
+impl ApiError {
+  /// System is busy, retry later
+  pub const SYSTEM_BUSY: i32 = -1;
+  /// Invalid secret or access token
+  pub const INVALID_CREDENTIAL: i32 = 40001;
+  /// Invalid corp id
+  pub const INVALID_CORP_ID: i32 = 40013;
+  /// Invalid secret
+  pub const INVALID_SECRET: i32 = 40091;
+  /// Missing access token
+  pub const MISSING_ACCESS_TOKEN: i32 = 41001;
+  /// Missing corp id
+  pub const MISSING_CORP_ID: i32 = 41002;
+  /// Missing secret
+  pub const MISSING_SECRET: i32 = 41004;
+  /// Access token expired
+  pub const ACCESS_TOKEN_EXPIRED: i32 = 42001;
+  /// API frequency out of limit
+  pub const FREQUENCY_LIMITED: i32 = 45009;
+  /// No permission to access the department or member
+  pub const NO_PRIVILEGE: i32 = 60011;
+  /// No permission to access the specified app
+  pub const NO_AGENT_PERMISSION: i32 = 301002;
+  /// No permission to access the external contacts of the user
+  pub const NO_EXTERNAL_CONTACT_PERMISSION: i32 = 84061;
+
+  /// Whether it is worth to retry the request
+  pub fn is_transient(&self) -> bool {
+    matches!(self.code, Self::SYSTEM_BUSY | Self::FREQUENCY_LIMITED)
+  }
+
+  /// Whether the corp id or secret is rejected
+  pub fn is_credential_error(&self) -> bool {
+    matches!(
+      self.code,
+      Self::INVALID_CREDENTIAL
+        | Self::INVALID_CORP_ID
+        | Self::INVALID_SECRET
+        | Self::MISSING_CORP_ID
+        | Self::MISSING_SECRET
+    )
+  }
+
+  /// Whether the app is not allowed to access the resource
+  pub fn is_permission_error(&self) -> bool {
+    matches!(
+      self.code,
+      Self::NO_PRIVILEGE | Self::NO_AGENT_PERMISSION | Self::NO_EXTERNAL_CONTACT_PERMISSION
+    )
+  }
+
+  /// What to do about a misconfiguration, for the errors a first-time user is likely to hit
+  pub fn hint(&self) -> Option<&'static str> {
+    let hint = match self.code {
+      Self::MISSING_CORP_ID => "Missing corp id, did you set --corp-id or WX_CORP_ID?",
+      Self::INVALID_CORP_ID => {
+        "Invalid corp id, check --corp-id or WX_CORP_ID against the one starting with `ww` \
+         in My Company (我的企业) of the admin console"
+      }
+      Self::MISSING_SECRET => "Missing secret, did you set --corp-secret or WX_CORP_SECRET?",
+      Self::MISSING_ACCESS_TOKEN => {
+        "Missing access token, did you set --corp-token or WX_CORP_TOKEN, or does \
+         --token-command print an empty line?"
+      }
+      _ => return None,
+    };
+    Some(hint)
+  }
+
+  /// Add the [ApiError::hint] of the [ApiError] in the chain of `err` to it, if any
+  pub fn with_hint(err: anyhow::Error) -> anyhow::Error {
+    match ApiError::find(&err).and_then(ApiError::hint) {
+      Some(hint) => err.context(hint),
+      None => err,
+    }
+  }
+
+  /// Find the [ApiError] in the chain of `err`
+  pub fn find(err: &anyhow::Error) -> Option<&ApiError> {
+    err.chain().find_map(|err| err.downcast_ref::<ApiError>())
+  }
+}
+
+/// A response which is not JSON or has an error status, like an HTML page from a proxy
+#[derive(Debug, Clone)]
+pub struct UnexpectedResponse {
+  pub status: u16,
+  pub content_type: Option<String>,
+  /// The beginning of the body
+  pub snippet: String,
+}
+
+impl Display for UnexpectedResponse {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let content_type = self
+      .content_type
+      .as_deref()
+      .unwrap_or("unknown content type");
+    write!(
+      f,
+      "unexpected response with HTTP {} ({content_type}): {}",
+      self.status, self.snippet
+    )
+  }
+}
+
+impl Error for UnexpectedResponse {}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetTokenResp {
   #[serde(rename = "errcode")]
@@ -23,7 +160,7 @@ impl Success for GetTokenResp {
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct AgentListResp {
   #[serde(rename = "errcode")]
   pub code: Option<i32>,
@@ -33,7 +170,14 @@ pub struct AgentListResp {
   pub agent_list: Vec<AgentBasic>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl AgentListResp {
+  /// Sort agents by id, for stable output
+  pub fn sort(&mut self) {
+    self.agent_list.sort_by_key(|x| x.id);
+  }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct AgentBasic {
   #[serde(rename = "agentid")]
   pub id: u32,
@@ -42,7 +186,7 @@ pub struct AgentBasic {
   pub round_logo_url: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct AgentDetail {
   #[serde(rename = "errcode")]
   pub code: Option<i32>,
@@ -66,30 +210,30 @@ pub struct AgentDetail {
   pub publish_status: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct AllowUserInfos {
   pub user: Vec<User>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct User {
   #[serde(rename = "userid")]
   pub user_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct AllowParties {
   #[serde(rename = "partyid")]
   pub party_id: Vec<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct AllowTags {
   #[serde(rename = "tagid")]
   pub tag_id: Vec<u32>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct DepartmentResp {
   #[serde(rename = "errcode")]
   pub code: Option<i32>,
@@ -99,16 +243,27 @@ pub struct DepartmentResp {
   pub departments: Vec<Department>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl DepartmentResp {
+  /// Sort departments by id, for stable output
+  pub fn sort(&mut self) {
+    self.departments.sort_by_key(|x| x.id);
+  }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Department {
   pub id: u32,
   pub name: String,
   #[serde(rename = "parentid")]
   pub parent_id: Option<u32>,
   pub order: u32,
+  #[serde(default)]
+  pub name_en: Option<String>,
+  #[serde(default)]
+  pub department_leader: Option<Vec<String>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct DepartmentMembersResp {
   #[serde(rename = "errcode")]
   pub code: Option<i32>,
@@ -118,7 +273,75 @@ pub struct DepartmentMembersResp {
   pub members: Vec<DepartmentMember>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl DepartmentMembersResp {
+  /// Sort members by user id, for stable output
+  pub fn sort(&mut self) {
+    self.members.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+  }
+
+  /// Remove the disabled and resigned members, returns their user ids
+  pub fn retain_active(&mut self) -> Vec<String> {
+    let (active, inactive) = self
+      .members
+      .drain(..)
+      .partition(DepartmentMember::is_active);
+    self.members = active;
+    inactive.into_iter().map(|x| x.user_id).collect()
+  }
+}
+
+/// [DepartmentMembersResp] deserialized member by member, for `--lenient`
+#[derive(Deserialize, Debug)]
+pub struct LenientMembersResp {
+  #[serde(rename = "errcode")]
+  pub code: Option<i32>,
+  #[serde(rename = "errmsg")]
+  pub msg: Option<String>,
+  #[serde(rename = "userlist", deserialize_with = "lenient")]
+  pub members: Vec<Result<DepartmentMember, Rejected>>,
+}
+
+impl LenientMembersResp {
+  /// Split into the response of members deserialized and the rejected ones
+  pub fn split(self) -> (DepartmentMembersResp, Vec<Rejected>) {
+    let (members, rejected) = self.members.into_iter().partition_result();
+    let resp = DepartmentMembersResp {
+      code: self.code,
+      msg: self.msg,
+      members,
+    };
+    (resp, rejected)
+  }
+}
+
+/// An element of a response which failed to deserialize, kept as is
+#[derive(Serialize, Debug)]
+pub struct Rejected {
+  pub error: String,
+  pub value: Value,
+}
+
+/// Deserialize the elements of an array one by one, so elements of an unexpected shape are
+/// rejected on their own instead of failing the whole array
+fn lenient<'de, D, T>(deserializer: D) -> Result<Vec<Result<T, Rejected>>, D::Error>
+where
+  D: Deserializer<'de>,
+  T: DeserializeOwned,
+{
+  let values = Vec::<Value>::deserialize(deserializer)?;
+  let result = values
+    .into_iter()
+    .map(|value| match T::deserialize(&value) {
+      Ok(x) => Ok(x),
+      Err(err) => Err(Rejected {
+        error: err.to_string(),
+        value,
+      }),
+    });
+  Ok(result.collect())
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct DepartmentMember {
   pub name: String,
   pub department: Vec<u32>,
@@ -143,10 +366,132 @@ pub struct DepartmentMember {
   pub biz_mail: Option<String>,
   #[serde(rename = "userid")]
   pub user_id: String,
-  pub extattr: HashMap<String, Value>,
+  /// Sorted by key, so the output is the same in every run
+  pub extattr: BTreeMap<String, Value>,
+  /// Full name paths of `department`, like `Company/Engineering/Backend`,
+  /// only filled with `--annotate-departments`
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub department_path: Option<Vec<String>>,
+  /// Whether the member leads each of `department`, from `is_leader_in_dept`
+  #[serde(default)]
+  pub leadership: Vec<Leadership>,
+  /// Values of the contact fields before `--normalize-contacts`, only the changed ones
+  #[serde(rename = "_raw", default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub raw: BTreeMap<String, String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct Leadership {
+  pub department_id: u32,
+  pub is_leader: bool,
+}
+
+impl DepartmentMember {
+  /// `status` of a disabled member
+  pub const STATUS_DISABLED: u32 = 2;
+  /// `status` of a resigned member
+  pub const STATUS_RESIGNED: u32 = 5;
+
+  /// Neither disabled nor resigned, members not activated yet are still active
+  pub fn is_active(&self) -> bool {
+    self.enable != 0
+      && self.status != DepartmentMember::STATUS_DISABLED
+      && self.status != DepartmentMember::STATUS_RESIGNED
+  }
+
+  /// Trim the contact fields, lowercase the emails and strip the `+86` prefix and separators
+  /// of mobiles, the values changed are kept in `raw`
+  pub fn normalize_contacts(&mut self) {
+    let mut normalize = |name: &str, value: &mut String, f: fn(&str) -> String| {
+      let normalized = f(value);
+      if normalized != *value {
+        let raw = std::mem::replace(value, normalized);
+        // a raw value recorded before is kept, like the one of a member restored from a
+        // checkpoint and normalized again
+        self.raw.entry(name.to_string()).or_insert(raw);
+      }
+    };
+    normalize("mobile", &mut self.mobile, normalize_mobile);
+    normalize("telephone", &mut self.telephone, |x| x.trim().to_string());
+    normalize("email", &mut self.email, |x| x.trim().to_lowercase());
+    if let Some(biz_mail) = &mut self.biz_mail {
+      normalize("biz_mail", biz_mail, |x| x.trim().to_lowercase());
+    }
+  }
+}
+
+#[cfg(test)]
+impl DepartmentMember {
+  /// An active member in department 1 as returned by `user/list`, with the fields in
+  /// `overrides` replaced
+  pub fn test_json(user_id: &str, overrides: Value) -> Value {
+    let mut value = serde_json::json!({
+      "userid": user_id, "name": user_id, "department": [1], "position": "", "mobile": "",
+      "gender": "", "email": "", "avatar": "", "isleader": 0, "status": 1, "enable": 1,
+      "hide_mobile": 0, "english_name": "", "telephone": "", "order": [], "qr_code": "",
+      "alias": "", "is_leader_in_dept": [], "thumb_avatar": "", "extattr": {},
+    });
+    let Value::Object(overrides) = overrides else {
+      panic!("overrides should be an object: {overrides}");
+    };
+    for (key, x) in overrides {
+      value[key] = x;
+    }
+    value
+  }
+
+  /// [DepartmentMember::test_json] deserialized
+  pub fn test(user_id: &str, overrides: Value) -> DepartmentMember {
+    serde_json::from_value(DepartmentMember::test_json(user_id, overrides)).unwrap()
+  }
+}
+
+/// Mainland mobiles become the bare 11 digits like the API returns, others keep their country
+/// code with the separators removed, like `+85291234567`
+fn normalize_mobile(mobile: &str) -> String {
+  let compact: String = mobile
+    .chars()
+    .filter(|x| !x.is_whitespace() && !matches!(x, '-' | '(' | ')'))
+    .collect();
+  let compact = match compact.strip_prefix("00") {
+    Some(rest) => format!("+{rest}"),
+    None => compact,
+  };
+  match compact.strip_prefix("+86") {
+    Some(rest) if rest.len() == 11 && rest.starts_with('1') => rest.to_string(),
+    _ => compact,
+  }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct SimpleMembersResp {
+  #[serde(rename = "errcode")]
+  pub code: Option<i32>,
+  #[serde(rename = "errmsg")]
+  pub msg: Option<String>,
+  #[serde(rename = "userlist")]
+  pub members: Vec<SimpleMember>,
+}
+
+impl SimpleMembersResp {
+  /// Sort members by user id, for stable output
+  pub fn sort(&mut self) {
+    self.members.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+  }
+}
+
+/// Member returned by `user/simplelist`, for apps without the permission of `user/list`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct SimpleMember {
+  #[serde(rename = "userid")]
+  pub user_id: String,
+  pub name: String,
+  pub department: Vec<u32>,
+  #[serde(default)]
+  pub open_userid: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct TagsResp {
   #[serde(rename = "errcode")]
   pub code: Option<i32>,
@@ -156,7 +501,14 @@ pub struct TagsResp {
   pub tags: Vec<Tag>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl TagsResp {
+  /// Sort tags by id, for stable output
+  pub fn sort(&mut self) {
+    self.tags.sort_by_key(|x| x.id);
+  }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Tag {
   #[serde(rename = "tagid")]
   pub id: u32,
@@ -164,7 +516,7 @@ pub struct Tag {
   pub name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
 pub struct TagMembersResp {
   #[serde(rename = "errcode")]
   pub code: Option<i32>,
@@ -172,15 +524,192 @@ pub struct TagMembersResp {
   pub msg: Option<String>,
   #[serde(rename = "userlist")]
   pub members: Vec<TagMember>,
+  /// Departments in the tag as a whole
   #[serde(rename = "partylist")]
-  department_list: Vec<u32>,
+  pub department_list: Vec<u32>,
   #[serde(rename = "tagname")]
-  tag_name: String,
+  pub tag_name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl TagMembersResp {
+  /// Sort members by user id and departments by id, for stable output
+  pub fn sort(&mut self) {
+    self.members.sort_by(|a, b| a.id.cmp(&b.id));
+    self.department_list.sort_unstable();
+  }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct TagMember {
   #[serde(rename = "userid")]
-  id: String,
-  name: String,
+  pub id: String,
+  pub name: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ExternalContactListResp {
+  #[serde(rename = "errcode")]
+  pub code: Option<i32>,
+  #[serde(rename = "errmsg")]
+  pub msg: Option<String>,
+  #[serde(rename = "external_userid", default)]
+  pub external_user_ids: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ExternalContactDetailResp {
+  #[serde(rename = "errcode")]
+  pub code: Option<i32>,
+  #[serde(rename = "errmsg")]
+  pub msg: Option<String>,
+  pub external_contact: ExternalContact,
+  #[serde(default)]
+  pub follow_user: Vec<FollowUser>,
+  pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ExternalContact {
+  #[serde(rename = "external_userid")]
+  pub external_user_id: String,
+  pub name: String,
+  pub position: Option<String>,
+  pub avatar: Option<String>,
+  pub corp_name: Option<String>,
+  pub corp_full_name: Option<String>,
+  #[serde(rename = "type")]
+  pub contact_type: Option<u32>,
+  pub gender: Option<u32>,
+  #[serde(rename = "unionid")]
+  pub union_id: Option<String>,
+  pub external_profile: Option<Value>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+pub struct FollowUser {
+  #[serde(rename = "userid")]
+  pub user_id: String,
+  pub remark: Option<String>,
+  pub description: Option<String>,
+  #[serde(rename = "createtime")]
+  pub create_time: Option<u64>,
+  #[serde(default)]
+  pub tags: Vec<Value>,
+  pub remark_corp_name: Option<String>,
+  #[serde(default)]
+  pub remark_mobiles: Vec<String>,
+  pub oper_userid: Option<String>,
+  pub add_way: Option<u32>,
+  pub state: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+
+  use crate::api::data::{ApiError, DepartmentMember, DepartmentMembersResp, LenientMembersResp};
+
+  fn member(user_id: &str, status: u32, enable: u32) -> Value {
+    DepartmentMember::test_json(user_id, json!({"status": status, "enable": enable}))
+  }
+
+  #[test]
+  fn normalize_contacts_test() {
+    let mut value = member("a", 1, 1);
+    value["mobile"] = json!(" +86 138-0013-8000 ");
+    value["email"] = json!("Alice@Example.COM ");
+    value["telephone"] = json!("010-1234");
+    let mut member: DepartmentMember = serde_json::from_value(value).unwrap();
+    member.normalize_contacts();
+    assert_eq!(member.mobile, "13800138000");
+    assert_eq!(member.email, "alice@example.com");
+    assert_eq!(member.telephone, "010-1234");
+    assert_eq!(member.raw["mobile"], " +86 138-0013-8000 ");
+    assert_eq!(member.raw["email"], "Alice@Example.COM ");
+    assert!(!member.raw.contains_key("telephone"));
+    // the raw values of the first pass are kept
+    member.normalize_contacts();
+    assert_eq!(member.raw.len(), 2);
+    let json = serde_json::to_value(&member).unwrap();
+    assert_eq!(json["_raw"]["mobile"], " +86 138-0013-8000 ");
+
+    for (mobile, expected) in [
+      ("0086 13800138000", "13800138000"),
+      ("+852 9123 4567", "+85291234567"),
+      ("+86 10 1234", "+86101234"),
+      ("", ""),
+    ] {
+      assert_eq!(super::normalize_mobile(mobile), expected);
+    }
+  }
+
+  #[test]
+  fn retain_active_test() {
+    let mut resp: DepartmentMembersResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok",
+      "userlist": [
+        member("a", 1, 1), member("b", 2, 1), member("c", 4, 1), member("d", 5, 1),
+        member("e", 1, 0),
+      ],
+    }))
+    .unwrap();
+    assert_eq!(resp.retain_active(), ["b", "d", "e"]);
+    let active: Vec<&str> = resp.members.iter().map(|x| &*x.user_id).collect();
+    assert_eq!(active, ["a", "c"]);
+  }
+
+  #[test]
+  fn lenient_test() {
+    let mut bad = member("b", 1, 1);
+    bad["status"] = json!("active");
+    let resp: LenientMembersResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok", "userlist": [member("a", 1, 1), bad, 1],
+    }))
+    .unwrap();
+    let (resp, rejected) = resp.split();
+    assert_eq!(resp.members.len(), 1);
+    assert_eq!(resp.members[0].user_id, "a");
+    assert_eq!(rejected.len(), 2);
+    assert_eq!(rejected[0].value["userid"], "b");
+    assert!(
+      rejected[0].error.contains("invalid type"),
+      "{}",
+      rejected[0].error
+    );
+    assert_eq!(rejected[1].value, 1);
+  }
+
+  #[test]
+  fn extattr_order_test() {
+    let mut value = member("a", 1, 1);
+    value["extattr"] = json!({"work_id": 1, "attrs": [], "badge": "x", "level": 2, "desk": "A1"});
+    // every deserialization of a hash map has its own random order
+    let serialize = || {
+      let member: DepartmentMember = serde_json::from_value(value.clone()).unwrap();
+      serde_json::to_vec_pretty(&member).unwrap()
+    };
+    let first = serialize();
+    for _ in 0..8 {
+      assert_eq!(first, serialize());
+    }
+    let member: Value = serde_json::from_slice(&first).unwrap();
+    let keys: Vec<&String> = member["extattr"].as_object().unwrap().keys().collect();
+    assert_eq!(keys, ["attrs", "badge", "desk", "level", "work_id"]);
+  }
+
+  #[test]
+  fn hint_test() {
+    let err = |code| ApiError {
+      code,
+      msg: "error".to_string(),
+    };
+    let hinted = ApiError::with_hint(anyhow::Error::new(err(ApiError::MISSING_CORP_ID)));
+    assert!(hinted.to_string().contains("WX_CORP_ID"), "{hinted}");
+    assert_eq!(
+      ApiError::find(&hinted).unwrap().code,
+      ApiError::MISSING_CORP_ID
+    );
+    let plain = ApiError::with_hint(anyhow::Error::new(err(ApiError::NO_PRIVILEGE)));
+    assert_eq!(plain.to_string(), "errcode 60011: error");
+  }
 }
@@ -0,0 +1,1831 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::stream::{self, FuturesUnordered, Stream, StreamExt};
+use itertools::Itertools;
+use log::{error, info, warn};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinError;
+use tokio::time::{sleep, timeout};
+use tokio::{select, spawn};
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, Instrument};
+
+use crate::api::data::{
+  AgentBasic, AgentDetail, AgentListResp, ApiError, Department, DepartmentMember,
+  DepartmentMembersResp, DepartmentResp, Leadership, Rejected, SimpleMember, SimpleMembersResp,
+  Tag, TagMember, TagMembersResp, TagsResp,
+};
+use crate::api::WxClient;
+
+#[derive(Debug, Clone)]
+pub struct DumpOptions {
+  /// Fetch the agent list and the details of each agent
+  pub agents: bool,
+  /// Fetch the members of child departments too
+  pub recursive: bool,
+  /// Fetch the members of child departments only for top-level departments, so a member
+  /// is listed in its own department and its top-level one instead of every ancestor
+  pub recursive_root_only: bool,
+  /// Fill the department name paths of each member
+  pub annotate_departments: bool,
+  /// Canonicalize the mobiles and emails of members, see [DepartmentMember::normalize_contacts]
+  pub normalize_contacts: bool,
+  /// Only fetch the first departments and tags sorted by id, for sampling
+  pub limit: Option<usize>,
+  /// Only fetch the departments whose name matches
+  pub department_name_filter: Option<Regex>,
+  /// Only fetch the departments with these ids
+  pub department_ids: Option<Vec<u32>>,
+  /// Only fetch the tags whose name matches
+  pub tag_name_filter: Option<Regex>,
+  /// Only fetch the tags with these ids
+  pub tag_ids: Option<Vec<u32>>,
+  /// Drop the disabled and resigned members of departments before they are observed
+  pub active_only: bool,
+  /// Fetch only the user id, name and departments of members from the simple list,
+  /// instead of the full details
+  pub minimal_members: bool,
+  /// Deserialize members one by one, and keep the ones failed aside instead of failing
+  /// the whole department
+  pub lenient: bool,
+  /// Restore the departments and tags in the checkpoint of an interrupted run instead of
+  /// fetching them again
+  pub resume: Option<Arc<Checkpoint>>,
+  /// Abort the dump on the first failed job or item
+  pub fail_fast: Option<FailFast>,
+  /// Skip a department or subtree whose members are not fetched in time, including retries
+  pub item_timeout: Option<Duration>,
+  /// Fail the departments whose members look truncated by the API instead of only flagging
+  /// them, see [SUSPECTED_MEMBER_CAP]
+  pub strict_completeness: bool,
+  pub delay: Duration,
+  pub department_delay: Option<Duration>,
+  pub tag_delay: Option<Duration>,
+  pub department_concurrency: Option<u32>,
+  pub tag_concurrency: Option<u32>,
+}
+
+impl Default for DumpOptions {
+  fn default() -> Self {
+    DumpOptions {
+      agents: true,
+      recursive: false,
+      recursive_root_only: false,
+      annotate_departments: false,
+      normalize_contacts: false,
+      limit: None,
+      department_name_filter: None,
+      department_ids: None,
+      tag_name_filter: None,
+      tag_ids: None,
+      active_only: false,
+      minimal_members: false,
+      lenient: false,
+      resume: None,
+      fail_fast: None,
+      item_timeout: None,
+      strict_completeness: false,
+      delay: Duration::from_millis(200),
+      department_delay: None,
+      tag_delay: None,
+      department_concurrency: None,
+      tag_concurrency: None,
+    }
+  }
+}
+
+impl DumpOptions {
+  /// Whether to fetch the members of child departments of `department`, where `ids` are
+  /// all visible departments, the ones whose parent is not visible are top-level
+  fn fetch_child(&self, department: &Department, ids: &HashSet<u32>) -> bool {
+    self.recursive
+      || (self.recursive_root_only && !department.parent_id.is_some_and(|i| ids.contains(&i)))
+  }
+
+  /// Log a failed item, and abort the dump with it under `--fail-fast`
+  fn failed(&self, message: String, err: anyhow::Error) {
+    error!("{message}: {err:?}");
+    if let Some(fail_fast) = &self.fail_fast {
+      fail_fast.fail(format!("{message}: {err:#}"));
+    }
+  }
+}
+
+/// Abort a dump on the first failure, in-flight requests are cancelled and no more are sent
+#[derive(Debug, Clone, Default)]
+pub struct FailFast {
+  token: CancellationToken,
+  /// The failure which aborted the dump
+  first: Arc<Mutex<Option<String>>>,
+}
+
+impl FailFast {
+  fn fail(&self, err: String) {
+    let mut first = self.first.lock().unwrap_or_else(PoisonError::into_inner);
+    if first.is_none() {
+      *first = Some(err);
+      self.token.cancel();
+    }
+  }
+
+  fn error(&self) -> Option<String> {
+    self
+      .first
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone()
+  }
+}
+
+/// Everything fetched by [WxClient::dump_all]
+#[derive(Serialize, Debug, Default)]
+pub struct Dump {
+  pub agents: Vec<AgentBasic>,
+  pub agent_details: BTreeMap<u32, AgentDetail>,
+  pub departments: Vec<Department>,
+  /// Members of each department which were fetched successfully, keyed by department id, a
+  /// member split from a subtree into several departments is kept once
+  pub members_by_department: BTreeMap<u32, Vec<Arc<DepartmentMember>>>,
+  /// Members of the departments without the permission of full details,
+  /// fetched from the simple list instead, keyed by department id
+  pub simple_members_by_department: BTreeMap<u32, Vec<SimpleMember>>,
+  pub tags: Vec<Tag>,
+  /// Members of each tag which were fetched successfully, keyed by tag id
+  pub tag_members: BTreeMap<u32, Vec<TagMember>>,
+  /// Departments in each tag as a whole, keyed by tag id
+  pub tag_departments: BTreeMap<u32, Vec<u32>>,
+  /// Departments whose members are not visible to the app at all, which are
+  /// not counted as failures
+  pub no_permission_departments: Vec<u32>,
+  /// Departments skipped after [DumpOptions::item_timeout], which are counted as failures
+  pub timed_out_departments: Vec<u32>,
+  /// Departments whose members are possibly truncated by the API, see [SUSPECTED_MEMBER_CAP]
+  pub truncated_departments: Vec<u32>,
+  /// User ids of the members dropped by [DumpOptions::active_only]
+  #[serde(skip)]
+  pub inactive_members: BTreeSet<String>,
+  /// Jobs which failed as a whole, their fields above are left empty
+  #[serde(skip)]
+  pub failed_jobs: Vec<Job>,
+}
+
+/// Members fetched so far, saved while dumping so an interrupted run can be resumed
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Checkpoint {
+  pub members_by_department: BTreeMap<u32, Vec<DepartmentMember>>,
+  pub simple_members_by_department: BTreeMap<u32, Vec<SimpleMember>>,
+  pub tag_members: BTreeMap<u32, Vec<TagMember>>,
+  pub tag_departments: BTreeMap<u32, Vec<u32>>,
+}
+
+impl Checkpoint {
+  /// Members of a department in the checkpoint, the inactive ones dropped are not kept
+  fn department(&self, id: u32) -> Option<Members> {
+    if let Some(members) = self.members_by_department.get(&id) {
+      let members = members.iter().cloned().map(Arc::new).collect();
+      return Some(Members::Full {
+        members,
+        inactive: Vec::new(),
+        truncated: false,
+      });
+    }
+    let members = self.simple_members_by_department.get(&id)?.clone();
+    Some(Members::Simple {
+      members,
+      truncated: false,
+    })
+  }
+
+  /// Members and departments of a tag in the checkpoint
+  fn tag(&self, id: u32) -> Option<(Vec<TagMember>, Vec<u32>)> {
+    let members = self.tag_members.get(&id)?;
+    let departments = self.tag_departments.get(&id)?;
+    Some((members.clone(), departments.clone()))
+  }
+}
+
+/// A department annotated with its member counts
+#[derive(Serialize, Debug)]
+pub struct DepartmentCount<'a> {
+  #[serde(flatten)]
+  pub department: &'a Department,
+  /// Members directly in the department, [None] if its members failed to fetch
+  pub member_count: Option<usize>,
+  /// Members in the department or any of its descendants, counted once each
+  pub recursive_member_count: usize,
+}
+
+/// A member of any of the tags, with the tags it is in
+#[derive(Serialize, Debug)]
+pub struct TagUnionMember<'a> {
+  #[serde(flatten)]
+  pub member: &'a TagMember,
+  pub tags: Vec<&'a Tag>,
+}
+
+impl Dump {
+  /// Entries of [Dump::members_by_department] sharing their member with an entry before them,
+  /// like the ones split from a subtree by [DumpOptions::recursive] into every ancestor
+  pub fn shared_member_entries(&self) -> usize {
+    let entries = self.members_by_department.values().flatten();
+    entries.clone().count() - entries.unique_by(|x| Arc::as_ptr(x)).count()
+  }
+
+  /// Agents, departments and tags whose details or members failed to fetch
+  pub fn failed_items(&self) -> usize {
+    let agents = self.agents.len() - self.agent_details.len();
+    let departments = self.departments.len()
+      - self.members_by_department.len()
+      - self.simple_members_by_department.len()
+      - self.no_permission_departments.len();
+    let tags = self.tags.len() - self.tag_members.len();
+    agents + departments + tags
+  }
+
+  /// Tags of each member keyed by user id, members in no tag are left out
+  pub fn tags_by_user(&self) -> BTreeMap<&str, Vec<&Tag>> {
+    let mut result: BTreeMap<&str, Vec<&Tag>> = BTreeMap::new();
+    for tag in &self.tags {
+      for member in self.tag_members.get(&tag.id).into_iter().flatten() {
+        let tags = result.entry(&member.id).or_default();
+        if !tags.iter().any(|x| x.id == tag.id) {
+          tags.push(tag);
+        }
+      }
+    }
+    result
+  }
+
+  /// Members of every fetched tag, each listed once with the tags it is in, sorted by user id
+  pub fn tag_union(&self) -> Vec<TagUnionMember<'_>> {
+    let mut result: BTreeMap<&str, TagUnionMember> = BTreeMap::new();
+    for tag in &self.tags {
+      for member in self.tag_members.get(&tag.id).into_iter().flatten() {
+        let x = result.entry(&member.id).or_insert_with(|| TagUnionMember {
+          member,
+          tags: Vec::new(),
+        });
+        if !x.tags.iter().any(|x| x.id == tag.id) {
+          x.tags.push(tag);
+        }
+      }
+    }
+    result.into_values().collect()
+  }
+
+  /// Count the members of every department, whatever `--recursive` was used for fetching
+  pub fn department_counts(&self) -> Vec<DepartmentCount<'_>> {
+    let parents = self
+      .departments
+      .iter()
+      .map(|x| (x.id, x.parent_id))
+      .collect();
+    let users = self
+      .members_by_department
+      .values()
+      .flatten()
+      .map(|x| (&*x.user_id, &*x.department));
+    let recursive = recursive_counts(&parents, users);
+    self
+      .departments
+      .iter()
+      .map(|department| DepartmentCount {
+        department,
+        member_count: self
+          .members_by_department
+          .get(&department.id)
+          .map(|members| {
+            let direct = members
+              .iter()
+              .filter(|x| x.department.contains(&department.id));
+            direct.count()
+          }),
+        recursive_member_count: recursive.get(&department.id).copied().unwrap_or_default(),
+      })
+      .collect()
+  }
+}
+
+/// Count the distinct users in each department and its descendants, given the parent
+/// of each department and the departments of each user
+fn recursive_counts<'a>(
+  parents: &HashMap<u32, Option<u32>>,
+  users: impl Iterator<Item = (&'a str, &'a [u32])>,
+) -> HashMap<u32, usize> {
+  let mut users_by_department: HashMap<u32, HashSet<&str>> = HashMap::new();
+  for (user_id, departments) in users {
+    for &id in departments {
+      let mut current = Some(id);
+      while let Some(id) = current {
+        // stop at an ancestor already visited, which also guards against cycles
+        if !users_by_department.entry(id).or_default().insert(user_id) {
+          break;
+        }
+        current = parents
+          .get(&id)
+          .copied()
+          .flatten()
+          .filter(|i| parents.contains_key(i));
+      }
+    }
+  }
+  users_by_department
+    .into_iter()
+    .map(|(id, users)| (id, users.len()))
+    .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+  Agents,
+  Departments,
+  Tags,
+}
+
+impl fmt::Display for Job {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Job::Agents => write!(f, "Fetch agent list"),
+      Job::Departments => write!(f, "Fetch department members"),
+      Job::Tags => write!(f, "Fetch tag members"),
+    }
+  }
+}
+
+/// Receives every response as soon as it is fetched, e.g. to save it to disk,
+/// an error returned for a listing fails the whole job
+#[allow(unused_variables)]
+pub trait DumpObserver: Send + Sync {
+  fn on_agents(&self, resp: &AgentListResp) -> Result<()> {
+    Ok(())
+  }
+
+  /// Called instead of [DumpObserver::on_agents] if the agent list is not allowed,
+  /// which is not a failure
+  fn on_agents_denied(&self, err: &ApiError) -> Result<()> {
+    Ok(())
+  }
+
+  fn on_agent_detail(&self, agent: &AgentBasic, resp: &AgentDetail) {}
+
+  fn on_departments(&self, resp: &DepartmentResp) -> Result<()> {
+    Ok(())
+  }
+
+  fn on_department_members(&self, department: &Department, resp: &DepartmentMembersResp) {}
+
+  /// Called with the members of a department failed to deserialize with [DumpOptions::lenient]
+  fn on_rejected_members(&self, department: &Department, rejected: &[Rejected]) {}
+
+  /// Called instead of [DumpObserver::on_department_members] if only the simple list is allowed
+  fn on_department_simple_members(&self, department: &Department, resp: &SimpleMembersResp) {}
+
+  fn on_tags(&self, resp: &TagsResp) -> Result<()> {
+    Ok(())
+  }
+
+  fn on_tag_members(&self, tag: &Tag, resp: &TagMembersResp) {}
+}
+
+impl DumpObserver for () {}
+
+impl WxClient {
+  /// Fetch agents, departments, tags and their members
+  pub async fn dump_all(&self, opts: &DumpOptions) -> Result<Dump> {
+    self.dump_all_with(opts, Arc::new(())).await
+  }
+
+  /// Same as [WxClient::dump_all], and pass every response to `observer` once fetched
+  pub async fn dump_all_with(
+    &self,
+    opts: &DumpOptions,
+    observer: Arc<dyn DumpObserver>,
+  ) -> Result<Dump> {
+    let fail_fast = &opts.fail_fast;
+    let agent_job = opts.agents.then(|| {
+      let job = agent_job(self.clone(), opts.clone(), observer.clone());
+      spawn(abort_on_failure(fail_fast.clone(), Job::Agents, job))
+    });
+    let job = department_job(self.clone(), opts.clone(), observer.clone());
+    let department_job = spawn(abort_on_failure(fail_fast.clone(), Job::Departments, job));
+    let job = tag_job(self.clone(), opts.clone(), observer);
+    let tag_job = spawn(abort_on_failure(fail_fast.clone(), Job::Tags, job));
+
+    let (agents, departments, tags) = tokio::join!(
+      async {
+        match agent_job {
+          Some(agent_job) => Some(agent_job.await),
+          None => None,
+        }
+      },
+      department_job,
+      tag_job,
+    );
+
+    let mut dump = Dump::default();
+    if let Some(agents) = agents {
+      match job_result(Job::Agents, agents) {
+        Some((agents, details)) => {
+          dump.agents = agents;
+          dump.agent_details = details;
+        }
+        None => dump.failed_jobs.push(Job::Agents),
+      }
+    }
+    match job_result(Job::Departments, departments) {
+      Some((departments, members)) => {
+        dump.departments = departments;
+        for (id, members) in members {
+          match members {
+            Members::Full {
+              members,
+              inactive,
+              truncated,
+            } => {
+              dump.members_by_department.insert(id, members);
+              dump.inactive_members.extend(inactive);
+              if truncated {
+                dump.truncated_departments.push(id);
+              }
+            }
+            Members::Simple { members, truncated } => {
+              dump.simple_members_by_department.insert(id, members);
+              if truncated {
+                dump.truncated_departments.push(id);
+              }
+            }
+            Members::NoPermission => dump.no_permission_departments.push(id),
+            Members::TimedOut => dump.timed_out_departments.push(id),
+          }
+        }
+      }
+      None => dump.failed_jobs.push(Job::Departments),
+    }
+    match job_result(Job::Tags, tags) {
+      Some((tags, members)) => {
+        dump.tags = tags;
+        for (id, (members, departments)) in members {
+          dump.tag_members.insert(id, members);
+          dump.tag_departments.insert(id, departments);
+        }
+      }
+      None => dump.failed_jobs.push(Job::Tags),
+    }
+    if let Some(err) = fail_fast.as_ref().and_then(FailFast::error) {
+      bail!("Aborted by --fail-fast: {err}");
+    }
+    Ok(dump)
+  }
+
+  /// Members of every visible department one at a time, each listed once, so they can be
+  /// processed without holding the whole tenant in memory. Departments are fetched one by
+  /// one like pages, the ones without permission are skipped, and the stream ends at the
+  /// first other error
+  pub fn stream_all_members(&self) -> impl Stream<Item = Result<DepartmentMember>> + '_ {
+    stream::try_unfold(MemberPages::default(), move |mut pages| async move {
+      loop {
+        if let Some(member) = pages.page.next() {
+          if pages.seen.insert(member.user_id.clone()) {
+            return Ok(Some((member, pages)));
+          }
+          continue;
+        }
+        let departments = match &mut pages.departments {
+          Some(departments) => departments,
+          None => {
+            let resp = self
+              .get_all_departments()
+              .await
+              .context("Failed to get departments list")?;
+            let ids = resp.departments.iter().map(|x| x.id).collect();
+            pages.departments.insert(ids)
+          }
+        };
+        let Some(id) = departments.pop_front() else {
+          return Ok(None);
+        };
+        match self.get_department_members(id, false).await {
+          Ok(resp) => pages.page = resp.members.into_iter(),
+          Err(err) if ApiError::find(&err).is_some_and(ApiError::is_permission_error) => {
+            warn!("No permission to get the members of department {id}, skipped");
+          }
+          Err(err) => {
+            return Err(err.context(format!("Failed to get the members of department {id}")));
+          }
+        }
+      }
+    })
+  }
+}
+
+/// State of [WxClient::stream_all_members]
+#[derive(Default)]
+struct MemberPages {
+  /// Departments left to fetch, [None] before the list is fetched
+  departments: Option<VecDeque<u32>>,
+  /// Members of the current department
+  page: std::vec::IntoIter<DepartmentMember>,
+  /// Ids of the members yielded
+  seen: HashSet<String>,
+}
+
+/// Abort the other jobs under `--fail-fast` once `job` fails
+async fn abort_on_failure<T>(
+  fail_fast: Option<FailFast>,
+  job: Job,
+  run: impl Future<Output = Result<T>>,
+) -> Result<T> {
+  let result = run.await;
+  if let (Some(fail_fast), Err(err)) = (&fail_fast, &result) {
+    fail_fast.fail(format!("{job} job failed: {err:#}"));
+  }
+  result
+}
+
+async fn agent_job(
+  wx: WxClient,
+  opts: DumpOptions,
+  observer: Arc<dyn DumpObserver>,
+) -> Result<(Vec<AgentBasic>, BTreeMap<u32, AgentDetail>)> {
+  let mut resp = match wx.get_agent_list().await {
+    Ok(resp) => resp,
+    Err(err) => match ApiError::find(&err) {
+      Some(api_err)
+        if [ApiError::NO_PRIVILEGE, ApiError::NO_AGENT_PERMISSION].contains(&api_err.code) =>
+      {
+        warn!("No permission to get the agent list, skipped: {api_err}");
+        observer.on_agents_denied(api_err)?;
+        return Ok((Vec::new(), BTreeMap::new()));
+      }
+      _ => return Err(err.context("Failed to get agent list")),
+    },
+  };
+  resp.sort();
+  let agent_to_print = resp
+    .agent_list
+    .iter()
+    .map(|i| format!("{} - {}", i.id, i.name))
+    .join(", ");
+  info!("Agents: {agent_to_print}");
+  observer.on_agents(&resp)?;
+
+  let details = fetch_each(
+    "agent details",
+    resp.agent_list.clone(),
+    opts.delay,
+    None,
+    opts.fail_fast.as_ref(),
+    |x| {
+      let wx = wx.clone();
+      let observer = observer.clone();
+      let opts = opts.clone();
+      let span = info_span!("agent", id = x.id);
+      async move {
+        match wx.get_agent_detail(x.id).await {
+          Ok(resp) => {
+            observer.on_agent_detail(&x, &resp);
+            Some((x.id, resp))
+          }
+          Err(err) => {
+            let message = format!("Failed to get agent details: {} - {}", x.id, x.name);
+            opts.failed(message, err);
+            None
+          }
+        }
+      }
+      .instrument(span)
+    },
+  )
+  .await;
+  Ok((resp.agent_list, details.into_iter().collect()))
+}
+
+async fn department_job(
+  wx: WxClient,
+  opts: DumpOptions,
+  observer: Arc<dyn DumpObserver>,
+) -> Result<(Vec<Department>, Vec<(u32, Members)>)> {
+  let mut resp = wx
+    .get_all_departments()
+    .await
+    .context("Failed to get departments list")?;
+  // computed before sampling, from all departments
+  let ids = resp.departments.iter().map(|x| x.id).collect();
+  let parents: Arc<HashMap<u32, u32>> = Arc::new(
+    resp
+      .departments
+      .iter()
+      .filter_map(|x| Some((x.id, x.parent_id?)))
+      .collect(),
+  );
+  let names = opts
+    .annotate_departments
+    .then(|| Arc::new(department_paths(&resp.departments)));
+
+  resp.sort();
+  if let Some(filter) = &opts.department_name_filter {
+    filter_by_name(&mut resp.departments, filter, |x| &x.name, "departments");
+  }
+  if let Some(ids) = &opts.department_ids {
+    filter_by_id(&mut resp.departments, ids, |x| x.id, "departments");
+  }
+  sample(&mut resp.departments, opts.limit, "departments");
+  info!("Total {} departments to query", resp.departments.len());
+  observer.on_departments(&resp)?;
+
+  let delay = opts.department_delay.unwrap_or(opts.delay);
+  let checkpoint = opts.resume.clone().unwrap_or_default();
+  let (mut members, restored) = if opts.recursive {
+    // a subtree is fetched at once, so it is restored only if all its departments are
+    let (subtrees, restored) = restore(
+      subtrees(&resp.departments, &parents),
+      "department subtrees",
+      |subtree| {
+        let members = subtree
+          .iter()
+          .map(|x| Some((x.id, checkpoint.department(x.id)?)));
+        members.collect::<Option<Vec<_>>>()
+      },
+    );
+    info!(
+      "Total {} subtrees to query with their descendants",
+      subtrees.len()
+    );
+    let members = fetch_each(
+      "department subtrees",
+      subtrees,
+      delay,
+      opts.department_concurrency,
+      opts.fail_fast.as_ref(),
+      |subtree| {
+        let wx = wx.clone();
+        let observer = observer.clone();
+        let parents = parents.clone();
+        let opts = opts.clone();
+        let names = names.clone();
+        let span = info_span!("subtree", root = subtree[0].id);
+        async move {
+          let names = names.as_deref();
+          let ids: Vec<u32> = subtree.iter().map(|x| x.id).collect();
+          let root = format!(
+            "subtree of department: {} - {}",
+            subtree[0].id, subtree[0].name
+          );
+          let fetch = fetch_subtree(&wx, &*observer, subtree, &parents, &opts, names);
+          let members = with_item_timeout(&opts, &root, fetch, || {
+            ids.iter().map(|&id| (id, Members::TimedOut)).collect()
+          });
+          Some(members.await)
+        }
+        .instrument(span)
+      },
+    )
+    .await;
+    let members: Vec<_> = members.into_iter().flatten().collect();
+    (members, restored.into_iter().flatten().collect())
+  } else {
+    let (departments, restored) =
+      restore(resp.departments.clone(), "departments", |x: &Department| {
+        Some((x.id, checkpoint.department(x.id)?))
+      });
+    let members = fetch_each(
+      "department members",
+      departments,
+      delay,
+      opts.department_concurrency,
+      opts.fail_fast.as_ref(),
+      |x| {
+        let wx = wx.clone();
+        let observer = observer.clone();
+        let fetch_child = opts.fetch_child(&x, &ids);
+        let opts = opts.clone();
+        let names = names.clone();
+        let span = info_span!("department", id = x.id);
+        async move {
+          let names = names.as_deref();
+          let department = format!("department: {} - {}", x.id, x.name);
+          let fetch = fetch_department(&wx, &*observer, &x, fetch_child, &opts, names);
+          with_item_timeout(&opts, &department, fetch, || {
+            Some((x.id, Members::TimedOut))
+          })
+          .await
+        }
+        .instrument(span)
+      },
+    )
+    .await;
+    (members, restored)
+  };
+  members.extend(restored);
+  Ok((resp.departments, members))
+}
+
+/// Run `fetch` of `item` within [DumpOptions::item_timeout] if any, or report it as failed and
+/// return `timed_out`
+async fn with_item_timeout<T>(
+  opts: &DumpOptions,
+  item: &str,
+  fetch: impl Future<Output = T>,
+  timed_out: impl FnOnce() -> T,
+) -> T {
+  let Some(limit) = opts.item_timeout else {
+    return fetch.await;
+  };
+  match timeout(limit, fetch).await {
+    Ok(result) => result,
+    Err(_) => {
+      let err = anyhow!("Timed out after {}", humantime::format_duration(limit));
+      opts.failed(format!("Skipped the members of {item}"), err);
+      timed_out()
+    }
+  }
+}
+
+/// Fetch the members of a department, from the simple list if the full details are not allowed
+async fn fetch_department(
+  wx: &WxClient,
+  observer: &dyn DumpObserver,
+  x: &Department,
+  fetch_child: bool,
+  opts: &DumpOptions,
+  names: Option<&HashMap<u32, String>>,
+) -> Option<(u32, Members)> {
+  if opts.minimal_members {
+    return fetch_simple(wx, observer, x, fetch_child, opts).await;
+  }
+  match get_members(wx, x.id, fetch_child, opts.lenient).await {
+    Ok((mut resp, rejected)) => {
+      let count = resp.members.len() + rejected.len();
+      let truncated = check_truncated(x, count, fetch_child, opts)?;
+      reject_members(observer, x, &rejected);
+      let inactive = prepare_members(&mut resp, opts, names);
+      observer.on_department_members(x, &resp);
+      let members = resp.members.into_iter().map(Arc::new).collect();
+      let members = Members::Full {
+        members,
+        inactive,
+        truncated,
+      };
+      Some((x.id, members))
+    }
+    Err(err) if ApiError::find(&err).is_some_and(|i| i.code == ApiError::NO_PRIVILEGE) => {
+      warn!(
+        "No permission to get the members of department: {} - {}, fallback to the simple list",
+        x.id, x.name
+      );
+      fetch_simple(wx, observer, x, fetch_child, opts).await
+    }
+    Err(err) => {
+      let message = format!(
+        "Failed to get the members of department: {} - {}",
+        x.id, x.name
+      );
+      opts.failed(message, err);
+      None
+    }
+  }
+}
+
+/// `user/list` has no pagination, and large departments are suspected to be cut off at a round
+/// number without any error, so a department fetched without its children with a multiple of
+/// this many members is flagged
+const SUSPECTED_MEMBER_CAP: usize = 1000;
+
+/// Warn if the `count` members of `x` fetched without its children look truncated, returns
+/// whether they do, or [None] after failing `x` under [DumpOptions::strict_completeness]
+fn check_truncated(
+  x: &Department,
+  count: usize,
+  fetch_child: bool,
+  opts: &DumpOptions,
+) -> Option<bool> {
+  if fetch_child || count == 0 || !count.is_multiple_of(SUSPECTED_MEMBER_CAP) {
+    return Some(false);
+  }
+  let message = format!(
+    "Department: {} - {} returned exactly {count} members, possibly truncated by the API",
+    x.id, x.name
+  );
+  if opts.strict_completeness {
+    opts.failed(message, anyhow!("Failed by --strict-completeness"));
+    return None;
+  }
+  warn!(
+    "{message}, fetch its child departments separately, or list its user ids with the \
+     cursor-paginated user/list_id"
+  );
+  Some(true)
+}
+
+/// Fetch the user id, name and departments of the members of a department from the simple list
+async fn fetch_simple(
+  wx: &WxClient,
+  observer: &dyn DumpObserver,
+  x: &Department,
+  fetch_child: bool,
+  opts: &DumpOptions,
+) -> Option<(u32, Members)> {
+  match wx.get_department_members_simple(x.id, fetch_child).await {
+    Ok(mut resp) => {
+      let truncated = check_truncated(x, resp.members.len(), fetch_child, opts)?;
+      resp.sort();
+      observer.on_department_simple_members(x, &resp);
+      let members = resp.members;
+      Some((x.id, Members::Simple { members, truncated }))
+    }
+    Err(err) if ApiError::find(&err).is_some_and(|i| i.code == ApiError::NO_PRIVILEGE) => {
+      warn!(
+        "No permission to get the simple members of department: {} - {}, skipped",
+        x.id, x.name
+      );
+      Some((x.id, Members::NoPermission))
+    }
+    Err(err) => {
+      let message = format!(
+        "Failed to get the simple members of department: {} - {}",
+        x.id, x.name
+      );
+      opts.failed(message, err);
+      None
+    }
+  }
+}
+
+/// Fetch the members of the first department in `subtree` with all its descendants at once,
+/// then split them into the other departments in `subtree` as if each one was fetched with
+/// `fetch_child`, falls back to fetching each department if the first one fails
+async fn fetch_subtree(
+  wx: &WxClient,
+  observer: &dyn DumpObserver,
+  subtree: Vec<Department>,
+  parents: &HashMap<u32, u32>,
+  opts: &DumpOptions,
+  names: Option<&HashMap<u32, String>>,
+) -> Vec<(u32, Members)> {
+  if opts.minimal_members {
+    return fetch_simple_subtree(wx, observer, subtree, parents, opts, names).await;
+  }
+  let root = &subtree[0];
+  let mut resp = match get_members(wx, root.id, true, opts.lenient).await {
+    Ok((resp, rejected)) => {
+      // not split, since their departments are unknown
+      reject_members(observer, root, &rejected);
+      resp
+    }
+    Err(err) => {
+      warn!(
+        "Failed to get the members under department: {} - {} at once, fallback to each department: {err:#}",
+        root.id, root.name
+      );
+      return fetch_separately(wx, observer, &subtree, opts, names).await;
+    }
+  };
+  // dropped members are counted by user id, so keep them with the root only
+  let mut inactive = Some(prepare_members(&mut resp, opts, names));
+  // shared by every department a member is split into, instead of a copy in each
+  let shared: Vec<_> = resp.members.drain(..).map(Arc::new).collect();
+  let split = split_subtree(&shared, |x| &x.department, &subtree, parents);
+  subtree
+    .iter()
+    .zip(split)
+    .map(|(x, members)| {
+      // the copies only live until the observer has saved them
+      let resp = DepartmentMembersResp {
+        code: resp.code,
+        msg: resp.msg.clone(),
+        members: members.iter().map(|x| DepartmentMember::clone(x)).collect(),
+      };
+      observer.on_department_members(x, &resp);
+      let inactive = inactive.take().unwrap_or_default();
+      // the heuristic is for a single department, not for a subtree split
+      let truncated = false;
+      (
+        x.id,
+        Members::Full {
+          members,
+          inactive,
+          truncated,
+        },
+      )
+    })
+    .collect()
+}
+
+/// [fetch_subtree] from the simple list under [DumpOptions::minimal_members]
+async fn fetch_simple_subtree(
+  wx: &WxClient,
+  observer: &dyn DumpObserver,
+  subtree: Vec<Department>,
+  parents: &HashMap<u32, u32>,
+  opts: &DumpOptions,
+  names: Option<&HashMap<u32, String>>,
+) -> Vec<(u32, Members)> {
+  let root = &subtree[0];
+  let mut resp = match wx.get_department_members_simple(root.id, true).await {
+    Ok(resp) => resp,
+    Err(err) => {
+      warn!(
+        "Failed to get the simple members under department: {} - {} at once, fallback to each department: {err:#}",
+        root.id, root.name
+      );
+      return fetch_separately(wx, observer, &subtree, opts, names).await;
+    }
+  };
+  resp.sort();
+  let split = split_subtree(&resp.members, |x| &x.department, &subtree, parents);
+  subtree
+    .iter()
+    .zip(split)
+    .map(|(x, members)| {
+      let resp = SimpleMembersResp {
+        code: resp.code,
+        msg: resp.msg.clone(),
+        members,
+      };
+      observer.on_department_simple_members(x, &resp);
+      let members = resp.members;
+      (
+        x.id,
+        Members::Simple {
+          members,
+          truncated: false,
+        },
+      )
+    })
+    .collect()
+}
+
+/// Fetch each department in `subtree` with its descendants, after failing to fetch them at once
+async fn fetch_separately(
+  wx: &WxClient,
+  observer: &dyn DumpObserver,
+  subtree: &[Department],
+  opts: &DumpOptions,
+  names: Option<&HashMap<u32, String>>,
+) -> Vec<(u32, Members)> {
+  let mut result = Vec::new();
+  for x in subtree {
+    let members = fetch_department(wx, observer, x, true, opts, names);
+    result.extend(members.await);
+  }
+  result
+}
+
+/// Fetch the members of a department, with the ones failed to deserialize aside if `lenient`
+async fn get_members(
+  wx: &WxClient,
+  id: u32,
+  fetch_child: bool,
+  lenient: bool,
+) -> Result<(DepartmentMembersResp, Vec<Rejected>)> {
+  match lenient {
+    true => wx.get_department_members_lenient(id, fetch_child).await,
+    false => Ok((
+      wx.get_department_members(id, fetch_child).await?,
+      Vec::new(),
+    )),
+  }
+}
+
+fn reject_members(observer: &dyn DumpObserver, department: &Department, rejected: &[Rejected]) {
+  if rejected.is_empty() {
+    return;
+  }
+  warn!(
+    "{} members of department: {} - {} failed to deserialize, skipped",
+    rejected.len(),
+    department.id,
+    department.name
+  );
+  observer.on_rejected_members(department, rejected);
+}
+
+/// Sort, normalize and annotate fetched members, and drop the inactive ones under
+/// [DumpOptions::active_only], returns the user ids dropped
+fn prepare_members(
+  resp: &mut DepartmentMembersResp,
+  opts: &DumpOptions,
+  names: Option<&HashMap<u32, String>>,
+) -> Vec<String> {
+  resp.sort();
+  annotate_leadership(resp);
+  if let Some(names) = names {
+    annotate_departments(resp, names);
+  }
+  if opts.normalize_contacts {
+    resp
+      .members
+      .iter_mut()
+      .for_each(DepartmentMember::normalize_contacts);
+  }
+  if opts.active_only {
+    resp.retain_active()
+  } else {
+    Vec::new()
+  }
+}
+
+/// Group `departments` by their top-most ancestor among them, which comes first in its group
+fn subtrees(departments: &[Department], parents: &HashMap<u32, u32>) -> Vec<Vec<Department>> {
+  let selected: HashSet<u32> = departments.iter().map(|x| x.id).collect();
+  let root = |id: u32| {
+    let mut root = id;
+    let mut seen = HashSet::from([id]);
+    let mut current = parents.get(&id);
+    // guard against cycles
+    while let Some(&parent) = current.filter(|i| seen.insert(**i)) {
+      if selected.contains(&parent) {
+        root = parent;
+      }
+      current = parents.get(&parent);
+    }
+    root
+  };
+  let mut groups: Vec<Vec<Department>> = Vec::new();
+  let mut index: HashMap<u32, usize> = HashMap::new();
+  let (roots, others): (Vec<_>, Vec<_>) = departments.iter().partition(|x| root(x.id) == x.id);
+  for x in roots {
+    index.insert(x.id, groups.len());
+    groups.push(vec![x.clone()]);
+  }
+  for x in others {
+    groups[index[&root(x.id)]].push(x.clone());
+  }
+  groups
+}
+
+/// Members of each department in `subtree`, including the ones in its descendants, out of
+/// the members of the whole subtree in the departments told by `department`
+fn split_subtree<T: Clone>(
+  members: &[T],
+  department: impl Fn(&T) -> &Vec<u32>,
+  subtree: &[Department],
+  parents: &HashMap<u32, u32>,
+) -> Vec<Vec<T>> {
+  let index: HashMap<u32, usize> = subtree.iter().enumerate().map(|(i, x)| (x.id, i)).collect();
+  let mut result = vec![Vec::new(); subtree.len()];
+  for member in members {
+    let mut seen = HashSet::new();
+    for &id in department(member) {
+      let mut current = Some(id);
+      // ancestors seen already are walked from another department of the member
+      while let Some(id) = current.filter(|i| seen.insert(*i)) {
+        if let Some(&i) = index.get(&id) {
+          result[i].push(member.clone());
+        }
+        current = parents.get(&id).copied();
+      }
+    }
+  }
+  result
+}
+
+/// Members of a department, in full details or from the simple list
+enum Members {
+  Full {
+    members: Vec<Arc<DepartmentMember>>,
+    /// User ids of the inactive members dropped
+    inactive: Vec<String>,
+    /// Possibly truncated by the API, see [check_truncated]
+    truncated: bool,
+  },
+  Simple {
+    members: Vec<SimpleMember>,
+    truncated: bool,
+  },
+  /// Not visible to the app at all
+  NoPermission,
+  /// Not fetched in [DumpOptions::item_timeout]
+  TimedOut,
+}
+
+/// Full name path of each department, like `Company/Engineering/Backend`,
+/// a parent not in `departments` ends the path
+fn department_paths(departments: &[Department]) -> HashMap<u32, String> {
+  let by_id: HashMap<u32, &Department> = departments.iter().map(|x| (x.id, x)).collect();
+  departments
+    .iter()
+    .map(|department| {
+      let chain = ancestors(&by_id, department);
+      (department.id, chain.iter().rev().map(|x| &x.name).join("/"))
+    })
+    .collect()
+}
+
+/// The department and its ancestors in `by_id`, from itself to the top
+pub fn ancestors<'a>(
+  by_id: &HashMap<u32, &'a Department>,
+  department: &'a Department,
+) -> Vec<&'a Department> {
+  let mut chain = vec![department];
+  let mut current = department.parent_id.and_then(|i| by_id.get(&i));
+  while let Some(parent) = current {
+    // guard against cycles
+    if chain.iter().any(|x| x.id == parent.id) {
+      break;
+    }
+    chain.push(parent);
+    current = parent.parent_id.and_then(|i| by_id.get(&i));
+  }
+  chain
+}
+
+/// Fill the department name paths of every member, the raw ids are kept in `department`
+fn annotate_departments(resp: &mut DepartmentMembersResp, names: &HashMap<u32, String>) {
+  for member in &mut resp.members {
+    let paths = member
+      .department
+      .iter()
+      .map(|id| names.get(id).cloned().unwrap_or_else(|| id.to_string()))
+      .collect();
+    member.department_path = Some(paths);
+  }
+}
+
+/// Fill the leadership of every member by zipping `department` with `is_leader_in_dept`,
+/// up to the shorter one if their lengths differ
+fn annotate_leadership(resp: &mut DepartmentMembersResp) {
+  for member in &mut resp.members {
+    let (departments, leaders) = (&member.department, &member.is_leader_in_dept);
+    if departments.len() != leaders.len() {
+      warn!(
+        "Member {} is in {} departments but has {} leader flags, extra ones are ignored",
+        member.user_id,
+        departments.len(),
+        leaders.len()
+      );
+    }
+    member.leadership = departments
+      .iter()
+      .zip(leaders)
+      .map(|(&department_id, &is_leader)| Leadership {
+        department_id,
+        is_leader: is_leader != 0,
+      })
+      .collect();
+  }
+}
+
+/// Members and departments of each tag, keyed by tag id
+type TagMembers = BTreeMap<u32, (Vec<TagMember>, Vec<u32>)>;
+
+async fn tag_job(
+  wx: WxClient,
+  opts: DumpOptions,
+  observer: Arc<dyn DumpObserver>,
+) -> Result<(Vec<Tag>, TagMembers)> {
+  let mut resp = wx.get_tags().await.context("Failed to get tags list")?;
+  resp.sort();
+  if let Some(filter) = &opts.tag_name_filter {
+    filter_by_name(&mut resp.tags, filter, |x| &x.name, "tags");
+  }
+  if let Some(ids) = &opts.tag_ids {
+    filter_by_id(&mut resp.tags, ids, |x| x.id, "tags");
+  }
+  sample(&mut resp.tags, opts.limit, "tags");
+  info!("Total {} tags to query", resp.tags.len());
+  observer.on_tags(&resp)?;
+
+  let checkpoint = opts.resume.clone().unwrap_or_default();
+  let (tags, restored) = restore(resp.tags.clone(), "tags", |x| {
+    Some((x.id, checkpoint.tag(x.id)?))
+  });
+  let mut members = fetch_each(
+    "tag members",
+    tags,
+    opts.tag_delay.unwrap_or(opts.delay),
+    opts.tag_concurrency,
+    opts.fail_fast.as_ref(),
+    |x| {
+      let wx = wx.clone();
+      let observer = observer.clone();
+      let opts = opts.clone();
+      let span = info_span!("tag", id = x.id);
+      async move {
+        match wx.get_tag_members(x.id).await {
+          Ok(mut resp) => {
+            resp.sort();
+            observer.on_tag_members(&x, &resp);
+            Some((x.id, (resp.members, resp.department_list)))
+          }
+          Err(err) => {
+            let message = format!("Failed to get the members of tag: {} - {}", x.id, x.name);
+            opts.failed(message, err);
+            None
+          }
+        }
+      }
+      .instrument(span)
+    },
+  )
+  .await;
+  members.extend(restored);
+  Ok((resp.tags, members.into_iter().collect()))
+}
+
+/// Parse a name filter, which is case-insensitive unless it starts with `(?-i)`
+pub fn parse_name_filter(s: &str) -> Result<Regex> {
+  RegexBuilder::new(s)
+    .case_insensitive(true)
+    .build()
+    .context("Invalid name filter")
+}
+
+/// Keep only the items whose name matches `filter`
+fn filter_by_name<T>(items: &mut Vec<T>, filter: &Regex, name: impl Fn(&T) -> &str, kind: &str) {
+  let total = items.len();
+  items.retain(|x| filter.is_match(name(x)));
+  info!("{} of {total} {kind} match the name filter", items.len());
+}
+
+/// Keep only the items in `ids`, the ones not visible are reported
+fn filter_by_id<T>(items: &mut Vec<T>, ids: &[u32], id: impl Fn(&T) -> u32, kind: &str) {
+  let total = items.len();
+  items.retain(|x| ids.contains(&id(x)));
+  let missing: Vec<u32> = ids
+    .iter()
+    .filter(|&&i| !items.iter().any(|x| id(x) == i))
+    .copied()
+    .unique()
+    .collect();
+  if !missing.is_empty() {
+    warn!("Some {kind} are not found or not visible to the app: {missing:?}");
+  }
+  info!("{} of {total} {kind} are selected by id", items.len());
+}
+
+/// Keep only the first `limit` items, which should be sorted already, if there is a limit
+fn sample<T>(items: &mut Vec<T>, limit: Option<usize>, name: &str) {
+  let Some(limit) = limit else {
+    return;
+  };
+  if items.len() > limit {
+    warn!(
+      "Only {limit} of {} {name} are fetched according to --limit, the output is a truncated sample",
+      items.len()
+    );
+    items.truncate(limit);
+  }
+}
+
+/// Split `items` into the ones to fetch and the results restored from a checkpoint by `restore`
+fn restore<T, R>(items: Vec<T>, name: &str, restore: impl Fn(&T) -> Option<R>) -> (Vec<T>, Vec<R>) {
+  let mut pending = Vec::new();
+  let mut restored = Vec::new();
+  for x in items {
+    match restore(&x) {
+      Some(result) => restored.push(result),
+      None => pending.push(x),
+    }
+  }
+  if !restored.is_empty() {
+    info!(
+      "Restored {} {name} from the checkpoint, {} left to fetch",
+      restored.len(),
+      pending.len()
+    );
+  }
+  (pending, restored)
+}
+
+/// Run `fetch` for each item in its own task, sleeping `delay` between spawns and keeping
+/// at most `concurrency` tasks in flight, failed or panicked items are left out.
+/// A new task starts as soon as any one finishes, so slow items don't hold back the rest
+async fn fetch_each<T, R, F, Fut>(
+  name: &str,
+  items: Vec<T>,
+  delay: Duration,
+  concurrency: Option<u32>,
+  fail_fast: Option<&FailFast>,
+  fetch: F,
+) -> Vec<R>
+where
+  F: Fn(T) -> Fut,
+  Fut: Future<Output = Option<R>> + Send + 'static,
+  R: Send + 'static,
+{
+  let concurrency = concurrency.map_or(usize::MAX, |n| n as usize);
+  // never cancelled without --fail-fast
+  let token = fail_fast.map(|x| x.token.clone()).unwrap_or_default();
+  let mut in_flight = FuturesUnordered::new();
+  let mut result = Vec::new();
+  for x in items {
+    while in_flight.len() >= concurrency && !token.is_cancelled() {
+      select! {
+        Some(x) = in_flight.next() => collect(name, &mut result, x),
+        _ = token.cancelled() => {}
+      }
+    }
+    if token.is_cancelled() {
+      break;
+    }
+    in_flight.push(spawn(fetch(x)));
+    select! {
+      _ = sleep(delay) => {}
+      _ = token.cancelled() => {}
+    }
+  }
+  loop {
+    select! {
+      x = in_flight.next() => match x {
+        Some(x) => collect(name, &mut result, x),
+        None => break,
+      },
+      _ = token.cancelled() => break,
+    }
+  }
+  // the dump is aborted, so the requests in flight are not waited for
+  for x in &in_flight {
+    x.abort();
+  }
+  result
+}
+
+fn collect<R>(name: &str, result: &mut Vec<R>, x: Result<Option<R>, JoinError>) {
+  match x {
+    Ok(x) => result.extend(x),
+    Err(err) => error!("Fetch {name} task panicked: {err:?}"),
+  }
+}
+
+/// Unwrap the result of a job, log and return [None] if the job failed or panicked
+fn job_result<T>(job: Job, result: Result<Result<T>, JoinError>) -> Option<T> {
+  match result {
+    Ok(Ok(value)) => Some(value),
+    Ok(Err(err)) => {
+      error!("{job} job failed: {err:?}");
+      None
+    }
+    Err(err) => {
+      error!("{job} job panicked: {err:?}");
+      None
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::{BTreeMap, HashMap, HashSet};
+  use std::fs;
+  use std::path::Path;
+  use std::time::Duration;
+
+  use futures_util::StreamExt;
+  use tokio::time::{sleep, Instant};
+
+  use reqwest::header::HeaderMap;
+  use serde_json::{json, Value};
+
+  use crate::api::data::{
+    Department, DepartmentMember, DepartmentMembersResp, Leadership, Tag, TagMember,
+  };
+  use crate::api::dump::{
+    annotate_leadership, check_truncated, department_paths, fetch_each, filter_by_id,
+    filter_by_name, parse_name_filter, recursive_counts, with_item_timeout, Dump, DumpOptions,
+    FailFast,
+  };
+  use crate::api::proxy::ProxyConfig;
+  use crate::api::WxClient;
+  use crate::util::TestDir;
+
+  fn department(id: u32, parent_id: u32) -> Department {
+    Department {
+      id,
+      name: id.to_string(),
+      parent_id: Some(parent_id),
+      order: 0,
+      name_en: None,
+      department_leader: None,
+    }
+  }
+
+  #[test]
+  fn fetch_child_test() {
+    // 1 -> 2 -> 3, with a member in each department
+    let departments = [department(1, 0), department(2, 1), department(3, 2)];
+    let ids: HashSet<u32> = departments.iter().map(|x| x.id).collect();
+    let members = |opts: &DumpOptions, user: u32| {
+      let ancestors = departments.iter().filter(|x| x.id <= user);
+      ancestors
+        .filter(|x| x.id == user || opts.fetch_child(x, &ids))
+        .count()
+    };
+
+    let recursive = DumpOptions {
+      recursive: true,
+      ..Default::default()
+    };
+    assert_eq!((1..=3).map(|i| members(&recursive, i)).sum::<usize>(), 6);
+
+    let root_only = DumpOptions {
+      recursive_root_only: true,
+      ..Default::default()
+    };
+    let policies: Vec<bool> = departments
+      .iter()
+      .map(|x| root_only.fetch_child(x, &ids))
+      .collect();
+    assert_eq!(policies, [true, false, false]);
+    assert_eq!(members(&root_only, 1), 1);
+    assert_eq!(members(&root_only, 2), 2);
+    assert_eq!(members(&root_only, 3), 2);
+
+    let flat = DumpOptions::default();
+    assert!((1..=3).all(|i| members(&flat, i) == 1));
+  }
+
+  #[test]
+  fn recursive_counts_test() {
+    // 1 -> 2 -> 3, 1 -> 4
+    let parents = HashMap::from([(1, Some(0)), (2, Some(1)), (3, Some(2)), (4, Some(1))]);
+    let users = [
+      ("a", &[1][..]),
+      ("b", &[2, 3][..]),
+      ("c", &[3][..]),
+      ("d", &[4][..]),
+      ("b", &[2, 3][..]),
+    ];
+    let counts = recursive_counts(&parents, users.into_iter());
+    assert_eq!(counts[&1], 4);
+    assert_eq!(counts[&2], 2);
+    assert_eq!(counts[&3], 2);
+    assert_eq!(counts[&4], 1);
+  }
+
+  #[test]
+  fn department_paths_test() {
+    let mut departments = vec![department(1, 0), department(2, 1), department(3, 2)];
+    departments[0].name = "Company".to_string();
+    departments[1].name = "Engineering".to_string();
+    departments[2].name = "Backend".to_string();
+    let paths = department_paths(&departments);
+    assert_eq!(paths[&1], "Company");
+    assert_eq!(paths[&3], "Company/Engineering/Backend");
+  }
+
+  fn member(user_id: &str, department: &[u32], is_leader_in_dept: &[u32]) -> Value {
+    let overrides = json!({"department": department, "is_leader_in_dept": is_leader_in_dept});
+    DepartmentMember::test_json(user_id, overrides)
+  }
+
+  /// Save a response like `--save-raw` does
+  fn write(dir: &Path, name: &str, value: Value) {
+    fs::write(dir.join(name), value.to_string()).unwrap();
+  }
+
+  /// A client reading the responses in `dir` instead of sending requests
+  async fn offline_client(dir: &Path) -> WxClient {
+    let mut wx = WxClient::new(&ProxyConfig::default(), None, HeaderMap::new(), None, None)
+      .await
+      .unwrap();
+    wx.set_token(String::new());
+    wx.set_offline_dir(Some(dir.to_path_buf()));
+    wx
+  }
+
+  #[tokio::test]
+  async fn recursive_subtree_test() {
+    // 1 -> 2 -> 3 -> 4, and 1 -> 5
+    let dir = TestDir::new("subtree");
+    let departments: Vec<Value> = [(1, 0), (2, 1), (3, 2), (4, 3), (5, 1)]
+      .iter()
+      .map(|(id, parent_id)| {
+        json!({"id": id, "name": id.to_string(), "parentid": parent_id, "order": 0})
+      })
+      .collect();
+    write(
+      &dir,
+      "department-list.json",
+      json!({"errcode": 0, "errmsg": "ok", "department": departments}),
+    );
+    write(
+      &dir,
+      "user-list-1-1.json",
+      json!({"errcode": 0, "errmsg": "ok", "userlist": [
+        member("a", &[4], &[0]), member("b", &[2], &[0]),
+        member("c", &[3, 5], &[0, 0]), member("d", &[1], &[0]),
+      ]}),
+    );
+    write(
+      &dir,
+      "tag-list.json",
+      json!({"errcode": 0, "errmsg": "ok", "taglist": []}),
+    );
+
+    let wx = offline_client(&dir).await;
+    let opts = DumpOptions {
+      agents: false,
+      recursive: true,
+      delay: Duration::ZERO,
+      ..Default::default()
+    };
+    let dump = wx.dump_all(&opts).await.unwrap();
+
+    // one request for the whole tree instead of one for each department
+    assert_eq!(wx.stats().report()["user/list"].requests, 1);
+    let counts: Vec<(u32, usize)> = dump
+      .members_by_department
+      .iter()
+      .map(|(id, members)| (*id, members.len()))
+      .collect();
+    assert_eq!(counts, [(1, 4), (2, 3), (3, 2), (4, 1), (5, 1)]);
+    // the 4 members are kept once each, whatever departments they are split into
+    assert_eq!(dump.shared_member_entries(), 7);
+  }
+
+  #[tokio::test]
+  async fn minimal_members_test() {
+    let dir = TestDir::new("minimal");
+    write(
+      &dir,
+      "department-list.json",
+      json!({"errcode": 0, "errmsg": "ok", "department": [
+        {"id": 1, "name": "1", "parentid": 0, "order": 0},
+        {"id": 2, "name": "2", "parentid": 1, "order": 0},
+      ]}),
+    );
+    // only the simple list of the whole tree is saved, the full one would fail
+    write(
+      &dir,
+      "user-simplelist-1-1.json",
+      json!({"errcode": 0, "errmsg": "ok", "userlist": [
+        {"userid": "a", "name": "a", "department": [1]},
+        {"userid": "b", "name": "b", "department": [2]},
+      ]}),
+    );
+    write(
+      &dir,
+      "tag-list.json",
+      json!({"errcode": 0, "errmsg": "ok", "taglist": []}),
+    );
+
+    let wx = offline_client(&dir).await;
+    let opts = DumpOptions {
+      agents: false,
+      recursive: true,
+      minimal_members: true,
+      delay: Duration::ZERO,
+      ..Default::default()
+    };
+    let dump = wx.dump_all(&opts).await.unwrap();
+
+    // split like the full list, instead of one request for each department
+    assert_eq!(wx.stats().report()["user/simplelist"].requests, 1);
+    assert!(dump.members_by_department.is_empty());
+    assert_eq!(dump.failed_items(), 0);
+    let counts: Vec<(u32, usize)> = dump
+      .simple_members_by_department
+      .iter()
+      .map(|(id, members)| (*id, members.len()))
+      .collect();
+    assert_eq!(counts, [(1, 2), (2, 1)]);
+  }
+
+  #[tokio::test]
+  async fn stream_all_members_test() {
+    let dir = TestDir::new("stream");
+    write(
+      &dir,
+      "department-list.json",
+      json!({"errcode": 0, "errmsg": "ok", "department": [
+        {"id": 1, "name": "1", "parentid": 0, "order": 0},
+        {"id": 2, "name": "2", "parentid": 1, "order": 0},
+        {"id": 3, "name": "3", "parentid": 1, "order": 0},
+        {"id": 4, "name": "4", "parentid": 1, "order": 0},
+      ]}),
+    );
+    write(
+      &dir,
+      "user-list-1-0.json",
+      json!({"errcode": 0, "errmsg": "ok", "userlist": [member("a", &[1], &[0])]}),
+    );
+    write(
+      &dir,
+      "user-list-2-0.json",
+      json!({"errcode": 0, "errmsg": "ok", "userlist": [
+        member("a", &[1, 2], &[0, 0]), member("b", &[2], &[0]),
+      ]}),
+    );
+    write(
+      &dir,
+      "user-list-3-0.json",
+      json!({"errcode": 60011, "errmsg": "no privilege"}),
+    );
+    // department 4 is not saved, which fails like a network error
+
+    let wx = offline_client(&dir).await;
+    let results: Vec<_> = wx.stream_all_members().collect().await;
+
+    let ids: Vec<&str> = results
+      .iter()
+      .filter_map(|x| x.as_ref().ok())
+      .map(|x| &*x.user_id)
+      .collect();
+    assert_eq!(ids, ["a", "b"]);
+    assert_eq!(results.len(), 3);
+    let err = results[2].as_ref().unwrap_err();
+    assert!(err.to_string().contains("department 4"), "{err:?}");
+  }
+
+  #[tokio::test]
+  async fn members_for_departments_test() {
+    let dir = TestDir::new("departments");
+    for (id, members) in [(1, ["a", "b"]), (2, ["b", "c"])] {
+      let members: Vec<Value> = members
+        .into_iter()
+        .map(|x| member(x, &[id], &[0]))
+        .collect();
+      let resp = json!({"errcode": 0, "errmsg": "ok", "userlist": members});
+      write(&dir, &format!("user-list-{id}-0.json"), resp);
+    }
+
+    let wx = offline_client(&dir).await;
+    let members = wx
+      .get_members_for_departments(&[2, 1], false)
+      .await
+      .unwrap();
+    let missing = wx.get_members_for_departments(&[1, 3], false).await;
+
+    let ids: Vec<&str> = members.iter().map(|x| &*x.user_id).collect();
+    assert_eq!(ids, ["b", "c", "a"]);
+    assert!(missing.unwrap_err().to_string().contains("department 3"));
+  }
+
+  #[test]
+  fn tags_by_user_test() {
+    let tag = |id: u32| Tag {
+      id,
+      name: format!("T{id}"),
+    };
+    let member = |id: &str| TagMember {
+      id: id.to_string(),
+      name: id.to_string(),
+    };
+    let dump = Dump {
+      tags: vec![tag(1), tag(2), tag(3)],
+      tag_members: BTreeMap::from([
+        (1, vec![member("a"), member("b")]),
+        (2, vec![member("a"), member("a")]),
+        (3, vec![]),
+      ]),
+      ..Default::default()
+    };
+    let tags_by_user: Vec<(&str, Vec<u32>)> = dump
+      .tags_by_user()
+      .into_iter()
+      .map(|(user, tags)| (user, tags.iter().map(|x| x.id).collect()))
+      .collect();
+    assert_eq!(tags_by_user, [("a", vec![1, 2]), ("b", vec![1])]);
+
+    let union: Vec<(&str, Vec<u32>)> = dump
+      .tag_union()
+      .into_iter()
+      .map(|x| (&*x.member.id, x.tags.iter().map(|x| x.id).collect()))
+      .collect();
+    assert_eq!(union, [("a", vec![1, 2]), ("b", vec![1])]);
+  }
+
+  #[test]
+  fn filter_by_id_test() {
+    let mut tags: Vec<Tag> = (1..=4)
+      .map(|id| Tag {
+        id,
+        name: id.to_string(),
+      })
+      .collect();
+    filter_by_id(&mut tags, &[4, 2, 7], |x| x.id, "tags");
+    assert_eq!(tags.iter().map(|x| x.id).collect::<Vec<_>>(), [2, 4]);
+  }
+
+  #[test]
+  fn annotate_leadership_test() {
+    let mut resp: DepartmentMembersResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok",
+      "userlist": [member("a", &[1, 2], &[0, 1]), member("b", &[1, 2], &[1])],
+    }))
+    .unwrap();
+    annotate_leadership(&mut resp);
+    let leadership = |department_id, is_leader| Leadership {
+      department_id,
+      is_leader,
+    };
+    assert_eq!(
+      resp.members[0].leadership,
+      [leadership(1, false), leadership(2, true)]
+    );
+    assert_eq!(resp.members[1].leadership, [leadership(1, true)]);
+  }
+
+  #[test]
+  fn filter_by_name_test() {
+    let mut names = vec!["Sales", "North sales", "R&D"];
+    let filter = parse_name_filter("sales").unwrap();
+    filter_by_name(&mut names, &filter, |x| x, "departments");
+    assert_eq!(names, ["Sales", "North sales"]);
+
+    let mut names = vec!["Sales", "North sales"];
+    let filter = parse_name_filter("(?-i)^Sales").unwrap();
+    filter_by_name(&mut names, &filter, |x| x, "departments");
+    assert_eq!(names, ["Sales"]);
+    assert!(parse_name_filter("(").is_err());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn fetch_each_test() {
+    let start = Instant::now();
+    let latencies = vec![300, 100, 100, 100, 100, 100];
+    let result = fetch_each(
+      "items",
+      latencies,
+      Duration::ZERO,
+      Some(2),
+      None,
+      |ms| async move {
+        sleep(Duration::from_millis(ms)).await;
+        Some(ms)
+      },
+    )
+    .await;
+    assert_eq!(result.len(), 6);
+    // the slow item holds one slot while the other one keeps fetching, sequentially it's 800ms
+    assert_eq!(start.elapsed(), Duration::from_millis(400));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn item_timeout_test() {
+    let fail_fast = FailFast::default();
+    let opts = DumpOptions {
+      item_timeout: Some(Duration::from_secs(1)),
+      fail_fast: Some(fail_fast.clone()),
+      ..Default::default()
+    };
+    let fetch = |ms| async move {
+      sleep(Duration::from_millis(ms)).await;
+      Some(ms)
+    };
+    let fast = with_item_timeout(&opts, "fast", fetch(500), || None).await;
+    assert_eq!(fast, Some(500));
+    assert!(fail_fast.error().is_none());
+    let start = Instant::now();
+    let slow = with_item_timeout(&opts, "slow", fetch(5000), || None).await;
+    assert_eq!(slow, None);
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+    assert!(fail_fast.error().unwrap().contains("slow"));
+  }
+
+  #[test]
+  fn check_truncated_test() {
+    let department: Department =
+      serde_json::from_value(json!({"id": 2, "name": "Sales", "parentid": 1, "order": 0})).unwrap();
+    let opts = DumpOptions::default();
+    assert_eq!(check_truncated(&department, 999, false, &opts), Some(false));
+    assert_eq!(check_truncated(&department, 2000, false, &opts), Some(true));
+    // a subtree has no single cap
+    assert_eq!(check_truncated(&department, 2000, true, &opts), Some(false));
+
+    let fail_fast = FailFast::default();
+    let strict = DumpOptions {
+      strict_completeness: true,
+      fail_fast: Some(fail_fast.clone()),
+      ..Default::default()
+    };
+    assert_eq!(check_truncated(&department, 1000, false, &strict), None);
+    assert!(fail_fast.error().unwrap().contains("2 - Sales"));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn fetch_each_fail_fast_test() {
+    let start = Instant::now();
+    let fail_fast = FailFast::default();
+    let latencies = vec![1000, 100, 100, 100, 100, 100];
+    let result = fetch_each(
+      "items",
+      latencies.into_iter().enumerate().collect(),
+      Duration::ZERO,
+      Some(2),
+      Some(&fail_fast),
+      |(i, ms)| {
+        let fail_fast = fail_fast.clone();
+        async move {
+          sleep(Duration::from_millis(ms)).await;
+          if i == 2 {
+            fail_fast.fail(format!("item {i} failed"));
+            return None;
+          }
+          Some(i)
+        }
+      },
+    )
+    .await;
+    // the slow item in flight is aborted, and the rest are not started
+    assert_eq!(result, [1]);
+    assert_eq!(start.elapsed(), Duration::from_millis(200));
+    assert_eq!(fail_fast.error().unwrap(), "item 2 failed");
+  }
+}
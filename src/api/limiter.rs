@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Instant};
+
+/// Spaces requests evenly to keep under a max requests per second,
+/// and holds every request back during a cool-down
+pub struct RateLimiter {
+  interval: Duration,
+  next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+  pub fn new(qps: u32) -> RateLimiter {
+    RateLimiter {
+      interval: Duration::from_secs(1) / qps.max(1),
+      next: Mutex::new(Instant::now()),
+    }
+  }
+
+  /// No limit on the rate, only cool-downs are applied
+  pub fn unlimited() -> RateLimiter {
+    RateLimiter {
+      interval: Duration::ZERO,
+      next: Mutex::new(Instant::now()),
+    }
+  }
+
+  /// Hold back every request for `duration` from now
+  pub async fn pause(&self, duration: Duration) {
+    let mut next = self.next.lock().await;
+    *next = (*next).max(Instant::now() + duration);
+  }
+
+  /// Wait until the next request is allowed to send
+  pub async fn acquire(&self) {
+    let at = {
+      let mut next = self.next.lock().await;
+      let at = (*next).max(Instant::now());
+      *next = at + self.interval;
+      at
+    };
+    sleep_until(at).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use tokio::time::Instant;
+
+  use crate::api::limiter::RateLimiter;
+
+  #[tokio::test(start_paused = true)]
+  async fn rate_limiter_test() {
+    let limiter = RateLimiter::new(10);
+    let start = Instant::now();
+    for _ in 0..11 {
+      limiter.acquire().await;
+    }
+    assert_eq!(start.elapsed(), Duration::from_secs(1));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn pause_test() {
+    let limiter = RateLimiter::unlimited();
+    let start = Instant::now();
+    limiter.acquire().await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    limiter.pause(Duration::from_secs(60)).await;
+    limiter.pause(Duration::from_secs(10)).await;
+    limiter.acquire().await;
+    limiter.acquire().await;
+    assert_eq!(start.elapsed(), Duration::from_secs(60));
+  }
+}
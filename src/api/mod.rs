@@ -1,101 +1,515 @@
-use std::sync::{Arc, RwLock};
+use std::any::type_name;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
-use log::debug;
-use reqwest::{Client, Proxy, Url};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use futures_util::future::try_join_all;
+use itertools::Itertools;
+use log::{debug, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Client, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{info_span, Instrument};
 
 use crate::api::data::{
-  AgentListResp, DepartmentMembersResp, DepartmentResp, GetTokenResp, Success, TagMembersResp,
-  TagsResp,
+  AgentListResp, ApiError, DepartmentMember, DepartmentMembersResp, DepartmentResp, ErrorResp,
+  ExternalContactDetailResp, ExternalContactListResp, GetTokenResp, LenientMembersResp, Rejected,
+  SimpleMembersResp, Success, TagMembersResp, TagsResp, UnexpectedResponse,
 };
 
+use crate::util::{expand_env, ReplaceSpecial};
+
+use self::limiter::RateLimiter;
+use self::proxy::ProxyConfig;
+use self::stats::{BudgetExhausted, RequestStats};
+use self::tls::ClientIdentity;
+
 use self::data::AgentDetail;
 
+pub const API_BASE: &str = "https://qyapi.weixin.qq.com/cgi-bin";
+pub const DEFAULT_RETRIES: u32 = 3;
+const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Id of the next request, to tell the logs of concurrent requests apart
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+pub const DEFAULT_RATE_LIMIT_COOLDOWN: Duration = Duration::from_secs(60);
+/// Generous enough for the member list of a huge department
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 256 * 1024 * 1024;
 const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Macintosh; Intel Mac OS X 12_5) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/15.6 Safari/605.1.15";
 
-mod data;
+pub mod command;
+pub mod data;
+pub mod dump;
+pub mod limiter;
+pub mod proxy;
+pub mod stats;
+pub mod tls;
 
 #[derive(Clone)]
 pub struct WxClient {
   client: Client,
-  pub token: Arc<RwLock<Option<String>>>,
+  token: Arc<RwLock<Option<String>>>,
+  /// When the token from [WxClient::login] expires
+  token_expires_at: Arc<RwLock<Option<DateTime<Local>>>>,
+  /// Token of the contact sync secret, used for departments, members and tags if set
+  contact_token: Arc<RwLock<Option<String>>>,
+  /// Shell command printing the token, run again when the token expires
+  token_command: Option<Arc<String>>,
+  refreshing: Arc<tokio::sync::Mutex<()>>,
+  raw_dir: Option<PathBuf>,
+  offline_dir: Option<PathBuf>,
+  limiter: Arc<RateLimiter>,
+  /// How long every request pauses after hitting the frequency limit
+  rate_limit_cooldown: Duration,
+  retries: u32,
+  retry_jitter: Jitter,
+  /// User agents used in turn, one per request
+  user_agents: Arc<Vec<String>>,
+  next_user_agent: Arc<AtomicUsize>,
+  stats: Arc<RequestStats>,
+  /// Max requests sent by this client and its clones, unlimited if `None`
+  max_requests: Option<u64>,
+  /// Max bytes read from the body of an API response
+  max_response_bytes: u64,
 }
 
 impl WxClient {
+  /// `pool_size` is the max idle connections kept per host, unlimited if `None`
   pub async fn new(
-    proxy: Option<Url>,
-    auth_user: Option<String>,
-    auth_pwd: Option<String>,
+    proxy: &ProxyConfig,
     user_agent: Option<String>,
+    headers: HeaderMap,
+    pool_size: Option<usize>,
+    identity: Option<&ClientIdentity>,
   ) -> Result<WxClient> {
-    let mut builder = Client::builder().pool_max_idle_per_host(0);
-    if let Some(proxy) = proxy {
-      let mut proxy = Proxy::all(proxy)?;
-      if auth_user.is_some() && auth_pwd.is_some() {
-        proxy = proxy.basic_auth(&*auth_user.unwrap(), &*auth_pwd.unwrap())
-      }
+    let mut builder = tls::select_backend(Client::builder()).default_headers(headers);
+    if let Some(pool_size) = pool_size {
+      builder = builder.pool_max_idle_per_host(pool_size);
+    }
+    if let Some(identity) = identity {
+      builder = identity.apply(builder)?;
+    }
+    for proxy in proxy.proxies()? {
       builder = builder.proxy(proxy)
     }
-    builder = builder.user_agent(user_agent.unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()));
     let reqwest = builder.build().context("Failed to create reqwest client")?;
     Ok(WxClient {
       client: reqwest,
       token: Arc::new(RwLock::new(None)),
+      token_expires_at: Arc::new(RwLock::new(None)),
+      contact_token: Arc::new(RwLock::new(None)),
+      token_command: None,
+      refreshing: Arc::new(tokio::sync::Mutex::new(())),
+      raw_dir: None,
+      offline_dir: None,
+      limiter: Arc::new(RateLimiter::unlimited()),
+      rate_limit_cooldown: DEFAULT_RATE_LIMIT_COOLDOWN,
+      retries: DEFAULT_RETRIES,
+      retry_jitter: Jitter::default(),
+      user_agents: Arc::new(vec![
+        user_agent.unwrap_or_else(|| DEFAULT_USER_AGENT.to_string())
+      ]),
+      next_user_agent: Arc::new(AtomicUsize::new(0)),
+      stats: Arc::new(RequestStats::default()),
+      max_requests: None,
+      max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
     })
   }
 
-  fn token(&self) -> Result<String> {
-    let result = self.token.read().unwrap();
-    match result.clone() {
-      Some(some) => Ok(some),
-      None => {
-        debug!("Token: {:?}", &self.token);
-        Err(anyhow!("Token is None, not login"))
+  /// Limit the rate of all requests sent by this client and its clones
+  pub fn set_rate_limiter(&mut self, limiter: Arc<RateLimiter>) {
+    self.limiter = limiter;
+  }
+
+  /// Pause every request sent by this client and its clones for `cooldown`
+  /// after hitting the frequency limit, since retrying earlier only prolongs the block
+  pub fn set_rate_limit_cooldown(&mut self, cooldown: Duration) {
+    self.rate_limit_cooldown = cooldown;
+  }
+
+  /// Max retries of a request on transient failures, like network errors or 5xx
+  pub fn set_retries(&mut self, retries: u32) {
+    self.retries = retries;
+  }
+
+  /// Randomize the backoff between retries, so requests failed together do not retry at once
+  pub fn set_retry_jitter(&mut self, jitter: Jitter) {
+    self.retry_jitter = jitter;
+  }
+
+  /// Rotate through `user_agents` round-robin, one per request, ignored if empty
+  pub fn set_user_agents(&mut self, user_agents: Vec<String>) {
+    if !user_agents.is_empty() {
+      self.user_agents = Arc::new(user_agents);
+    }
+  }
+
+  fn user_agent(&self) -> &str {
+    let next = self.next_user_agent.fetch_add(1, Ordering::Relaxed);
+    &self.user_agents[next % self.user_agents.len()]
+  }
+
+  /// Read the responses saved by [WxClient::set_raw_dir] from `dir` instead of sending requests
+  pub fn set_offline_dir(&mut self, dir: Option<PathBuf>) {
+    self.offline_dir = dir;
+  }
+
+  /// Save every raw response body into `dir` before deserializing it
+  pub fn set_raw_dir(&mut self, dir: Option<PathBuf>) {
+    self.raw_dir = dir;
+  }
+
+  /// Fail the responses of the API larger than `max_response_bytes` instead of reading them
+  /// into memory, against a misbehaving proxy or gateway
+  pub fn set_max_response_bytes(&mut self, max_response_bytes: u64) {
+    self.max_response_bytes = max_response_bytes;
+  }
+
+  /// Refuse new requests after `max_requests` were sent, to stay within the daily quota
+  pub fn set_max_requests(&mut self, max_requests: Option<u64>) {
+    self.max_requests = max_requests;
+  }
+
+  /// Use a token minted elsewhere, like `--corp-token`, replacing the current one
+  pub fn set_token(&self, token: String) {
+    *self.token.write().unwrap_or_else(PoisonError::into_inner) = Some(token);
+  }
+
+  /// The token from the last login or [WxClient::set_token], [None] before any
+  pub fn current_token(&self) -> Option<String> {
+    self
+      .token
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+      .clone()
+  }
+
+  /// Get the token from the stdout of a shell command, see [WxClient::refresh_token]
+  pub fn set_token_command(&mut self, command: Option<String>) {
+    self.token_command = command.map(Arc::new);
+  }
+
+  /// Run the token command and use its output as the token, skipped if the token is no
+  /// longer `expired`, which means another request refreshed it already
+  pub async fn refresh_token(&self, expired: Option<&str>) -> Result<()> {
+    let Some(command) = &self.token_command else {
+      return Err(anyhow!("No token command to refresh the token"));
+    };
+    let _refreshing = self.refreshing.lock().await;
+    if expired.is_some() && self.current_token().as_deref() != expired {
+      return Ok(());
+    }
+    let token = command::run_token_command(command).await?;
+    self.set_token(token);
+    Ok(())
+  }
+
+  /// Statistics of the requests sent by this client and its clones
+  pub fn stats(&self) -> &RequestStats {
+    &self.stats
+  }
+
+  /// Start over the statistics and the request budget, for another run with this client,
+  /// clones made before keep the old ones
+  pub fn reset_stats(&mut self) {
+    self.stats = Arc::new(RequestStats::default());
+  }
+
+  /// The token for `path`, the contact token for the address book if any
+  fn token(&self, path: &str) -> Result<String> {
+    let is_contact = ["department/", "user/", "tag/"]
+      .iter()
+      .any(|i| path.starts_with(i));
+    if is_contact {
+      if let Some(token) = self
+        .contact_token
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+      {
+        return Ok(token);
       }
     }
+    self
+      .current_token()
+      .ok_or_else(|| anyhow!("Token is None, not login"))
   }
 
   fn client(&self) -> Client {
     self.client.clone()
   }
 
-  pub async fn login(&self, corp_id: &str, secret: &str) -> Result<GetTokenResp> {
+  /// Send a GET request to the API, retry with backoff on transient failures
+  async fn request<T: DeserializeOwned>(&self, path: &str, query: &[(&str, &str)]) -> Result<T> {
+    let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let span = info_span!("request", id, endpoint = endpoint(path, query));
+    async {
+      let mut attempt = 0;
+      loop {
+        if !self.stats.reserve(self.max_requests) {
+          return Err(BudgetExhausted).with_context(|| format!("{path} is not sent"));
+        }
+        let start = Instant::now();
+        let result = self.request_once(path, query).await;
+        self.stats.record(path, start.elapsed(), result.is_ok());
+        let err = match result {
+          Ok(resp) => return Ok(resp),
+          Err(err) => err,
+        };
+        if !is_transient(&err) {
+          return Err(err);
+        }
+        if attempt >= self.retries {
+          return Err(err.context(format!("Retries exhausted after {} attempts", attempt + 1)));
+        }
+        attempt += 1;
+        self.stats.record_retry(path);
+        if ApiError::find(&err).is_some_and(|i| i.code == ApiError::FREQUENCY_LIMITED) {
+          warn!(
+            "Frequency limited on {path}, pause all requests for {:?}, retry {attempt}/{}",
+            self.rate_limit_cooldown, self.retries
+          );
+          // the next request waits for the cool-down in the limiter
+          self.limiter.pause(self.rate_limit_cooldown).await;
+          continue;
+        }
+        let backoff = self.retry_jitter.apply(retry_backoff(attempt));
+        warn!(
+          "Request to {path} failed, retry {attempt}/{} in {backoff:?}: {err:#}",
+          self.retries
+        );
+        sleep(backoff).await;
+      }
+    }
+    .instrument(span)
+    .await
+  }
+
+  /// Send a GET request to the API, then check the `errcode` before deserializing
+  async fn request_once<T: DeserializeOwned>(
+    &self,
+    path: &str,
+    query: &[(&str, &str)],
+  ) -> Result<T> {
+    let name = type_name::<T>().rsplit("::").next().unwrap_or_default();
+    let endpoint = endpoint(path, query);
+    let (text, source) = match &self.offline_dir {
+      Some(dir) => {
+        let file = dir.join(raw_name(path, query));
+        let text = fs::read_to_string(&file)
+          .with_context(|| format!("No saved raw response {}", file.to_string_lossy()))?;
+        (text, format!("{endpoint} (offline)"))
+      }
+      None => {
+        let (status, text) = self.fetch_text(path, query, name, &endpoint).await?;
+        (text, format!("{endpoint} (HTTP {status})"))
+      }
+    };
+    if let Some(dir) = &self.raw_dir {
+      self.save_raw(dir, path, query, &text);
+    }
+    let error = serde_json::from_str::<ErrorResp>(&text).with_context(|| {
+      format!(
+        "Failed to deserialize {name} from {source}: {}",
+        snippet(&text)
+      )
+    })?;
+    match error.code {
+      Some(code) if code != 0 => Err(ApiError {
+        code,
+        msg: error.msg.unwrap_or_default(),
+      })
+      .with_context(|| format!("Failed to get {name} from {source}")),
+      _ => serde_json::from_str::<T>(&text)
+        .with_context(|| format!("Failed to deserialize {name} from {source}")),
+    }
+  }
+
+  /// Fetch the body of a JSON response with its HTTP status, `endpoint` is for error messages
+  async fn fetch_text(
+    &self,
+    path: &str,
+    query: &[(&str, &str)],
+    name: &str,
+    endpoint: &str,
+  ) -> Result<(u16, String)> {
+    self.limiter.acquire().await;
     let resp = self
       .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/gettoken")
-      .query(&[("corpid", corp_id), ("corpsecret", secret)])
+      .get(format!("{API_BASE}/{path}"))
+      .query(query)
+      .header(USER_AGENT, self.user_agent())
       .send()
       .await
-      .context("Failed to to get token")?;
-    let resp = resp
-      .json::<GetTokenResp>()
+      // the url contains credentials in query
+      .map_err(reqwest::Error::without_url)
+      .with_context(|| format!("Failed to get {name} from {endpoint}"))?;
+    let status = resp.status();
+    let content_type = resp
+      .headers()
+      .get(CONTENT_TYPE)
+      .and_then(|i| i.to_str().ok())
+      .map(String::from);
+    let body = read_limited(resp, self.max_response_bytes)
       .await
-      .context("Failed to deserialize GetTokenResp")?;
-
-    if resp.is_success() && resp.access_token.is_some() {
-      let mut token = self.token.write().unwrap();
-      *token = Some(resp.access_token.clone().unwrap());
-    } else {
-      return Err(anyhow!("Failed to get token: {:#?}", resp));
+      .with_context(|| format!("Failed to read {name} from {endpoint} (HTTP {status})"))?;
+    let text = String::from_utf8_lossy(&body).into_owned();
+    // read the body first, so an error page from a proxy is shown instead of a serde error
+    if !status.is_success() || !text.trim_start().starts_with('{') {
+      return Err(UnexpectedResponse {
+        status: status.as_u16(),
+        content_type,
+        snippet: snippet(&text),
+      })
+      .with_context(|| format!("Failed to get {name} from {endpoint}"));
     }
+    Ok((status.as_u16(), text))
+  }
 
-    debug!("login: {resp:?}");
+  /// Send a HEAD request to the API host, any response means it is reachable, to check
+  /// the proxy before login
+  pub async fn preflight(&self) -> Result<()> {
+    self
+      .client()
+      .head(API_BASE)
+      .header(USER_AGENT, self.user_agent())
+      .timeout(PREFLIGHT_TIMEOUT)
+      .send()
+      .await?;
+    Ok(())
+  }
 
-    Ok(resp)
+  /// Send a GET request to a url outside the API, like an avatar, the body is left unread
+  pub async fn get_file(&self, url: &str) -> Result<Response> {
+    self
+      .client()
+      .get(url)
+      .header(USER_AGENT, self.user_agent())
+      .send()
+      .await
+      .and_then(|resp| resp.error_for_status())
+      .with_context(|| format!("Failed to get {url}"))
   }
 
-  /// get apps basic info
-  pub async fn get_agent_list(&self) -> Result<AgentListResp> {
+  /// Send a POST request with a JSON body to a url outside the API, like a webhook
+  pub async fn post_json<T: Serialize + ?Sized>(&self, url: &Url, body: &T) -> Result<()> {
     self
       .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/agent/list")
-      .query(&[("access_token", self.token()?)])
+      .post(url.clone())
+      .header(USER_AGENT, self.user_agent())
+      .json(body)
       .send()
       .await
-      .context("Failed to get AgentListResp")?
-      .json::<AgentListResp>()
+      .and_then(|resp| resp.error_for_status())
+      .with_context(|| format!("Failed to post to {url}"))?;
+    Ok(())
+  }
+
+  /// Save a raw response body to `<dir>/<endpoint>-<id>.json`, responses with token are skipped
+  fn save_raw(&self, dir: &Path, path: &str, query: &[(&str, &str)], text: &str) {
+    if path == "gettoken" {
+      return;
+    }
+    let file = dir.join(raw_name(path, query));
+    if let Err(err) = fs::write(&file, text) {
+      warn!(
+        "Failed to save raw response to {}: {err:?}",
+        file.to_string_lossy()
+      );
+    }
+  }
+
+  pub async fn login(&self, corp_id: &str, secret: &str) -> Result<GetTokenResp> {
+    let resp = self.fetch_token(corp_id, secret).await?;
+    *self.token.write().unwrap_or_else(PoisonError::into_inner) = resp.access_token.clone();
+    *self
+      .token_expires_at
+      .write()
+      .unwrap_or_else(PoisonError::into_inner) = resp
+      .expires_in
+      .map(|i| Local::now() + chrono::Duration::seconds(i.into()));
+    Ok(resp)
+  }
+
+  /// When the token expires, [None] if unknown, like a token provided directly
+  pub fn token_expires_at(&self) -> Option<DateTime<Local>> {
+    *self
+      .token_expires_at
+      .read()
+      .unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Login with the secret of contact sync (通讯录同步), whose token is used for departments,
+  /// members and tags, while the app secret is still used for the others like agents
+  pub async fn login_contact(&self, corp_id: &str, secret: &str) -> Result<GetTokenResp> {
+    let resp = self.fetch_token(corp_id, secret).await?;
+    *self
+      .contact_token
+      .write()
+      .unwrap_or_else(PoisonError::into_inner) = resp.access_token.clone();
+    Ok(resp)
+  }
+
+  async fn fetch_token(&self, corp_id: &str, secret: &str) -> Result<GetTokenResp> {
+    let resp = self
+      .request::<GetTokenResp>("gettoken", &[("corpid", corp_id), ("corpsecret", secret)])
       .await
-      .context("Failed to deserialize AgentListResp")
+      .map_err(|err| match ApiError::find(&err) {
+        Some(api_err) if api_err.hint().is_some() => ApiError::with_hint(err),
+        Some(api_err) if api_err.is_credential_error() => {
+          err.context("Credentials were rejected, check the corp id and secret")
+        }
+        _ => err.context("Failed to get token"),
+      })?;
+
+    if !resp.is_success() || resp.access_token.is_none() {
+      return Err(anyhow!("Failed to get token: {:#?}", resp));
+    }
+
+    debug!("login: token expires in {:?}s", resp.expires_in);
+
+    Ok(resp)
+  }
+
+  /// Send a GET request to any `cgi-bin` endpoint with the token, like `user/get`,
+  /// and deserialize the response into `T` after checking `errcode`
+  pub async fn get_json<T: DeserializeOwned>(
+    &self,
+    path: &str,
+    params: &[(&str, &str)],
+  ) -> Result<T> {
+    let token = self.token(path)?;
+    let mut query = vec![("access_token", &*token)];
+    query.extend_from_slice(params);
+    let result = self.request(path, &query).await;
+    match result {
+      Err(err)
+        if self.token_command.is_some()
+          && ApiError::find(&err).is_some_and(|i| i.code == ApiError::ACCESS_TOKEN_EXPIRED) =>
+      {
+        warn!("Token expired on {path}, refresh with the token command");
+        self.refresh_token(Some(&token)).await?;
+        let token = self.token(path)?;
+        query[0] = ("access_token", &*token);
+        self
+          .request(path, &query)
+          .await
+          .map_err(ApiError::with_hint)
+      }
+      result => result.map_err(ApiError::with_hint),
+    }
+  }
+
+  /// get apps basic info
+  pub async fn get_agent_list(&self) -> Result<AgentListResp> {
+    self.get_json("agent/list", &[]).await
   }
 
   pub async fn get_all_departments(&self) -> Result<DepartmentResp> {
@@ -104,18 +518,16 @@ impl WxClient {
 
   /// get departments
   /// ## params
-  /// - id: [None] for getting all departments with access
-  pub async fn get_departments(&self, _id: Option<u32>) -> Result<DepartmentResp> {
-    self
-      .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/department/list")
-      .query(&[("access_token", self.token()?)])
-      .send()
-      .await
-      .context("Failed to get DepartmentResp")?
-      .json::<DepartmentResp>()
-      .await
-      .context("Failed to deserialize DepartmentResp")
+  /// - id: [None] for getting all departments with access, otherwise the department and its children
+  pub async fn get_departments(&self, id: Option<u32>) -> Result<DepartmentResp> {
+    match id {
+      Some(id) => {
+        self
+          .get_json("department/list", &[("id", &id.to_string())])
+          .await
+      }
+      None => self.get_json("department/list", &[]).await,
+    }
   }
 
   /// get department members
@@ -125,70 +537,222 @@ impl WxClient {
     fetch_child: bool,
   ) -> Result<DepartmentMembersResp> {
     self
-      .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/user/list")
-      .query(&[
-        ("access_token", self.token()?),
-        ("department_id", id.to_string()),
-        (
-          "fetch_child",
-          match fetch_child {
-            true => "1".to_string(),
-            false => "0".to_string(),
-          },
-        ),
-      ])
-      .send()
+      .get_json(
+        "user/list",
+        &[
+          ("department_id", &id.to_string()),
+          ("fetch_child", if fetch_child { "1" } else { "0" }),
+        ],
+      )
       .await
-      .context("Failed to get DepartmentMembersResp")?
-      .json::<DepartmentMembersResp>()
+  }
+
+  /// Members of several departments, fetched concurrently under the shared rate limiter and
+  /// combined, each listed once in the order of `ids`
+  pub async fn get_members_for_departments(
+    &self,
+    ids: &[u32],
+    fetch_child: bool,
+  ) -> Result<Vec<DepartmentMember>> {
+    let resps = try_join_all(ids.iter().map(|&id| async move {
+      self
+        .get_department_members(id, fetch_child)
+        .await
+        .with_context(|| format!("Failed to get the members of department {id}"))
+    }))
+    .await?;
+    let mut seen = HashSet::new();
+    let members = resps.into_iter().flat_map(|x| x.members);
+    Ok(members.filter(|x| seen.insert(x.user_id.clone())).collect())
+  }
+
+  /// Same as [WxClient::get_department_members], but the members failed to deserialize are
+  /// returned aside instead of failing the whole response
+  pub async fn get_department_members_lenient(
+    &self,
+    id: u32,
+    fetch_child: bool,
+  ) -> Result<(DepartmentMembersResp, Vec<Rejected>)> {
+    let resp: LenientMembersResp = self
+      .get_json(
+        "user/list",
+        &[
+          ("department_id", &id.to_string()),
+          ("fetch_child", if fetch_child { "1" } else { "0" }),
+        ],
+      )
+      .await?;
+    Ok(resp.split())
+  }
+
+  /// get department members with only user id, name and departments,
+  /// which may be allowed when [WxClient::get_department_members] is not
+  pub async fn get_department_members_simple(
+    &self,
+    id: u32,
+    fetch_child: bool,
+  ) -> Result<SimpleMembersResp> {
+    self
+      .get_json(
+        "user/simplelist",
+        &[
+          ("department_id", &id.to_string()),
+          ("fetch_child", if fetch_child { "1" } else { "0" }),
+        ],
+      )
       .await
-      .context("Failed to deserialize DepartmentMembersResp")
   }
 
   pub async fn get_tags(&self) -> Result<TagsResp> {
+    self.get_json("tag/list", &[]).await
+  }
+
+  pub async fn get_tag_members(&self, tag_id: u32) -> Result<TagMembersResp> {
     self
-      .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/tag/list")
-      .query(&[("access_token", self.token()?)])
-      .send()
-      .await
-      .context("Failed to get TagsResp")?
-      .json::<TagsResp>()
+      .get_json("tag/get", &[("tagid", &tag_id.to_string())])
       .await
-      .context("Failed to deserialize TagsResp")
   }
 
-  pub async fn get_tag_members(&self, tag_id: u32) -> Result<TagMembersResp> {
+  /// get external contact ids of a member, requires the permission of external contacts
+  pub async fn get_external_contact_list(&self, user_id: &str) -> Result<ExternalContactListResp> {
     self
-      .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/tag/get")
-      .query(&[
-        ("access_token", self.token()?),
-        ("tagid", tag_id.to_string()),
-      ])
-      .send()
+      .get_json("externalcontact/list", &[("userid", user_id)])
       .await
-      .context("Failed to get TagMembersResp")?
-      .json::<TagMembersResp>()
+  }
+
+  pub async fn get_external_contact_detail(
+    &self,
+    external_user_id: &str,
+  ) -> Result<ExternalContactDetailResp> {
+    self
+      .get_json(
+        "externalcontact/get",
+        &[("external_userid", external_user_id)],
+      )
       .await
-      .context("Failed to deserialize TagMembersResp")
   }
 
   pub async fn get_agent_detail(&self, agent_id: u32) -> Result<AgentDetail> {
     self
-      .client()
-      .get("https://qyapi.weixin.qq.com/cgi-bin/agent/get")
-      .query(&[
-        ("access_token", self.token()?),
-        ("agentid", agent_id.to_string()),
-      ])
-      .send()
+      .get_json("agent/get", &[("agentid", &agent_id.to_string())])
       .await
-      .context("Failed to get AgentDetail")?
-      .json::<AgentDetail>()
-      .await
-      .context("Failed to deserialize AgentDetail")
+  }
+}
+
+/// `path` with the query except credentials, like `user/list?department_id=1`
+fn endpoint(path: &str, query: &[(&str, &str)]) -> String {
+  let query = query
+    .iter()
+    .filter(|(key, _)| !["access_token", "corpsecret"].contains(key))
+    .map(|(key, value)| format!("{key}={value}"))
+    .join("&");
+  match query.is_empty() {
+    true => path.to_string(),
+    false => format!("{path}?{query}"),
+  }
+}
+
+/// File name of a raw response, like `user-list-1-0.json`, the query values except the token
+/// are joined to the endpoint
+fn raw_name(path: &str, query: &[(&str, &str)]) -> String {
+  let name = query
+    .iter()
+    .filter(|(key, _)| *key != "access_token")
+    .map(|(_, value)| *value)
+    .fold(path.replace('/', "-"), |acc, i| format!("{acc}-{i}"));
+  format!("{name}.json").replace_special_char()
+}
+
+/// Parse a header like `X-Request-ID: 1`, the value is marked sensitive to keep it out of logs
+pub fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue)> {
+  let s = expand_env(s)?;
+  let (name, value) = s
+    .split_once(':')
+    .context("Expected \"Key: Value\", like \"X-Request-ID: 1\"")?;
+  let name = HeaderName::from_bytes(name.trim().as_bytes())
+    .with_context(|| format!("Invalid header name {:?}", name.trim()))?;
+  let mut value = HeaderValue::from_str(value.trim())
+    .with_context(|| format!("Invalid value of header {name}"))?;
+  value.set_sensitive(true);
+  Ok((name, value))
+}
+
+/// Network errors, 5xx, 429 and busy responses are worth retrying,
+/// while other errors like rejected credentials are not
+fn is_transient(err: &anyhow::Error) -> bool {
+  if let Some(api_err) = ApiError::find(err) {
+    return api_err.is_transient();
+  }
+  let transient_status =
+    |status: StatusCode| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS;
+  err.chain().any(|err| {
+    if let Some(err) = err.downcast_ref::<UnexpectedResponse>() {
+      return StatusCode::from_u16(err.status).is_ok_and(transient_status);
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+      Some(err) => match err.status() {
+        Some(status) => transient_status(status),
+        None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+      },
+      None => false,
+    }
+  })
+}
+
+/// Read the body of `resp` chunk by chunk, failing once it exceeds `limit` bytes instead of
+/// buffering all of it
+async fn read_limited(mut resp: Response, limit: u64) -> Result<Vec<u8>> {
+  let too_large = || anyhow!("Response body exceeds --max-response-bytes of {limit} bytes");
+  if resp.content_length().is_some_and(|len| len > limit) {
+    return Err(too_large());
+  }
+  let mut body = Vec::new();
+  while let Some(chunk) = resp.chunk().await.map_err(reqwest::Error::without_url)? {
+    if (body.len() + chunk.len()) as u64 > limit {
+      return Err(too_large());
+    }
+    body.extend_from_slice(&chunk);
+  }
+  Ok(body)
+}
+
+/// The beginning of a response body in one line, to show in errors
+fn snippet(text: &str) -> String {
+  const MAX_CHARS: usize = 200;
+  let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+  match text.char_indices().nth(MAX_CHARS) {
+    Some((i, _)) => format!("{}...", &text[..i]),
+    None => text,
+  }
+}
+
+/// Exponential backoff from 500ms, capped at 30s
+fn retry_backoff(attempt: u32) -> Duration {
+  Duration::from_millis(500)
+    .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+    .min(Duration::from_secs(30))
+}
+
+/// How the backoff between retries is randomized
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Jitter {
+  /// The exact backoff
+  None,
+  /// Random between 0 and the backoff
+  #[default]
+  Full,
+  /// Half the backoff, plus random up to the other half
+  Equal,
+}
+
+impl Jitter {
+  fn apply(self, backoff: Duration) -> Duration {
+    let random = |max: Duration| max.mul_f64(fastrand::f64());
+    match self {
+      Jitter::None => backoff,
+      Jitter::Full => random(backoff),
+      Jitter::Equal => backoff / 2 + random(backoff / 2),
+    }
   }
 }
 
@@ -201,8 +765,18 @@ mod tests {
 
   use lazy_static::lazy_static;
   use log::debug;
+  use reqwest::header::HeaderMap;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  use tokio::net::TcpListener;
 
-  use crate::api::WxClient;
+  use std::time::Duration;
+
+  use crate::api::data::UnexpectedResponse;
+  use crate::api::data::{ApiError, Department, DepartmentResp, TagMembersResp};
+  use crate::api::proxy::ProxyConfig;
+  use crate::api::{
+    endpoint, is_transient, parse_header, read_limited, retry_backoff, snippet, Jitter, WxClient,
+  };
   use crate::init_logger;
 
   lazy_static! {
@@ -211,7 +785,7 @@ mod tests {
 
   async fn client() -> Result<WxClient> {
     init_logger("debug");
-    let cli = WxClient::new(None, None, None, None).await?;
+    let cli = WxClient::new(&ProxyConfig::default(), None, HeaderMap::new(), None, None).await?;
     let option = { TOKEN.read().unwrap().clone() };
     match option {
       None => {
@@ -230,7 +804,7 @@ mod tests {
           *token = Some(ac);
         }
       }
-      some @ Some(_) => *cli.token.write().unwrap() = some,
+      Some(token) => cli.set_token(token),
     };
     Ok(cli)
   }
@@ -278,6 +852,167 @@ mod tests {
     Ok(())
   }
 
+  #[test]
+  fn retry_backoff_test() {
+    assert_eq!(retry_backoff(1), Duration::from_millis(500));
+    assert_eq!(retry_backoff(2), Duration::from_secs(1));
+    assert_eq!(retry_backoff(4), Duration::from_secs(4));
+    assert_eq!(retry_backoff(20), Duration::from_secs(30));
+
+    let backoff = Duration::from_secs(4);
+    assert_eq!(Jitter::None.apply(backoff), backoff);
+    for _ in 0..100 {
+      assert!(Jitter::Full.apply(backoff) <= backoff);
+      let equal = Jitter::Equal.apply(backoff);
+      assert!(equal >= backoff / 2 && equal <= backoff, "{equal:?}");
+    }
+  }
+
+  #[test]
+  fn is_transient_test() {
+    let busy = anyhow::Error::new(ApiError {
+      code: -1,
+      msg: "system busy".to_string(),
+    });
+    assert!(is_transient(&busy.context("Failed to get DepartmentResp")));
+    let rejected = anyhow::Error::new(ApiError {
+      code: 40001,
+      msg: "invalid credential".to_string(),
+    });
+    assert!(!is_transient(&rejected));
+    assert!(!is_transient(&anyhow::anyhow!("Failed to deserialize")));
+    let bad_gateway = anyhow::Error::new(UnexpectedResponse {
+      status: 502,
+      content_type: Some("text/html".to_string()),
+      snippet: String::new(),
+    });
+    assert!(is_transient(&bad_gateway));
+  }
+
+  #[test]
+  fn endpoint_test() {
+    let query = [("access_token", "secret"), ("department_id", "1")];
+    assert_eq!(endpoint("user/list", &query), "user/list?department_id=1");
+    let query = [("corpid", "ww1"), ("corpsecret", "secret")];
+    assert_eq!(endpoint("gettoken", &query), "gettoken?corpid=ww1");
+    assert_eq!(endpoint("agent/list", &[]), "agent/list");
+  }
+
+  #[test]
+  fn snippet_test() {
+    assert_eq!(
+      snippet("<html>\n  <body>Login</body>\n</html>"),
+      "<html> <body>Login</body> </html>"
+    );
+    let long = "测".repeat(300);
+    assert_eq!(snippet(&long), format!("{}...", "测".repeat(200)));
+  }
+
+  #[tokio::test]
+  async fn read_limited_test() -> Result<()> {
+    // a body of 100 bytes, with or without Content-Length
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+      for length in ["Content-Length: 100\r\n", ""].into_iter().cycle() {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).await;
+        let head = format!("HTTP/1.1 200 OK\r\n{length}Connection: close\r\n\r\n");
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(&[b'x'; 100]).await;
+      }
+    });
+    let client = reqwest::Client::builder().no_proxy().build()?;
+    let url = format!("http://{addr}");
+    for _ in 0..2 {
+      let resp = client.get(&url).send().await?;
+      assert_eq!(read_limited(resp, 100).await?.len(), 100);
+      let resp = client.get(&url).send().await?;
+      let err = read_limited(resp, 99).await.unwrap_err();
+      assert!(err.to_string().contains("--max-response-bytes"), "{err:?}");
+    }
+    Ok(())
+  }
+
+  #[tokio::test]
+  async fn poisoned_token_test() -> Result<()> {
+    let cli = WxClient::new(&ProxyConfig::default(), None, HeaderMap::new(), None, None).await?;
+    cli.set_token("token".to_string());
+    let token = cli.token.clone();
+    let panicked = std::thread::spawn(move || {
+      let _guard = token.write().unwrap();
+      panic!("panic while holding the token");
+    })
+    .join();
+    assert!(panicked.is_err());
+    assert!(cli.token.is_poisoned());
+    assert_eq!(cli.token("agent/list")?, "token");
+    cli.set_token("another".to_string());
+    assert_eq!(cli.current_token().as_deref(), Some("another"));
+    Ok(())
+  }
+
+  #[test]
+  fn deserialize_department_test() -> Result<()> {
+    let department: Department =
+      serde_json::from_str(r#"{"id":2,"name":"Sales","parentid":1,"order":10}"#)?;
+    assert_eq!(department.name_en, None);
+    assert_eq!(department.department_leader, None);
+
+    let department: Department = serde_json::from_str(
+      r#"{"id":2,"name":"销售","name_en":"Sales","department_leader":["zhangsan"],"parentid":1,"order":10}"#,
+    )?;
+    assert_eq!(department.name_en.as_deref(), Some("Sales"));
+    assert_eq!(
+      department.department_leader,
+      Some(vec!["zhangsan".to_string()])
+    );
+    Ok(())
+  }
+
+  #[test]
+  fn parse_header_test() -> Result<()> {
+    let (name, value) = parse_header("X-Request-ID: abc:1")?;
+    assert_eq!(name.as_str(), "x-request-id");
+    assert_eq!(value.to_str()?, "abc:1");
+    assert_eq!(format!("{value:?}"), "Sensitive");
+    assert!(parse_header("X-Request-ID").is_err());
+    assert!(parse_header("X Request: 1").is_err());
+    assert!(parse_header("X-Request-ID: a\nb").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn sort_test() -> Result<()> {
+    let bytes = |json: &str| -> Result<Vec<u8>> {
+      let mut departments: DepartmentResp = serde_json::from_str(json)?;
+      departments.sort();
+      Ok(serde_json::to_vec_pretty(&departments)?)
+    };
+    let a = bytes(
+      r#"{"errcode":0,"department":[{"id":2,"name":"B","parentid":1,"order":1},{"id":1,"name":"A","parentid":0,"order":2}]}"#,
+    )?;
+    let b = bytes(
+      r#"{"errcode":0,"department":[{"id":1,"name":"A","parentid":0,"order":2},{"id":2,"name":"B","parentid":1,"order":1}]}"#,
+    )?;
+    assert_eq!(a, b);
+
+    let bytes = |json: &str| -> Result<Vec<u8>> {
+      let mut members: TagMembersResp = serde_json::from_str(json)?;
+      members.sort();
+      Ok(serde_json::to_vec_pretty(&members)?)
+    };
+    let a = bytes(
+      r#"{"errcode":0,"tagname":"T","userlist":[{"userid":"b","name":"B"},{"userid":"a","name":"A"}],"partylist":[3,2]}"#,
+    )?;
+    let b = bytes(
+      r#"{"errcode":0,"tagname":"T","userlist":[{"userid":"a","name":"A"},{"userid":"b","name":"B"}],"partylist":[2,3]}"#,
+    )?;
+    assert_eq!(a, b);
+    Ok(())
+  }
+
   #[tokio::test]
   async fn get_agent_list() -> Result<()> {
     let cli = client().await?;
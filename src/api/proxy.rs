@@ -0,0 +1,111 @@
+use anyhow::{Context, Result};
+use reqwest::{Proxy, Url};
+
+use crate::util::expand_env;
+
+/// Proxies to send requests through, checked from the most specific one:
+/// host-scoped proxies, then the scheme ones, then `all`
+#[derive(Default, Clone)]
+pub struct ProxyConfig {
+  pub all: Option<Url>,
+  pub http: Option<Url>,
+  pub https: Option<Url>,
+  /// Proxies only for requests to the given hosts
+  pub hosts: Vec<(String, Url)>,
+  /// Basic auth applied to every proxy
+  pub auth: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+  pub fn proxies(&self) -> Result<Vec<Proxy>> {
+    let mut proxies = Vec::new();
+    for (host, url) in &self.hosts {
+      let host = host.clone();
+      let url = url.clone();
+      proxies.push(Proxy::custom(move |target| {
+        (target.host_str() == Some(&*host)).then(|| url.clone())
+      }));
+    }
+    if let Some(url) = &self.http {
+      proxies.push(Proxy::http(url.clone()).context("Invalid http proxy")?);
+    }
+    if let Some(url) = &self.https {
+      proxies.push(Proxy::https(url.clone()).context("Invalid https proxy")?);
+    }
+    if let Some(url) = &self.all {
+      proxies.push(Proxy::all(url.clone()).context("Invalid proxy")?);
+    }
+    if let Some((user, password)) = &self.auth {
+      proxies = proxies
+        .into_iter()
+        .map(|proxy| proxy.basic_auth(user, password))
+        .collect();
+    }
+    Ok(proxies)
+  }
+
+  /// The proxy used for requests to `target`, in the same order as [ProxyConfig::proxies]
+  pub fn proxy_for(&self, target: &Url) -> Option<&Url> {
+    let host = self
+      .hosts
+      .iter()
+      .find(|(host, _)| target.host_str() == Some(host))
+      .map(|(_, url)| url);
+    let scheme = match target.scheme() {
+      "http" => self.http.as_ref(),
+      "https" => self.https.as_ref(),
+      _ => None,
+    };
+    host.or(scheme).or(self.all.as_ref())
+  }
+}
+
+/// Parse a host-scoped proxy like `qyapi.weixin.qq.com=socks5://127.0.0.1:1080`
+pub fn parse_host_proxy(s: &str) -> Result<(String, Url)> {
+  let s = expand_env(s)?;
+  let (host, url) = s
+    .split_once('=')
+    .context("Expected HOST=URL, like qyapi.weixin.qq.com=socks5://127.0.0.1:1080")?;
+  let url = Url::parse(url).with_context(|| format!("Invalid proxy url {url}"))?;
+  Ok((host.to_string(), url))
+}
+
+/// Parse a proxy url, with `${VAR}` replaced by environment variables
+pub fn parse_proxy(s: &str) -> Result<Url> {
+  let url = expand_env(s)?;
+  Url::parse(&url).with_context(|| format!("Invalid proxy url {url}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use reqwest::Url;
+
+  use crate::api::proxy::{parse_host_proxy, ProxyConfig};
+
+  #[test]
+  fn parse_host_proxy_test() {
+    let (host, url) = parse_host_proxy("qyapi.weixin.qq.com=socks5://127.0.0.1:1080").unwrap();
+    assert_eq!(host, "qyapi.weixin.qq.com");
+    assert_eq!(url.as_str(), "socks5://127.0.0.1:1080");
+    assert!(parse_host_proxy("socks5://127.0.0.1:1080").is_err());
+  }
+
+  #[test]
+  fn proxy_for_test() {
+    let url = |s: &str| Url::parse(s).unwrap();
+    let mut config = ProxyConfig::default();
+    let api = url("https://qyapi.weixin.qq.com/cgi-bin");
+    assert_eq!(config.proxy_for(&api), None);
+    config.all = Some(url("http://all:8080"));
+    config.http = Some(url("http://http:8080"));
+    assert_eq!(config.proxy_for(&api), config.all.as_ref());
+    config.https = Some(url("http://https:8080"));
+    assert_eq!(config.proxy_for(&api), config.https.as_ref());
+    config.hosts = vec![("qyapi.weixin.qq.com".to_string(), url("socks5://host:1080"))];
+    assert_eq!(config.proxy_for(&api), Some(&config.hosts[0].1));
+    assert_eq!(
+      config.proxy_for(&url("http://example.com")),
+      config.http.as_ref()
+    );
+  }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Counters and latencies of the requests sent to each endpoint
+#[derive(Debug, Default)]
+pub struct RequestStats {
+  endpoints: Mutex<BTreeMap<String, Endpoint>>,
+  /// Attempts started, including the ones in flight
+  started: AtomicU64,
+  budget_exhausted: AtomicBool,
+}
+
+/// A request refused by the budget of [RequestStats::reserve]
+#[derive(Debug, Clone, Copy)]
+pub struct BudgetExhausted;
+
+impl Display for BudgetExhausted {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "Request budget exhausted")
+  }
+}
+
+impl Error for BudgetExhausted {}
+
+#[derive(Debug, Default)]
+struct Endpoint {
+  succeeded: u64,
+  failed: u64,
+  retried: u64,
+  latencies: Vec<Duration>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EndpointReport {
+  /// Every attempt, including retries
+  pub requests: u64,
+  pub succeeded: u64,
+  pub failed: u64,
+  pub retried: u64,
+  pub p50_ms: u64,
+  pub p95_ms: u64,
+}
+
+impl RequestStats {
+  /// Count an attempt about to start, returns false without counting if `budget` is used up
+  pub fn reserve(&self, budget: Option<u64>) -> bool {
+    let reserved = self
+      .started
+      .fetch_update(
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+        |started| match budget {
+          Some(budget) if started >= budget => None,
+          _ => Some(started + 1),
+        },
+      )
+      .is_ok();
+    if !reserved {
+      self.budget_exhausted.store(true, Ordering::Relaxed);
+    }
+    reserved
+  }
+
+  /// Whether any request was refused by the budget of [RequestStats::reserve]
+  pub fn budget_exhausted(&self) -> bool {
+    self.budget_exhausted.load(Ordering::Relaxed)
+  }
+
+  /// Record an attempt of a request to `path`
+  pub fn record(&self, path: &str, latency: Duration, success: bool) {
+    let mut endpoints = self
+      .endpoints
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    let endpoint = endpoints.entry(path.to_string()).or_default();
+    match success {
+      true => endpoint.succeeded += 1,
+      false => endpoint.failed += 1,
+    }
+    endpoint.latencies.push(latency);
+  }
+
+  pub fn record_retry(&self, path: &str) {
+    let mut endpoints = self
+      .endpoints
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    endpoints.entry(path.to_string()).or_default().retried += 1;
+  }
+
+  pub fn report(&self) -> BTreeMap<String, EndpointReport> {
+    let endpoints = self
+      .endpoints
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner);
+    endpoints
+      .iter()
+      .map(|(path, endpoint)| {
+        let mut latencies = endpoint.latencies.clone();
+        latencies.sort_unstable();
+        let report = EndpointReport {
+          requests: endpoint.succeeded + endpoint.failed,
+          succeeded: endpoint.succeeded,
+          failed: endpoint.failed,
+          retried: endpoint.retried,
+          p50_ms: percentile(&latencies, 50).as_millis() as u64,
+          p95_ms: percentile(&latencies, 95).as_millis() as u64,
+        };
+        (path.clone(), report)
+      })
+      .collect()
+  }
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+  if sorted.is_empty() {
+    return Duration::ZERO;
+  }
+  let rank = (sorted.len() * percent).div_ceil(100);
+  sorted[rank.saturating_sub(1)]
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use crate::api::stats::RequestStats;
+
+  #[test]
+  fn request_stats_test() {
+    let stats = RequestStats::default();
+    for i in 1..=100 {
+      stats.record("user/list", Duration::from_millis(i), i != 100);
+    }
+    stats.record_retry("user/list");
+    let report = stats.report();
+    let report = &report["user/list"];
+    assert_eq!(report.requests, 100);
+    assert_eq!(report.succeeded, 99);
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.retried, 1);
+    assert_eq!(report.p50_ms, 50);
+    assert_eq!(report.p95_ms, 95);
+  }
+
+  #[test]
+  fn reserve_test() {
+    let stats = RequestStats::default();
+    assert!(stats.reserve(Some(2)));
+    assert!(stats.reserve(Some(2)));
+    assert!(!stats.budget_exhausted());
+    assert!(!stats.reserve(Some(2)));
+    assert!(stats.budget_exhausted());
+    assert!(stats.reserve(None));
+  }
+}
@@ -0,0 +1,146 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use reqwest::ClientBuilder;
+
+/// Client certificate presented to servers requiring mutual TLS, like an API gateway
+/// in front of the API
+#[derive(Debug, Clone)]
+pub enum ClientIdentity {
+  /// A PEM certificate chain and its PEM private key, PKCS#8 with native-tls
+  Pem { cert: PathBuf, key: PathBuf },
+  /// A PKCS#12 archive with both, protected by `password`, only with native-tls
+  Pkcs12 { path: PathBuf, password: String },
+}
+
+impl ClientIdentity {
+  /// Read and parse the files into `builder`, so a bad certificate fails on startup instead
+  /// of every request
+  pub fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder> {
+    let identity = match self {
+      ClientIdentity::Pem { cert, key } => backend::pem(
+        &read(cert, "client certificate")?,
+        &read(key, "client key")?,
+      )?,
+      ClientIdentity::Pkcs12 { path, password } => {
+        backend::pkcs12(&read(path, "client identity")?, password)?
+      }
+    };
+    Ok(backend::identity(builder, identity))
+  }
+}
+
+fn read(path: &Path, name: &str) -> Result<Vec<u8>> {
+  fs::read(path).with_context(|| format!("Failed to read {name} {}", path.to_string_lossy()))
+}
+
+/// Select the TLS backend of the enabled features, rustls wins if both are
+pub fn select_backend(builder: ClientBuilder) -> ClientBuilder {
+  #[cfg(feature = "rustls")]
+  let builder = builder.use_rustls_tls();
+  builder
+}
+
+#[cfg(feature = "rustls")]
+mod backend {
+  use anyhow::{bail, Context, Result};
+  use reqwest::{ClientBuilder, Identity};
+
+  pub fn pem(cert: &[u8], key: &[u8]) -> Result<Identity> {
+    Identity::from_pem(&[cert, b"\n", key].concat())
+      .context("Invalid client certificate or key, both must be in PEM")
+  }
+
+  pub fn pkcs12(_der: &[u8], _password: &str) -> Result<Identity> {
+    bail!("PKCS#12 client identities need the native-tls feature, use --client-cert instead")
+  }
+
+  pub fn identity(builder: ClientBuilder, identity: Identity) -> ClientBuilder {
+    builder.identity(identity)
+  }
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+mod backend {
+  use anyhow::{Context, Result};
+  use reqwest::{ClientBuilder, Identity};
+
+  pub fn pem(cert: &[u8], key: &[u8]) -> Result<Identity> {
+    Identity::from_pkcs8_pem(cert, key)
+      .context("Invalid client certificate or key, the key must be in PKCS#8 PEM")
+  }
+
+  pub fn pkcs12(der: &[u8], password: &str) -> Result<Identity> {
+    Identity::from_pkcs12_der(der, password)
+      .context("Invalid PKCS#12 client identity or wrong password")
+  }
+
+  pub fn identity(builder: ClientBuilder, identity: Identity) -> ClientBuilder {
+    builder.identity(identity)
+  }
+}
+
+/// Builds without a TLS backend can only reach plain HTTP, like --offline or a local mock
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+mod backend {
+  use std::convert::Infallible;
+
+  use anyhow::{bail, Result};
+  use reqwest::ClientBuilder;
+
+  const NO_TLS: &str = "Client certificates need the native-tls or rustls feature";
+
+  pub fn pem(_cert: &[u8], _key: &[u8]) -> Result<Infallible> {
+    bail!(NO_TLS)
+  }
+
+  pub fn pkcs12(_der: &[u8], _password: &str) -> Result<Infallible> {
+    bail!(NO_TLS)
+  }
+
+  pub fn identity(_builder: ClientBuilder, identity: Infallible) -> ClientBuilder {
+    match identity {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use reqwest::Client;
+
+  use crate::api::tls::ClientIdentity;
+  use crate::util::TestDir;
+
+  #[test]
+  fn load_test() {
+    let dir = TestDir::new("tls");
+    let garbage = dir.join("garbage.pem");
+    fs::write(&garbage, "not a certificate").unwrap();
+
+    let missing = ClientIdentity::Pem {
+      cert: dir.join("missing.pem"),
+      key: garbage.clone(),
+    };
+    let err = format!("{:#}", missing.apply(Client::builder()).unwrap_err());
+    assert!(err.contains("Failed to read client certificate"), "{err}");
+    let invalid = ClientIdentity::Pem {
+      cert: garbage.clone(),
+      key: garbage.clone(),
+    };
+    let err = format!("{:#}", invalid.apply(Client::builder()).unwrap_err());
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    assert!(err.contains("Invalid client certificate"), "{err}");
+    #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+    assert!(
+      err.contains("need the native-tls or rustls feature"),
+      "{err}"
+    );
+    let invalid = ClientIdentity::Pkcs12 {
+      path: garbage,
+      password: String::new(),
+    };
+    assert!(invalid.apply(Client::builder()).is_err());
+  }
+}
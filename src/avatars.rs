@@ -0,0 +1,119 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+use reqwest::header::CONTENT_TYPE;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::spawn;
+use tokio::sync::Semaphore;
+
+use crate::api::WxClient;
+use crate::layout::Paths;
+use crate::util::ReplaceSpecial;
+
+const EXTENSIONS: [&str; 4] = ["jpg", "png", "webp", "gif"];
+
+/// Download the avatar of each `(user_id, url)` to `avatars/<user_id>.<ext>` concurrently,
+/// returns the total bytes downloaded
+pub async fn download_avatars(
+  wx: &WxClient,
+  paths: &Paths,
+  avatars: Vec<(String, String)>,
+  concurrency: u32,
+) -> Result<u64> {
+  fs::create_dir_all(paths.dir("avatars"))
+    .await
+    .context("Failed to create folder ./avatars")?;
+  info!("Total {} avatars to download", avatars.len());
+  let limiter = Arc::new(Semaphore::new(concurrency as usize));
+  let mut vec = Vec::new();
+  for (user_id, url) in avatars {
+    let permit = limiter.clone().acquire_owned().await?;
+    let wx = wx.clone();
+    let name = user_id.clone().replace_special_char();
+    let paths = paths.clone();
+    vec.push(spawn(async move {
+      let _permit = permit;
+      let path = |ext: &str| paths.item("avatars", &format!("{name}.{ext}"));
+      if let Some(path) = EXTENSIONS.iter().map(|i| path(i)).find(|i| i.exists()) {
+        debug!(
+          "Avatar of {user_id} exists at {}, skipped",
+          path.to_string_lossy()
+        );
+        return 0;
+      }
+      match download(&wx, &url, path).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+          error!("Failed to download the avatar of {user_id}: {err:?}");
+          0
+        }
+      }
+    }));
+  }
+  let mut total = 0;
+  for x in vec {
+    match x.await {
+      Ok(bytes) => total += bytes,
+      Err(err) => error!("Download avatar task panicked: {err:?}"),
+    }
+  }
+  info!("Successfully download avatars, {total} bytes in total");
+  Ok(total)
+}
+
+/// Stream an image to the path returned by `path` for its extension, through a partial file
+async fn download(wx: &WxClient, url: &str, path: impl Fn(&str) -> PathBuf) -> Result<u64> {
+  let mut resp = wx.get_file(url).await?;
+  let content_type = resp.headers().get(CONTENT_TYPE);
+  let path = path(image_extension(content_type.and_then(|i| i.to_str().ok())));
+  let part = path.with_extension("part");
+  let mut file = BufWriter::new(
+    File::create(&part)
+      .await
+      .with_context(|| format!("Failed to create {}", part.to_string_lossy()))?,
+  );
+  let mut bytes = 0;
+  while let Some(chunk) = resp.chunk().await.context("Failed to read avatar")? {
+    file.write_all(&chunk).await?;
+    bytes += chunk.len() as u64;
+  }
+  file.flush().await?;
+  drop(file);
+  if bytes == 0 {
+    let _ = fs::remove_file(&part).await;
+    bail!("Empty avatar from {url}");
+  }
+  fs::rename(&part, &path)
+    .await
+    .with_context(|| format!("Failed to save {}", path.to_string_lossy()))?;
+  Ok(bytes)
+}
+
+/// File extension of an image by its `Content-Type`, JPEG if unknown
+fn image_extension(content_type: Option<&str>) -> &'static str {
+  let mime = content_type
+    .and_then(|i| i.split(';').next())
+    .map(str::trim);
+  match mime {
+    Some("image/png") => "png",
+    Some("image/webp") => "webp",
+    Some("image/gif") => "gif",
+    _ => "jpg",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::avatars::image_extension;
+
+  #[test]
+  fn image_extension_test() {
+    assert_eq!(image_extension(Some("image/png")), "png");
+    assert_eq!(image_extension(Some("image/webp; charset=binary")), "webp");
+    assert_eq!(image_extension(Some("image/jpeg")), "jpg");
+    assert_eq!(image_extension(None), "jpg");
+  }
+}
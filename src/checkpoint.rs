@@ -0,0 +1,206 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+
+use crate::api::data::{
+  AgentBasic, AgentDetail, AgentListResp, ApiError, Department, DepartmentMembersResp,
+  DepartmentResp, Rejected, SimpleMembersResp, Tag, TagMembersResp, TagsResp,
+};
+use crate::api::dump::{Checkpoint, DumpObserver};
+
+/// Checkpoint file in the output directory, kept at the top in any layout for `--resume`
+pub const CHECKPOINT: &str = "checkpoint.json";
+
+/// Collect the members fetched into a [Checkpoint] and save it every `interval` departments
+/// and tags, every response is passed on to `inner` as is
+pub struct Checkpointer {
+  inner: Arc<dyn DumpObserver>,
+  path: PathBuf,
+  interval: Option<usize>,
+  state: Mutex<State>,
+}
+
+struct State {
+  checkpoint: Checkpoint,
+  /// Departments and tags recorded since the start of this run
+  completed: usize,
+}
+
+impl Checkpointer {
+  /// Start from `checkpoint`, which is restored by `--resume` or empty, and save it to `path`
+  /// every `interval` items, or only in [Checkpointer::finish] without an interval
+  pub fn new(
+    inner: Arc<dyn DumpObserver>,
+    path: PathBuf,
+    interval: Option<usize>,
+    checkpoint: Checkpoint,
+  ) -> Checkpointer {
+    Checkpointer {
+      inner,
+      path,
+      interval,
+      state: Mutex::new(State {
+        checkpoint,
+        completed: 0,
+      }),
+    }
+  }
+
+  fn record(&self, update: impl FnOnce(&mut Checkpoint)) {
+    let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+    update(&mut state.checkpoint);
+    state.completed += 1;
+    let Some(interval) = self.interval else {
+      return;
+    };
+    if state.completed.is_multiple_of(interval) {
+      match save(&self.path, &state.checkpoint) {
+        Ok(_) => debug!("Saved checkpoint after {} items", state.completed),
+        Err(err) => error!("Failed to save checkpoint: {err:?}"),
+      }
+    }
+  }
+
+  /// Save the checkpoint for another `--resume` if anything failed to fetch,
+  /// or remove it once everything is fetched
+  pub fn finish(&self, incomplete: bool) -> Result<()> {
+    if incomplete {
+      let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+      save(&self.path, &state.checkpoint)?;
+      info!(
+        "Saved checkpoint to {}, run again with --resume to fetch the failed ones",
+        self.path.to_string_lossy()
+      );
+    } else if self.path.exists() {
+      fs::remove_file(&self.path)
+        .with_context(|| format!("Failed to delete {}", self.path.to_string_lossy()))?;
+    }
+    Ok(())
+  }
+}
+
+impl DumpObserver for Checkpointer {
+  fn on_agents(&self, resp: &AgentListResp) -> Result<()> {
+    self.inner.on_agents(resp)
+  }
+
+  fn on_agents_denied(&self, err: &ApiError) -> Result<()> {
+    self.inner.on_agents_denied(err)
+  }
+
+  fn on_agent_detail(&self, agent: &AgentBasic, resp: &AgentDetail) {
+    self.inner.on_agent_detail(agent, resp)
+  }
+
+  fn on_departments(&self, resp: &DepartmentResp) -> Result<()> {
+    self.inner.on_departments(resp)
+  }
+
+  fn on_department_members(&self, department: &Department, resp: &DepartmentMembersResp) {
+    self.inner.on_department_members(department, resp);
+    self.record(|x| {
+      let members = resp.members.clone();
+      x.members_by_department.insert(department.id, members);
+    });
+  }
+
+  fn on_rejected_members(&self, department: &Department, rejected: &[Rejected]) {
+    self.inner.on_rejected_members(department, rejected)
+  }
+
+  fn on_department_simple_members(&self, department: &Department, resp: &SimpleMembersResp) {
+    self.inner.on_department_simple_members(department, resp);
+    self.record(|x| {
+      let members = resp.members.clone();
+      x.simple_members_by_department
+        .insert(department.id, members);
+    });
+  }
+
+  fn on_tags(&self, resp: &TagsResp) -> Result<()> {
+    self.inner.on_tags(resp)
+  }
+
+  fn on_tag_members(&self, tag: &Tag, resp: &TagMembersResp) {
+    self.inner.on_tag_members(tag, resp);
+    self.record(|x| {
+      x.tag_members.insert(tag.id, resp.members.clone());
+      x.tag_departments
+        .insert(tag.id, resp.department_list.clone());
+    });
+  }
+}
+
+/// Read the checkpoint saved at `path`, [None] if there is none
+pub fn load(path: &Path) -> Result<Option<Checkpoint>> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  let file =
+    File::open(path).with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+  let checkpoint = serde_json::from_reader(BufReader::new(file))
+    .with_context(|| format!("Failed to read checkpoint {}", path.to_string_lossy()))?;
+  Ok(Some(checkpoint))
+}
+
+/// Save `checkpoint` to `path` in compact JSON with the names of the API, whatever
+/// `--json-style`, so it is read back as is
+fn save(path: &Path, checkpoint: &Checkpoint) -> Result<()> {
+  // written aside and renamed, so a crash while saving keeps the previous checkpoint
+  let partial = path.with_file_name("checkpoint-partial.json");
+  let file = File::create(&partial)
+    .with_context(|| format!("Failed to create {}", partial.to_string_lossy()))?;
+  let mut writer = BufWriter::new(file);
+  serde_json::to_writer(&mut writer, checkpoint)
+    .and_then(|_| writer.flush().map_err(serde_json::Error::io))
+    .with_context(|| format!("Failed to write {}", partial.to_string_lossy()))?;
+  fs::rename(&partial, path)
+    .with_context(|| format!("Failed to rename to {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::api::data::{Tag, TagMembersResp};
+  use crate::api::dump::{Checkpoint, DumpObserver};
+  use crate::checkpoint::{load, Checkpointer};
+  use crate::util::TestDir;
+
+  #[test]
+  fn checkpointer_test() {
+    let dir = TestDir::new("checkpoint");
+    let path = dir.join("checkpoint.json");
+    let checkpointer =
+      Checkpointer::new(Arc::new(()), path.clone(), Some(2), Checkpoint::default());
+
+    let tag = |id: u32| Tag {
+      id,
+      name: id.to_string(),
+    };
+    let resp: TagMembersResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok", "tagname": "A",
+      "userlist": [{"userid": "a", "name": "A"}], "partylist": [1],
+    }))
+    .unwrap();
+    checkpointer.on_tag_members(&tag(1), &resp);
+    assert!(load(&path).unwrap().is_none());
+    checkpointer.on_tag_members(&tag(2), &resp);
+    checkpointer.on_tag_members(&tag(3), &resp);
+    let saved = load(&path).unwrap().unwrap();
+    assert_eq!(saved.tag_members.keys().collect::<Vec<_>>(), [&1, &2]);
+    assert_eq!(saved.tag_departments[&2], [1]);
+    assert_eq!(saved.tag_members[&1][0].id, "a");
+
+    checkpointer.finish(true).unwrap();
+    assert_eq!(load(&path).unwrap().unwrap().tag_members.len(), 3);
+    checkpointer.finish(false).unwrap();
+    assert!(!path.exists());
+  }
+}
@@ -0,0 +1,29 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::api::data::{AgentBasic, Department, DepartmentMember, Tag, TagMember};
+use crate::api::dump::Dump;
+
+/// Everything in one document, the single-file counterpart of the output directory
+#[derive(Serialize, Debug)]
+pub struct Combined<'a> {
+  pub agents: &'a [AgentBasic],
+  pub departments: &'a [Department],
+  pub tags: &'a [Tag],
+  pub members_by_department: &'a BTreeMap<u32, Vec<Arc<DepartmentMember>>>,
+  pub tag_members: &'a BTreeMap<u32, Vec<TagMember>>,
+}
+
+impl<'a> Combined<'a> {
+  pub fn new(dump: &'a Dump) -> Combined<'a> {
+    Combined {
+      agents: &dump.agents,
+      departments: &dump.departments,
+      tags: &dump.tags,
+      members_by_department: &dump.members_by_department,
+      tag_members: &dump.tag_members,
+    }
+  }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+
+use crate::api::data::DepartmentMember;
+use crate::api::dump::Dump;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+pub const MEMBER_HEADERS: [&str; 17] = [
+  "user_id",
+  "name",
+  "alias",
+  "english_name",
+  "department",
+  "main_department",
+  "position",
+  "gender",
+  "mobile",
+  "telephone",
+  "email",
+  "biz_mail",
+  "is_leader",
+  "status",
+  "enable",
+  "avatar",
+  "qr_code",
+];
+
+/// Write every member once to a CSV file, with the same columns as the Members sheet
+pub fn write_members_csv(path: &Path, dump: &Dump, delimiter: u8, bom: bool) -> Result<()> {
+  let file =
+    File::create(path).with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+  write_members(BufWriter::new(file), dump, delimiter, bom)
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+fn write_members<W: Write>(mut writer: W, dump: &Dump, delimiter: u8, bom: bool) -> Result<()> {
+  if bom {
+    writer.write_all(UTF8_BOM)?;
+  }
+  let mut csv = csv::WriterBuilder::new()
+    .delimiter(delimiter)
+    .from_writer(writer);
+  csv.write_record(MEMBER_HEADERS)?;
+  for (x, department) in unique_members(dump) {
+    csv.write_record([
+      &x.user_id,
+      &x.name,
+      &x.alias,
+      &x.english_name,
+      &department.iter().join(","),
+      &x.main_department.map(|i| i.to_string()).unwrap_or_default(),
+      &x.position,
+      &x.gender,
+      &x.mobile,
+      &x.telephone,
+      &x.email,
+      x.biz_mail.as_deref().unwrap_or_default(),
+      &x.is_leader.to_string(),
+      &x.status.to_string(),
+      &x.enable.to_string(),
+      &x.avatar,
+      &x.qr_code,
+    ])?;
+  }
+  csv.flush()?;
+  Ok(())
+}
+
+/// Parse a CSV delimiter, a single ASCII character or `tab`
+pub fn parse_delimiter(value: &str) -> Result<u8> {
+  match value {
+    "tab" | "\\t" | "\t" => Ok(b'\t'),
+    _ => match value.as_bytes() {
+      [x] if x.is_ascii() && !x.is_ascii_alphanumeric() && *x != b'"' => Ok(*x),
+      _ => bail!("Delimiter must be a single ASCII symbol or `tab`, got `{value}`"),
+    },
+  }
+}
+
+/// Members fetched from several departments, deduplicated by user id,
+/// with the departments of every occurrence merged
+pub fn unique_members(dump: &Dump) -> Vec<(&DepartmentMember, Vec<u32>)> {
+  let mut result: Vec<(&DepartmentMember, Vec<u32>)> = Vec::new();
+  let mut index: HashMap<&str, usize> = HashMap::new();
+  for member in dump.members_by_department.values().flatten() {
+    match index.get(&*member.user_id) {
+      Some(&i) => {
+        let department = &mut result[i].1;
+        for id in &member.department {
+          if !department.contains(id) {
+            department.push(*id);
+          }
+        }
+      }
+      None => {
+        index.insert(&member.user_id, result.len());
+        result.push((member, member.department.clone()));
+      }
+    }
+  }
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::api::data::DepartmentMember;
+  use crate::api::dump::Dump;
+  use crate::csv_export::{parse_delimiter, write_members};
+
+  #[test]
+  fn write_members_test() {
+    let overrides = json!({"name": "Zhang; \"San\"", "department": [1, 2], "main_department": 1});
+    let member = DepartmentMember::test("zhangsan", overrides);
+    let dump = Dump {
+      members_by_department: BTreeMap::from([(1, vec![Arc::new(member)])]),
+      ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_members(&mut out, &dump, b';', true).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let mut lines = text.lines();
+    assert!(lines
+      .next()
+      .unwrap()
+      .starts_with("\u{FEFF}user_id;name;alias;"));
+    assert_eq!(
+      lines.next().unwrap(),
+      "zhangsan;\"Zhang; \"\"San\"\"\";;;1,2;1;;;;;;;0;1;1;;"
+    );
+
+    let mut out = Vec::new();
+    write_members(&mut out, &dump, b',', false).unwrap();
+    assert!(out.starts_with(b"user_id,name,"));
+  }
+
+  #[test]
+  fn parse_delimiter_test() {
+    assert_eq!(parse_delimiter(";").unwrap(), b';');
+    assert_eq!(parse_delimiter("tab").unwrap(), b'\t');
+    assert!(parse_delimiter("ab").is_err());
+    assert!(parse_delimiter("a").is_err());
+    assert!(parse_delimiter("，").is_err());
+  }
+}
@@ -0,0 +1,232 @@
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{Context, Result};
+use reqwest::Url;
+
+use crate::api::data::{ApiError, GetTokenResp};
+use crate::api::WxClient;
+
+/// Host of the API, resolved to tell DNS failures apart from the proxy and the API
+const API_HOST: &str = "qyapi.weixin.qq.com";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+  Ok,
+  /// The API answered, but the app has no permission
+  Denied,
+  Failed,
+  /// Not tried since an earlier step failed
+  Skipped,
+}
+
+impl Display for Status {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let status = match self {
+      Status::Ok => "OK",
+      Status::Denied => "DENIED",
+      Status::Failed => "FAILED",
+      Status::Skipped => "SKIPPED",
+    };
+    f.pad(status)
+  }
+}
+
+#[derive(Debug)]
+struct Step {
+  name: String,
+  /// Whether nothing can be dumped if it fails
+  essential: bool,
+  status: Status,
+  detail: String,
+}
+
+/// Results of `--diagnose`, each step is tried in order and printed as a table
+#[derive(Debug, Default)]
+pub struct Diagnosis {
+  steps: Vec<Step>,
+}
+
+impl Diagnosis {
+  fn push(&mut self, name: &str, essential: bool, status: Status, detail: String) {
+    self.steps.push(Step {
+      name: name.to_string(),
+      essential,
+      status,
+      detail,
+    });
+  }
+
+  /// Record `result` as OK with `detail`, or as denied or failed with the error
+  fn record<T>(
+    &mut self,
+    name: &str,
+    essential: bool,
+    result: Result<T>,
+    detail: impl FnOnce(&T) -> String,
+  ) -> Option<T> {
+    match result {
+      Ok(value) => {
+        self.push(name, essential, Status::Ok, detail(&value));
+        Some(value)
+      }
+      Err(err) => {
+        let (status, detail) = match ApiError::find(&err) {
+          Some(api_err) if api_err.is_permission_error() => (Status::Denied, api_err.to_string()),
+          Some(api_err) => match api_err.hint() {
+            Some(hint) => (Status::Failed, format!("{api_err}, {hint}")),
+            None => (Status::Failed, api_err.to_string()),
+          },
+          None if err.chain().count() == 1 => (Status::Failed, err.to_string()),
+          // the whole chain repeats the connection errors of hyper
+          None => (Status::Failed, format!("{err}: {}", err.root_cause())),
+        };
+        self.push(name, essential, status, detail);
+        None
+      }
+    }
+  }
+
+  fn skip(&mut self, name: &str, essential: bool, reason: &str) {
+    self.push(name, essential, Status::Skipped, reason.to_string());
+  }
+
+  /// Send a HEAD request to the API through `proxy`, skipped without one
+  pub async fn proxy(&mut self, wx: &WxClient, proxy: Option<&Url>) {
+    let Some(proxy) = proxy else {
+      self.skip("proxy", false, "no proxy configured");
+      return;
+    };
+    let mut url = proxy.clone();
+    let _ = url.set_password(None);
+    let result = wx.preflight().await.context("Proxy unreachable");
+    self.record("proxy", true, result, |_| url.to_string());
+  }
+
+  /// Resolve the API host locally, not essential behind a proxy, which resolves it itself
+  pub async fn dns(&mut self, proxied: bool) {
+    let result = tokio::net::lookup_host((API_HOST, 443))
+      .await
+      .map(|addrs| addrs.map(|x| x.ip().to_string()).collect::<Vec<_>>())
+      .with_context(|| format!("Failed to resolve {API_HOST}"));
+    self.record("dns", !proxied, result, |addrs| {
+      format!("{API_HOST} -> {}", addrs.join(", "))
+    });
+  }
+
+  /// Record the login, `Ok(None)` for a token provided directly
+  pub fn token(&mut self, login: Result<Option<GetTokenResp>>) {
+    self.record("token", true, login, |login| {
+      match login.as_ref().and_then(|x| x.expires_in) {
+        Some(expires_in) => format!("expires in {expires_in}s"),
+        None => "provided directly, checked by the requests below".to_string(),
+      }
+    });
+  }
+
+  /// Probe the endpoints a dump needs, members are fetched from the first visible department
+  pub async fn endpoints(&mut self, wx: &WxClient) {
+    let agents = wx.get_agent_list().await;
+    self.record("agent/list", false, agents, |x| {
+      format!("{} agents", x.agent_list.len())
+    });
+    let departments = wx.get_all_departments().await;
+    let departments = self.record("department/list", true, departments, |x| {
+      format!("{} departments", x.departments.len())
+    });
+    match departments.as_ref().and_then(|x| x.departments.first()) {
+      Some(department) => {
+        let members = wx.get_department_members(department.id, false).await;
+        self.record("user/list", true, members, |x| {
+          format!(
+            "{} members in department {}",
+            x.members.len(),
+            department.id
+          )
+        });
+      }
+      None => self.skip("user/list", true, "no department to sample"),
+    }
+    let tags = wx.get_tags().await;
+    self.record("tag/list", false, tags, |x| {
+      format!("{} tags", x.tags.len())
+    });
+  }
+
+  /// Skip the endpoints after the login failed
+  pub fn skip_endpoints(&mut self) {
+    for (name, essential) in [
+      ("agent/list", false),
+      ("department/list", true),
+      ("user/list", true),
+      ("tag/list", false),
+    ] {
+      self.skip(name, essential, "no token");
+    }
+  }
+
+  /// Whether every essential step is OK
+  pub fn passed(&self) -> bool {
+    self
+      .steps
+      .iter()
+      .all(|x| !x.essential || x.status == Status::Ok)
+  }
+}
+
+impl Display for Diagnosis {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    // one more for the mark of essential steps
+    let width = self
+      .steps
+      .iter()
+      .map(|x| x.name.len() + 1)
+      .max()
+      .unwrap_or(0);
+    writeln!(f, "{:width$}  {:7}  DETAIL", "STEP", "STATUS")?;
+    for x in &self.steps {
+      let name = match x.essential {
+        true => format!("{}*", x.name),
+        false => x.name.clone(),
+      };
+      let detail = x.detail.replace('\n', " ");
+      writeln!(f, "{name:width$}  {:7}  {detail}", x.status)?;
+    }
+    match self.passed() {
+      true => write!(f, "\nAll essential (*) steps passed"),
+      false => write!(f, "\nSome essential (*) steps failed"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use anyhow::anyhow;
+
+  use crate::api::data::ApiError;
+  use crate::diagnose::{Diagnosis, Status};
+
+  #[test]
+  fn diagnosis_test() {
+    let mut diagnosis = Diagnosis::default();
+    diagnosis.token(Ok(None));
+    let denied = ApiError {
+      code: ApiError::NO_PRIVILEGE,
+      msg: "no privilege".to_string(),
+    };
+    diagnosis.record::<()>("agent/list", false, Err(denied.into()), |_| String::new());
+    assert!(diagnosis.passed());
+    diagnosis.record::<()>("user/list", true, Err(anyhow!("timed out")), |_| {
+      String::new()
+    });
+    assert!(!diagnosis.passed());
+
+    let status: Vec<Status> = diagnosis.steps.iter().map(|x| x.status).collect();
+    assert_eq!(status, [Status::Ok, Status::Denied, Status::Failed]);
+    let table = diagnosis.to_string();
+    assert!(
+      table.contains("user/list*   FAILED   timed out\n"),
+      "{table}"
+    );
+    assert!(table.contains("agent/list   DENIED "), "{table}");
+  }
+}
@@ -0,0 +1,245 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::api::dump::{Dump, Job};
+use crate::layout::Paths;
+
+#[derive(Serialize, Debug, Default)]
+pub struct Diff {
+  pub departments: Vec<GroupDiff>,
+  pub tags: Vec<GroupDiff>,
+}
+
+/// Changes of the members in a department or a tag
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct GroupDiff {
+  pub id: u32,
+  pub name: String,
+  pub added: Vec<Value>,
+  pub removed: Vec<Value>,
+  pub changed: Vec<MemberChange>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MemberChange {
+  #[serde(rename = "userid")]
+  pub user_id: String,
+  pub fields: BTreeMap<String, FieldChange>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldChange {
+  pub before: Value,
+  pub after: Value,
+}
+
+impl GroupDiff {
+  fn is_empty(&self) -> bool {
+    self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+  }
+}
+
+/// Compare the fetched departments and tags with a previous dump in `previous`,
+/// the jobs which failed in this run are skipped.
+pub fn diff_against(previous: &Path, paths: &Paths, dump: &Dump) -> Result<Diff> {
+  let mut diff = Diff::default();
+
+  if !dump.failed_jobs.contains(&Job::Departments) {
+    let before = read_previous(previous, &paths.previous(), "departments")?;
+    let names = dump.departments.iter().map(|x| (x.id, &x.name));
+    let after = fetched(names, &dump.members_by_department)?;
+    let ids = dump.departments.iter().map(|x| x.id).collect();
+    diff.departments = diff_groups(before, after, &ids);
+  } else {
+    warn!("Skip comparing departments, since fetching departments failed");
+  }
+
+  if !dump.failed_jobs.contains(&Job::Tags) {
+    let before = read_previous(previous, &paths.previous(), "tags")?;
+    let names = dump.tags.iter().map(|x| (x.id, &x.name));
+    let after = fetched(names, &dump.tag_members)?;
+    let ids = dump.tags.iter().map(|x| x.id).collect();
+    diff.tags = diff_groups(before, after, &ids);
+  } else {
+    warn!("Skip comparing tags, since fetching tags failed");
+  }
+
+  Ok(diff)
+}
+
+/// Members of each group fetched in this run, as JSON values keyed by id
+fn fetched<'a, T: Serialize>(
+  names: impl Iterator<Item = (u32, &'a String)>,
+  members: &BTreeMap<u32, Vec<T>>,
+) -> Result<BTreeMap<u32, (String, Vec<Value>)>> {
+  names
+    .filter_map(|(id, name)| Some((id, name, members.get(&id)?)))
+    .map(|(id, name, members)| {
+      let members = serde_json::to_value(members).context("Failed to serialize")?;
+      Ok((id, (name.clone(), as_array(members))))
+    })
+    .collect()
+}
+
+/// Read `members-<id>-<name>.json` files of a category in a previous dump, keyed by id
+pub fn read_previous(
+  previous: &Path,
+  paths: &Paths,
+  category: &str,
+) -> Result<BTreeMap<u32, (String, Vec<Value>)>> {
+  let dir = previous.join(paths.dir(category));
+  let prefix = format!("{}members-", paths.prefix(category));
+  let mut result = BTreeMap::new();
+  if !dir.is_dir() {
+    warn!("Previous dump has no {}", dir.to_string_lossy());
+    return Ok(result);
+  }
+  for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+    let path = entry?.path();
+    let Some(stem) = path.file_stem().map(|i| i.to_string_lossy().to_string()) else {
+      continue;
+    };
+    // members rejected by --lenient
+    if stem.ends_with(".errors") {
+      continue;
+    }
+    let Some((id, name)) = stem
+      .strip_prefix(&*prefix)
+      .and_then(|i| i.split_once('-'))
+      .and_then(|(id, name)| Some((id.parse::<u32>().ok()?, name.to_string())))
+    else {
+      continue;
+    };
+    let json = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut value: Value = serde_json::from_slice(&json)
+      .with_context(|| format!("Failed to deserialize {}", path.display()))?;
+    result.insert(id, (name, as_array(value["userlist"].take())));
+  }
+  Ok(result)
+}
+
+fn as_array(value: Value) -> Vec<Value> {
+  match value {
+    Value::Array(array) => array,
+    _ => Vec::new(),
+  }
+}
+
+/// Diff every group in `after`, and the groups only in `before` which no longer exist
+/// in the listing `ids`, since the others just failed to fetch in this run
+fn diff_groups(
+  mut before: BTreeMap<u32, (String, Vec<Value>)>,
+  after: BTreeMap<u32, (String, Vec<Value>)>,
+  ids: &HashSet<u32>,
+) -> Vec<GroupDiff> {
+  let mut result = Vec::new();
+  for (id, (name, after)) in after {
+    let before = before.remove(&id).map(|(_, i)| i).unwrap_or_default();
+    result.push(diff_members(id, name, before, after));
+  }
+  for (id, (name, before)) in before {
+    if !ids.contains(&id) {
+      result.push(diff_members(id, name, before, Vec::new()));
+    }
+  }
+  result.retain(|i| !i.is_empty());
+  result.sort_by_key(|i| i.id);
+  result
+}
+
+fn user_id(value: &Value) -> Option<&str> {
+  value["userid"].as_str()
+}
+
+fn diff_members(id: u32, name: String, before: Vec<Value>, after: Vec<Value>) -> GroupDiff {
+  let mut before: BTreeMap<String, Value> = before
+    .into_iter()
+    .filter_map(|i| Some((user_id(&i)?.to_string(), i)))
+    .collect();
+  let mut diff = GroupDiff {
+    id,
+    name,
+    ..Default::default()
+  };
+  for member in after {
+    let Some(user_id) = user_id(&member) else {
+      continue;
+    };
+    match before.remove(user_id) {
+      None => diff.added.push(member),
+      Some(old) => {
+        let fields = diff_fields(&old, &member);
+        if !fields.is_empty() {
+          diff.changed.push(MemberChange {
+            user_id: user_id.to_string(),
+            fields,
+          });
+        }
+      }
+    }
+  }
+  diff.removed = before.into_values().collect();
+  diff
+}
+
+fn diff_fields(before: &Value, after: &Value) -> BTreeMap<String, FieldChange> {
+  let (Some(before), Some(after)) = (before.as_object(), after.as_object()) else {
+    return BTreeMap::new();
+  };
+  before
+    .keys()
+    .chain(after.keys())
+    // the raw values of --normalize-contacts are the formatting noise it hides
+    .filter(|key| *key != "_raw")
+    .filter_map(|key| {
+      let old = before.get(key).unwrap_or(&Value::Null);
+      let new = after.get(key).unwrap_or(&Value::Null);
+      (old != new).then(|| {
+        let change = FieldChange {
+          before: old.clone(),
+          after: new.clone(),
+        };
+        (key.clone(), change)
+      })
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::diff::diff_members;
+
+  #[test]
+  fn diff_members_test() {
+    let before = vec![
+      json!({"userid": "a", "name": "A", "department": [1]}),
+      json!({"userid": "b", "name": "B", "department": [1]}),
+    ];
+    let after = vec![
+      json!({"userid": "a", "name": "A", "department": [2]}),
+      json!({"userid": "c", "name": "C", "department": [1]}),
+    ];
+    let diff = diff_members(1, "Dept".to_string(), before, after);
+    assert_eq!(
+      diff.added,
+      vec![json!({"userid": "c", "name": "C", "department": [1]})]
+    );
+    assert_eq!(
+      diff.removed,
+      vec![json!({"userid": "b", "name": "B", "department": [1]})]
+    );
+    assert_eq!(diff.changed.len(), 1);
+    assert_eq!(diff.changed[0].user_id, "a");
+    let change = &diff.changed[0].fields["department"];
+    assert_eq!(change.before, json!([1]));
+    assert_eq!(change.after, json!([2]));
+  }
+}
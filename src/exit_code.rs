@@ -0,0 +1,100 @@
+use std::process::exit;
+
+use crate::api::data::ApiError;
+use crate::api::stats::BudgetExhausted;
+
+/// Exit codes by the class of failure, so wrapping scripts can tell them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+  /// Any other failure, including invalid arguments
+  Generic = 1,
+  /// The dump finished, but some jobs or items failed to fetch
+  Partial = 2,
+  /// Missing or rejected credentials
+  Auth = 3,
+  /// The output directory or another file can not be written
+  Output = 4,
+  /// The frequency limit of the API or the budget of --max-requests is hit
+  Quota = 5,
+}
+
+/// Documented in `--help`
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Generic failure, including invalid arguments
+  2  Partial dump, some jobs or items failed to fetch
+  3  Missing or rejected credentials
+  4  Output directory or file error
+  5  Frequency limit or --max-requests exhausted";
+
+impl ExitCode {
+  pub fn exit(self) -> ! {
+    exit(self as i32)
+  }
+
+  /// Classify the error ending the program
+  pub fn of(err: &anyhow::Error) -> ExitCode {
+    if let Some(api_err) = ApiError::find(err) {
+      return match api_err.code {
+        ApiError::FREQUENCY_LIMITED => ExitCode::Quota,
+        _ if api_err.is_credential_error() || api_err.hint().is_some() => ExitCode::Auth,
+        _ => ExitCode::Generic,
+      };
+    }
+    if err.chain().any(|x| x.is::<BudgetExhausted>()) {
+      return ExitCode::Quota;
+    }
+    // network errors are I/O errors too, but not of the output
+    if err.chain().any(|x| x.is::<reqwest::Error>()) {
+      return ExitCode::Generic;
+    }
+    match err.chain().any(|x| x.is::<std::io::Error>()) {
+      true => ExitCode::Output,
+      false => ExitCode::Generic,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io;
+
+  use anyhow::{anyhow, Context};
+
+  use crate::api::data::ApiError;
+  use crate::api::stats::BudgetExhausted;
+  use crate::exit_code::ExitCode;
+
+  #[test]
+  fn exit_code_test() {
+    let api_err = |code| {
+      anyhow::Error::new(ApiError {
+        code,
+        msg: String::new(),
+      })
+      .context("Failed to get token")
+    };
+    assert_eq!(
+      ExitCode::of(&api_err(ApiError::MISSING_CORP_ID)),
+      ExitCode::Auth
+    );
+    assert_eq!(
+      ExitCode::of(&api_err(ApiError::INVALID_SECRET)),
+      ExitCode::Auth
+    );
+    assert_eq!(
+      ExitCode::of(&api_err(ApiError::FREQUENCY_LIMITED)),
+      ExitCode::Quota
+    );
+    assert_eq!(
+      ExitCode::of(&api_err(ApiError::NO_PRIVILEGE)),
+      ExitCode::Generic
+    );
+    let budget = anyhow::Error::new(BudgetExhausted).context("Failed to get departments");
+    assert_eq!(ExitCode::of(&budget), ExitCode::Quota);
+    let io = Err::<(), _>(io::Error::other("denied")).context("Failed to create folder");
+    assert_eq!(ExitCode::of(&io.unwrap_err()), ExitCode::Output);
+    assert_eq!(ExitCode::of(&anyhow!("other")), ExitCode::Generic);
+  }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::api::dump::Dump;
+
+/// Write departments, members and tags to `nodes.csv`, and their relationships to `edges.csv`
+/// in `dir`, in the CSV format of `neo4j-admin database import`
+pub fn write_graph(dir: &Path, dump: &Dump) -> Result<()> {
+  fs::create_dir_all(dir)
+    .with_context(|| format!("Failed to create folder {}", dir.to_string_lossy()))?;
+  let graph = Graph::new(dump);
+  let path = dir.join("nodes.csv");
+  create(&path)
+    .and_then(|file| graph.write_nodes(file))
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))?;
+  let path = dir.join("edges.csv");
+  create(&path)
+    .and_then(|file| graph.write_edges(file))
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>> {
+  Ok(BufWriter::new(File::create(path)?))
+}
+
+struct Graph<'a> {
+  dump: &'a Dump,
+  /// Name and departments of every member, including the ones only seen in tags
+  members: BTreeMap<&'a str, (&'a str, BTreeSet<u32>)>,
+}
+
+impl<'a> Graph<'a> {
+  fn new(dump: &'a Dump) -> Graph<'a> {
+    let mut members: BTreeMap<&str, (&str, BTreeSet<u32>)> = BTreeMap::new();
+    let full = dump.members_by_department.values().flatten();
+    let full = full.map(|x| (&*x.user_id, &*x.name, &*x.department));
+    let simple = dump.simple_members_by_department.values().flatten();
+    let simple = simple.map(|x| (&*x.user_id, &*x.name, &*x.department));
+    for (id, name, department) in full.chain(simple) {
+      let member = members.entry(id).or_insert_with(|| (name, BTreeSet::new()));
+      member.1.extend(department);
+    }
+    for x in dump.tag_members.values().flatten() {
+      members
+        .entry(&x.id)
+        .or_insert_with(|| (&x.name, BTreeSet::new()));
+    }
+    Graph { dump, members }
+  }
+
+  fn write_nodes<W: Write>(&self, writer: W) -> Result<()> {
+    let mut csv = csv::Writer::from_writer(writer);
+    csv.write_record(["id:ID", ":LABEL", "key", "name"])?;
+    for x in &self.dump.departments {
+      let key = x.id.to_string();
+      csv.write_record([&department_id(x.id), "Department", &key, &x.name])?;
+    }
+    for (id, (name, _)) in &self.members {
+      csv.write_record([&member_id(id), "Member", id, name])?;
+    }
+    for x in &self.dump.tags {
+      let key = x.id.to_string();
+      csv.write_record([&tag_id(x.id), "Tag", &key, &x.name])?;
+    }
+    csv.flush()?;
+    Ok(())
+  }
+
+  fn write_edges<W: Write>(&self, writer: W) -> Result<()> {
+    // edges to missing nodes fail the import, like departments not visible to the app
+    let departments: HashSet<u32> = self.dump.departments.iter().map(|x| x.id).collect();
+    let mut csv = csv::Writer::from_writer(writer);
+    csv.write_record([":START_ID", ":END_ID", ":TYPE"])?;
+    for (id, (_, department)) in &self.members {
+      for &x in department.iter().filter(|x| departments.contains(x)) {
+        csv.write_record([&member_id(id), &department_id(x), "MEMBER_OF"])?;
+      }
+    }
+    for tag in &self.dump.tags {
+      for x in self.dump.tag_members.get(&tag.id).into_iter().flatten() {
+        csv.write_record([&member_id(&x.id), &tag_id(tag.id), "HAS_TAG"])?;
+      }
+    }
+    for x in &self.dump.departments {
+      if let Some(parent) = x.parent_id.filter(|x| departments.contains(x)) {
+        csv.write_record([&department_id(x.id), &department_id(parent), "CHILD_OF"])?;
+      }
+    }
+    csv.flush()?;
+    Ok(())
+  }
+}
+
+// ids of all labels share one space in the import, so they are prefixed by the label
+
+fn department_id(id: u32) -> String {
+  format!("department-{id}")
+}
+
+fn member_id(id: &str) -> String {
+  format!("member-{id}")
+}
+
+fn tag_id(id: u32) -> String {
+  format!("tag-{id}")
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use serde_json::json;
+
+  use crate::api::dump::Dump;
+  use crate::graph::Graph;
+
+  #[test]
+  fn graph_test() {
+    let dump = Dump {
+      departments: serde_json::from_value(json!([
+        {"id": 1, "name": "Company", "parentid": 0, "order": 1},
+        {"id": 2, "name": "R&D, Team", "parentid": 1, "order": 1},
+      ]))
+      .unwrap(),
+      simple_members_by_department: BTreeMap::from([(
+        2,
+        serde_json::from_value(json!([{"userid": "a", "name": "A", "department": [2, 9]}]))
+          .unwrap(),
+      )]),
+      tags: serde_json::from_value(json!([{"tagid": 1, "tagname": "On-call"}])).unwrap(),
+      tag_members: BTreeMap::from([(
+        1,
+        serde_json::from_value(json!([
+          {"userid": "a", "name": "A"}, {"userid": "b", "name": "B"},
+        ]))
+        .unwrap(),
+      )]),
+      ..Default::default()
+    };
+    let graph = Graph::new(&dump);
+    let mut nodes = Vec::new();
+    graph.write_nodes(&mut nodes).unwrap();
+    assert_eq!(
+      String::from_utf8(nodes).unwrap(),
+      "id:ID,:LABEL,key,name\n\
+       department-1,Department,1,Company\n\
+       department-2,Department,2,\"R&D, Team\"\n\
+       member-a,Member,a,A\n\
+       member-b,Member,b,B\n\
+       tag-1,Tag,1,On-call\n"
+    );
+    let mut edges = Vec::new();
+    graph.write_edges(&mut edges).unwrap();
+    assert_eq!(
+      String::from_utf8(edges).unwrap(),
+      ":START_ID,:END_ID,:TYPE\n\
+       member-a,department-2,MEMBER_OF\n\
+       member-a,tag-1,HAS_TAG\n\
+       member-b,tag-1,HAS_TAG\n\
+       department-2,department-1,CHILD_OF\n"
+    );
+  }
+}
@@ -0,0 +1,104 @@
+use std::path::Path;
+use std::process::ExitStatus;
+
+use anyhow::{Context, Result};
+use log::info;
+
+use crate::api::command::shell;
+use crate::summary::Summary;
+
+/// Shell command run after a dump by `--post-hook`
+pub struct PostHook {
+  command: String,
+  /// Run after failed dumps too, for `--post-hook-always`
+  always: bool,
+}
+
+impl PostHook {
+  pub fn new(command: String, always: bool) -> PostHook {
+    PostHook { command, always }
+  }
+
+  /// Run the hook for a dump into `dir`, with its summary if it finished, and whether it
+  /// succeeded. [None] if it is skipped for a failed dump
+  pub async fn run(
+    &self,
+    dir: &Path,
+    summary: Option<&Summary>,
+    success: bool,
+  ) -> Result<Option<ExitStatus>> {
+    if !success && !self.always {
+      info!("Skipped the post hook for the failed dump");
+      return Ok(None);
+    }
+    info!("Running post hook: {}", self.command);
+    let status = shell(&self.command)
+      .envs(vars(dir, summary, success))
+      .status()
+      .await
+      .with_context(|| format!("Failed to run post hook: {}", self.command))?;
+    Ok(Some(status))
+  }
+}
+
+/// Environment variables passed to the hook, the counts are left out if the dump did not finish
+fn vars(dir: &Path, summary: Option<&Summary>, success: bool) -> Vec<(&'static str, String)> {
+  let mut vars = vec![
+    ("QYWX_OUTPUT_DIR", dir.to_string_lossy().into_owned()),
+    ("QYWX_SUCCESS", u8::from(success).to_string()),
+  ];
+  if let Some(x) = summary {
+    vars.extend([
+      ("QYWX_STARTED_AT", x.started_at.clone()),
+      (
+        "QYWX_DURATION_SECONDS",
+        format!("{:.3}", x.duration_seconds),
+      ),
+      ("QYWX_DEPARTMENT_COUNT", x.departments.to_string()),
+      ("QYWX_MEMBER_COUNT", x.members.to_string()),
+      ("QYWX_TAG_COUNT", x.tags.to_string()),
+      ("QYWX_FETCH_FAILURES", x.fetch_failures.to_string()),
+    ]);
+  }
+  vars
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+
+  use crate::hook::{vars, PostHook};
+  use crate::summary::Summary;
+
+  #[test]
+  fn vars_test() {
+    let summary = Summary {
+      members: 3,
+      fetch_failures: 1,
+      ..Default::default()
+    };
+    let failed = vars(Path::new("/out"), Some(&summary), false);
+    assert!(failed.contains(&("QYWX_OUTPUT_DIR", "/out".to_string())));
+    assert!(failed.contains(&("QYWX_SUCCESS", "0".to_string())));
+    assert!(failed.contains(&("QYWX_MEMBER_COUNT", "3".to_string())));
+    assert!(failed.contains(&("QYWX_FETCH_FAILURES", "1".to_string())));
+    assert_eq!(vars(Path::new("/out"), None, true).len(), 2);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn post_hook_test() {
+    let hook = PostHook::new("exit $QYWX_MEMBER_COUNT".to_string(), false);
+    let summary = Summary {
+      members: 3,
+      ..Default::default()
+    };
+    let status = hook.run(Path::new("."), Some(&summary), true).await;
+    assert_eq!(status.unwrap().unwrap().code(), Some(3));
+    assert!(hook
+      .run(Path::new("."), None, false)
+      .await
+      .unwrap()
+      .is_none());
+  }
+}
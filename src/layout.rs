@@ -0,0 +1,431 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use chrono::format::{self, Parsed, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime};
+use clap::ValueEnum;
+
+use crate::util::ReplaceSpecial;
+
+/// How output files are organized in the output directory
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+  /// `departments.json`, `departments/members-<id>-<name>.json`
+  Nested,
+  /// `departments.json`, `departments-members-<id>-<name>.json`
+  Flat,
+  /// `<YYYY-MM-DD>/departments.json`, `<YYYY-MM-DD>/departments/members-<id>-<name>.json`
+  Dated,
+}
+
+/// Names of the top-level files and folders created by this tool, without `.json` or `.prom`
+const MANAGED: [&str; 16] = [
+  "agents",
+  "departments",
+  "departments_with_counts",
+  "tags",
+  "tag_members_by_user",
+  "tags_union",
+  "external_contacts",
+  "raw",
+  "diff",
+  "summary",
+  "metrics",
+  "anomalies",
+  "manifest",
+  "avatars",
+  "checkpoint",
+  "checkpoint-partial",
+];
+
+/// Categories whose files are put into the output directory with a prefix by [Layout::Flat]
+const FLAT: [&str; 5] = [
+  "agents",
+  "departments",
+  "tags",
+  "avatars",
+  "external_contacts",
+];
+
+/// Extensions of the files in the categories of [FLAT]
+const ITEM_EXTENSIONS: [&str; 6] = ["json", "txt", "jpg", "png", "webp", "gif"];
+
+/// Whether an entry in the output directory is created by this tool, in any layout, with the
+/// top-level names stamped in `timestamp_format` by [Paths::with_timestamp]
+pub fn is_managed(name: &str, timestamp_format: &str) -> bool {
+  let stem = name
+    .strip_suffix(".json")
+    .or_else(|| name.strip_suffix(".prom"))
+    .unwrap_or(name);
+  let stamp = |x: &str| {
+    let items = StrftimeItems::new(timestamp_format);
+    format::parse(&mut Parsed::new(), x, items).is_ok()
+  };
+  let item = |x: &str| {
+    x.rsplit_once('.')
+      .is_some_and(|(_, extension)| ITEM_EXTENSIONS.contains(&extension))
+  };
+  fn after<'a>(name: &'a str, prefix: &str) -> Option<&'a str> {
+    name.strip_prefix(prefix)?.strip_prefix('-')
+  }
+  MANAGED.contains(&stem)
+    // like `tags-20240115T0900.json` and `raw-20240115T0900`
+    || MANAGED.iter().any(|x| after(stem, x).is_some_and(stamp))
+    // like `tags-members-1-Ops.json`, stamped or not
+    || FLAT.iter().any(|x| after(name, x).is_some_and(item))
+    || NaiveDate::parse_from_str(name, "%Y-%m-%d").is_ok()
+    || NaiveDateTime::parse_from_str(name, RUN_FOLDER_FORMAT).is_ok()
+}
+
+/// Builds the path of every output file, relative to the output directory
+#[derive(Clone, Debug)]
+pub struct Paths {
+  layout: Layout,
+  date: String,
+  /// Appended to top-level names, so runs are kept side by side
+  stamp: Option<String>,
+}
+
+/// Default format of [Paths::with_timestamp], ISO 8601 basic format to the minute
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M";
+
+/// Format of the folder of each run by [Paths::with_run_folder], without colons for Windows
+const RUN_FOLDER_FORMAT: &str = "%Y-%m-%dT%H%M%S";
+
+impl Paths {
+  pub fn new(layout: Layout, time: DateTime<Local>) -> Paths {
+    Paths {
+      layout,
+      date: time.format("%Y-%m-%d").to_string(),
+      stamp: None,
+    }
+  }
+
+  /// Append `time` in `format` to the top-level names, like `departments-20240115T0900.json`
+  /// and `departments-20240115T0900/members-1-Company.json`
+  pub fn with_timestamp(mut self, time: DateTime<Local>, format: &str) -> Result<Paths> {
+    let mut stamp = String::new();
+    if write!(stamp, "{}", time.format(format)).is_err() {
+      bail!("Invalid timestamp format: {format}");
+    }
+    if stamp.contains(['/', '\\']) {
+      bail!("Timestamp format should not contain path separators: {format}");
+    }
+    self.stamp = Some(stamp);
+    Ok(self)
+  }
+
+  /// Put every file into a folder named by `time` to the second, like `2024-01-15T090000/`,
+  /// for runs repeated within a day
+  pub fn with_run_folder(mut self, time: DateTime<Local>) -> Paths {
+    self.layout = Layout::Dated;
+    self.date = time.format(RUN_FOLDER_FORMAT).to_string();
+    self
+  }
+
+  /// `name` with the timestamp inserted before its extension, if any
+  fn stamped(&self, name: &str) -> String {
+    match &self.stamp {
+      Some(stamp) if name != "." => match name.split_once('.') {
+        Some((stem, extension)) => format!("{stem}-{stamp}.{extension}"),
+        None => format!("{name}-{stamp}"),
+      },
+      _ => name.to_string(),
+    }
+  }
+
+  /// `category` with its top-level folder stamped
+  fn stamped_category(&self, category: &str) -> String {
+    match category.split_once('/') {
+      Some((top, rest)) => format!("{}/{rest}", self.stamped(top)),
+      None => self.stamped(category),
+    }
+  }
+
+  /// Folder of the top-level files, the dated folder for [Layout::Dated]
+  pub fn root(&self) -> PathBuf {
+    match self.layout {
+      Layout::Nested | Layout::Flat => PathBuf::from("."),
+      Layout::Dated => PathBuf::from(&self.date),
+    }
+  }
+
+  /// Path of a top-level file or folder, like `departments.json`
+  pub fn top(&self, name: &str) -> PathBuf {
+    let name = self.stamped(name);
+    match self.layout {
+      Layout::Nested | Layout::Flat => PathBuf::from(name),
+      Layout::Dated => PathBuf::from(&self.date).join(name),
+    }
+  }
+
+  /// Folder of the files in a category, like `departments`, it may be shared with other categories
+  pub fn dir(&self, category: &str) -> PathBuf {
+    match self.layout {
+      Layout::Nested => PathBuf::from(self.stamped_category(category)),
+      Layout::Flat => PathBuf::from("."),
+      Layout::Dated => PathBuf::from(&self.date).join(self.stamped_category(category)),
+    }
+  }
+
+  /// Prefix of the file names in a category, only used by [Layout::Flat]
+  pub fn prefix(&self, category: &str) -> String {
+    match self.layout {
+      Layout::Flat => format!("{}-", self.stamped_category(category).replace('/', "-")),
+      Layout::Nested | Layout::Dated => String::new(),
+    }
+  }
+
+  /// Paths of a previous dump to compare with, which is expected to be the dated folder itself
+  /// for [Layout::Dated] and without timestamps
+  pub fn previous(&self) -> Paths {
+    let layout = match self.layout {
+      Layout::Flat => Layout::Flat,
+      Layout::Nested | Layout::Dated => Layout::Nested,
+    };
+    Paths {
+      layout,
+      date: self.date.clone(),
+      stamp: None,
+    }
+  }
+
+  /// Path of a file in a category, like `departments/members-1-Company.json`,
+  /// `name` should be sanitized already
+  pub fn item(&self, category: &str, name: &str) -> PathBuf {
+    self
+      .dir(category)
+      .join(format!("{}{name}", self.prefix(category)))
+  }
+}
+
+/// Default of [NameTemplate], like `members-1-Company`
+pub const DEFAULT_NAME_TEMPLATE: &str = "members-{id}-{name}";
+
+/// Name of the member file of each department or tag, like `{name}_{id}`
+#[derive(Clone, Debug)]
+pub struct NameTemplate(Vec<Part>);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+  Text(String),
+  Id,
+  Name,
+  ParentId,
+}
+
+impl NameTemplate {
+  /// Sanitized file name with `.json` appended, `{parent_id}` is empty if `parent_id` is [None]
+  pub fn render(&self, id: u32, name: &str, parent_id: Option<u32>) -> String {
+    let mut result = String::new();
+    for part in &self.0 {
+      match part {
+        Part::Text(text) => result.push_str(text),
+        Part::Id => result.push_str(&id.to_string()),
+        Part::Name => result.push_str(name),
+        Part::ParentId => result.push_str(&parent_id.map(|i| i.to_string()).unwrap_or_default()),
+      }
+    }
+    format!("{result}.json").replace_special_char()
+  }
+}
+
+impl Default for NameTemplate {
+  fn default() -> Self {
+    DEFAULT_NAME_TEMPLATE.parse().unwrap()
+  }
+}
+
+impl FromStr for NameTemplate {
+  type Err = anyhow::Error;
+
+  /// Parse placeholders `{id}`, `{name}` and `{parent_id}`, `{id}` is required to keep
+  /// the names unique
+  fn from_str(s: &str) -> Result<Self> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+      if start > 0 {
+        parts.push(Part::Text(rest[..start].to_string()));
+      }
+      let Some(end) = rest[start..].find('}') else {
+        bail!("Unclosed placeholder in name template: {s}");
+      };
+      parts.push(match &rest[start + 1..start + end] {
+        "id" => Part::Id,
+        "name" => Part::Name,
+        "parent_id" => Part::ParentId,
+        x => bail!("Unknown placeholder {{{x}}} in name template: {s}"),
+      });
+      rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+      bail!("Unmatched }} in name template: {s}");
+    }
+    if !rest.is_empty() {
+      parts.push(Part::Text(rest.to_string()));
+    }
+    if !parts.contains(&Part::Id) {
+      bail!("Name template should contain {{id}} to keep the names unique: {s}");
+    }
+    Ok(NameTemplate(parts))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use chrono::{Local, TimeZone};
+
+  use crate::layout::{is_managed, Layout, NameTemplate, Paths, DEFAULT_TIMESTAMP_FORMAT};
+
+  fn paths(layout: Layout) -> Paths {
+    Paths::new(
+      layout,
+      Local.with_ymd_and_hms(2024, 1, 15, 9, 0, 0).unwrap(),
+    )
+  }
+
+  #[test]
+  fn nested_layout_test() {
+    let paths = paths(Layout::Nested);
+    assert_eq!(paths.top("tags.json"), PathBuf::from("tags.json"));
+    assert_eq!(paths.dir("tags"), PathBuf::from("tags"));
+    assert_eq!(
+      paths.item("tags", "members-1-a.json"),
+      PathBuf::from("tags/members-1-a.json")
+    );
+    assert_eq!(
+      paths.item("external_contacts/zhangsan", "wm1.json"),
+      PathBuf::from("external_contacts/zhangsan/wm1.json")
+    );
+  }
+
+  #[test]
+  fn flat_layout_test() {
+    let paths = paths(Layout::Flat);
+    assert_eq!(paths.top("tags.json"), PathBuf::from("tags.json"));
+    assert_eq!(paths.dir("tags"), PathBuf::from("."));
+    assert_eq!(
+      paths.item("tags", "members-1-a.json"),
+      PathBuf::from("./tags-members-1-a.json")
+    );
+    assert_eq!(
+      paths.item("external_contacts/zhangsan", "wm1.json"),
+      PathBuf::from("./external_contacts-zhangsan-wm1.json")
+    );
+  }
+
+  #[test]
+  fn dated_layout_test() {
+    let paths = paths(Layout::Dated);
+    assert_eq!(
+      paths.top("tags.json"),
+      PathBuf::from("2024-01-15/tags.json")
+    );
+    assert_eq!(paths.dir("tags"), PathBuf::from("2024-01-15/tags"));
+    assert_eq!(
+      paths.item("tags", "members-1-a.json"),
+      PathBuf::from("2024-01-15/tags/members-1-a.json")
+    );
+  }
+
+  #[test]
+  fn run_folder_test() {
+    let time = Local.with_ymd_and_hms(2024, 1, 15, 9, 0, 30).unwrap();
+    let paths = paths(Layout::Nested).with_run_folder(time);
+    assert_eq!(
+      paths.item("tags", "members-1-a.json"),
+      PathBuf::from("2024-01-15T090030/tags/members-1-a.json")
+    );
+    assert!(is_managed("2024-01-15T090030", DEFAULT_TIMESTAMP_FORMAT));
+  }
+
+  #[test]
+  fn timestamp_test() {
+    let time = Local.with_ymd_and_hms(2024, 1, 15, 9, 0, 0).unwrap();
+    let paths = paths(Layout::Nested)
+      .with_timestamp(time, DEFAULT_TIMESTAMP_FORMAT)
+      .unwrap();
+    assert_eq!(
+      paths.top("tags.json"),
+      PathBuf::from("tags-20240115T0900.json")
+    );
+    assert_eq!(paths.top("raw"), PathBuf::from("raw-20240115T0900"));
+    assert_eq!(paths.top("."), PathBuf::from("."));
+    assert_eq!(
+      paths.item("external_contacts/zhangsan", "wm1.json"),
+      PathBuf::from("external_contacts-20240115T0900/zhangsan/wm1.json")
+    );
+    assert!(is_managed(
+      "tags-20240115T0900.json",
+      DEFAULT_TIMESTAMP_FORMAT
+    ));
+    assert!(is_managed("raw-20240115T0900", DEFAULT_TIMESTAMP_FORMAT));
+    assert!(is_managed("summary-20240115.json", "%Y%m%d"));
+    assert!(!is_managed(
+      "summary-20240115.json",
+      DEFAULT_TIMESTAMP_FORMAT
+    ));
+
+    let paths = paths.previous();
+    assert_eq!(paths.top("tags.json"), PathBuf::from("tags.json"));
+
+    let flat = Paths::new(Layout::Flat, time)
+      .with_timestamp(time, "%Y%m%d")
+      .unwrap();
+    assert_eq!(
+      flat.item("tags", "members-1-a.json"),
+      PathBuf::from("./tags-20240115-members-1-a.json")
+    );
+
+    assert!(Paths::new(Layout::Nested, time)
+      .with_timestamp(time, "%Y/%m")
+      .is_err());
+    assert!(Paths::new(Layout::Nested, time)
+      .with_timestamp(time, "%Q")
+      .is_err());
+  }
+
+  #[test]
+  fn is_managed_test() {
+    let is_managed = |name| is_managed(name, DEFAULT_TIMESTAMP_FORMAT);
+    assert!(is_managed("departments.json"));
+    assert!(is_managed("tags"));
+    assert!(is_managed("metrics.prom"));
+    assert!(is_managed("checkpoint-partial.json"));
+    assert!(is_managed("departments-members-1-Company.json"));
+    assert!(is_managed("tags-20240115T0900-members-1-Ops.json"));
+    assert!(is_managed("external_contacts-a-b.json"));
+    assert!(is_managed("avatars-zhangsan.jpg"));
+    assert!(is_managed("2022-09-01"));
+    assert!(!is_managed("notes.txt"));
+    assert!(!is_managed("departmentsx.json"));
+    assert!(!is_managed("2022-13-01"));
+    assert!(!is_managed("summary-notes.txt"));
+    assert!(!is_managed("summary-notes.json"));
+    assert!(!is_managed("raw-backup"));
+    assert!(!is_managed("diff-2023.csv"));
+    assert!(!is_managed("tags-old"));
+    assert!(!is_managed("departments-backup.zip"));
+  }
+
+  #[test]
+  fn name_template_test() {
+    let default = NameTemplate::default();
+    assert_eq!(
+      default.render(1, "R&D/QA", Some(0)),
+      "members-1-R&D-QA.json"
+    );
+    let template: NameTemplate = "{parent_id}/{name}_{id}".parse().unwrap();
+    assert_eq!(template.render(2, "QA", Some(1)), "1-QA_2.json");
+    assert_eq!(template.render(2, "QA", None), "-QA_2.json");
+    assert!("{name}".parse::<NameTemplate>().is_err());
+    assert!("{id}-{title}".parse::<NameTemplate>().is_err());
+    assert!("{id}-{name".parse::<NameTemplate>().is_err());
+    assert!("{id}}".parse::<NameTemplate>().is_err());
+  }
+}
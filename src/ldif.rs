@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::iter;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use itertools::Itertools;
+
+use crate::api::data::Department;
+use crate::api::dump::{ancestors, Dump};
+
+/// Write departments as organizational units nested by their parents, and members as
+/// `inetOrgPerson` entries under their main department, with every department path in `ou`
+pub fn write_ldif(path: &Path, dump: &Dump, base_dn: &str) -> Result<()> {
+  fs::write(path, to_ldif(dump, base_dn))
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+fn to_ldif(dump: &Dump, base_dn: &str) -> String {
+  let by_id: HashMap<u32, &Department> = dump.departments.iter().map(|x| (x.id, x)).collect();
+  let chains: HashMap<u32, Vec<&Department>> = dump
+    .departments
+    .iter()
+    .map(|x| (x.id, ancestors(&by_id, x)))
+    .collect();
+  let dn = |id: u32| {
+    let chain = chains.get(&id)?;
+    let ous = chain.iter().map(|x| format!("ou={}", escape_dn(&x.name)));
+    Some(ous.chain(iter::once(base_dn.to_string())).join(","))
+  };
+  let path = |id: u32| {
+    let chain = chains.get(&id)?;
+    Some(chain.iter().rev().map(|x| &x.name).join("/"))
+  };
+
+  let mut text = String::from("version: 1\n");
+  // parents before children, so the entries can be added in order
+  for department in dump
+    .departments
+    .iter()
+    .sorted_by_key(|x| chains[&x.id].len())
+  {
+    text.push('\n');
+    let dn = dn(department.id).unwrap_or_default();
+    attribute(&mut text, "dn", &dn);
+    attribute(&mut text, "objectClass", "top");
+    attribute(&mut text, "objectClass", "organizationalUnit");
+    attribute(&mut text, "ou", &department.name);
+  }
+
+  let members = dump
+    .members_by_department
+    .values()
+    .flatten()
+    .unique_by(|x| &x.user_id);
+  for member in members {
+    text.push('\n');
+    let parent = member
+      .main_department
+      .iter()
+      .chain(&member.department)
+      .find_map(|id| dn(*id))
+      .unwrap_or_else(|| base_dn.to_string());
+    let dn = format!("uid={},{parent}", escape_dn(&member.user_id));
+    attribute(&mut text, "dn", &dn);
+    for class in ["top", "person", "organizationalPerson", "inetOrgPerson"] {
+      attribute(&mut text, "objectClass", class);
+    }
+    attribute(&mut text, "uid", &member.user_id);
+    attribute(&mut text, "cn", &member.name);
+    attribute(&mut text, "sn", &member.name);
+    for (name, value) in [
+      ("mail", &member.email),
+      ("mobile", &member.mobile),
+      ("title", &member.position),
+    ] {
+      if !value.is_empty() {
+        attribute(&mut text, name, value);
+      }
+    }
+    for path in member.department.iter().filter_map(|id| path(*id)) {
+      attribute(&mut text, "ou", &path);
+    }
+  }
+  text
+}
+
+/// Append `name: value`, or `name:: <base64>` if the value is not a safe string of RFC 2849
+fn attribute(text: &mut String, name: &str, value: &str) {
+  let safe = value
+    .bytes()
+    .all(|b| b.is_ascii() && b != b'\0' && b != b'\n' && b != b'\r')
+    && !value.starts_with([' ', ':', '<'])
+    && !value.ends_with(' ');
+  // writing into a String never fails
+  let _ = match safe {
+    true => writeln!(text, "{name}: {value}"),
+    false => writeln!(text, "{name}:: {}", STANDARD.encode(value)),
+  };
+}
+
+/// Escape an attribute value in a DN, see RFC 4514
+fn escape_dn(value: &str) -> String {
+  let mut escaped = String::new();
+  let last = value.chars().count().saturating_sub(1);
+  for (i, c) in value.chars().enumerate() {
+    let special = matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\' | '=')
+      || (i == 0 && matches!(c, '#' | ' '))
+      || (i == last && c == ' ');
+    if special {
+      escaped.push('\\');
+    }
+    escaped.push(c);
+  }
+  escaped
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::api::data::{Department, DepartmentMember};
+  use crate::api::dump::Dump;
+  use crate::ldif::{escape_dn, to_ldif};
+
+  fn department(id: u32, name: &str, parent_id: u32) -> Department {
+    Department {
+      id,
+      name: name.to_string(),
+      parent_id: Some(parent_id),
+      order: 0,
+      name_en: None,
+      department_leader: None,
+    }
+  }
+
+  #[test]
+  fn to_ldif_test() {
+    let member = DepartmentMember::test(
+      "zhangsan",
+      json!({
+        "name": "张三", "department": [2, 1], "main_department": 2, "position": "Engineer",
+        "email": "zs@example.com",
+      }),
+    );
+    let dump = Dump {
+      departments: vec![department(2, "R&D", 1), department(1, "Company", 0)],
+      members_by_department: BTreeMap::from([(1, vec![Arc::new(member)])]),
+      ..Default::default()
+    };
+    let ldif = to_ldif(&dump, "dc=example,dc=com");
+    assert_eq!(
+      ldif,
+      "version: 1\n\
+       \n\
+       dn: ou=Company,dc=example,dc=com\n\
+       objectClass: top\n\
+       objectClass: organizationalUnit\n\
+       ou: Company\n\
+       \n\
+       dn: ou=R&D,ou=Company,dc=example,dc=com\n\
+       objectClass: top\n\
+       objectClass: organizationalUnit\n\
+       ou: R&D\n\
+       \n\
+       dn: uid=zhangsan,ou=R&D,ou=Company,dc=example,dc=com\n\
+       objectClass: top\n\
+       objectClass: person\n\
+       objectClass: organizationalPerson\n\
+       objectClass: inetOrgPerson\n\
+       uid: zhangsan\n\
+       cn:: 5byg5LiJ\n\
+       sn:: 5byg5LiJ\n\
+       mail: zs@example.com\n\
+       title: Engineer\n\
+       ou: Company/R&D\n\
+       ou: Company\n"
+    );
+  }
+
+  #[test]
+  fn escape_dn_test() {
+    assert_eq!(escape_dn("a,b+c"), "a\\,b\\+c");
+    assert_eq!(escape_dn("#a "), "\\#a\\ ");
+    assert_eq!(escape_dn("研发"), "研发");
+  }
+}
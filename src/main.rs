@@ -1,28 +1,98 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
-use std::process::exit;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process::{exit, ExitStatus};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{env, fs};
 
-use anyhow::{Context, Result};
-use clap::{Parser, ValueHint};
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use clap::builder::PossibleValue;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
 use clap_verbosity_flag::Verbosity;
+use futures_util::StreamExt;
 use itertools::Itertools;
-use log::{debug, error, info, warn};
+use log::{debug, error, info, warn, LevelFilter};
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue};
 use reqwest::Url;
-use tokio::spawn;
+use tokio::sync::watch;
 use tokio::time::sleep;
+use tokio::{select, signal, spawn};
+use tracing_subscriber::EnvFilter;
 
-use crate::api::WxClient;
-use crate::util::ReplaceSpecial;
+use crate::api::data::{ApiError, GetTokenResp};
+use crate::api::dump::{parse_name_filter, DumpObserver, DumpOptions, FailFast, Job};
+use crate::api::limiter::RateLimiter;
+use crate::api::proxy::{parse_host_proxy, parse_proxy, ProxyConfig};
+use crate::api::tls::ClientIdentity;
+use crate::api::{
+  parse_header, Jitter, WxClient, API_BASE, DEFAULT_MAX_RESPONSE_BYTES,
+  DEFAULT_RATE_LIMIT_COOLDOWN, DEFAULT_RETRIES,
+};
+use crate::checkpoint::{Checkpointer, CHECKPOINT};
+use crate::combined::Combined;
+use crate::diagnose::Diagnosis;
+use crate::exit_code::{ExitCode, EXIT_CODES};
+use crate::hook::PostHook;
+use crate::layout::{
+  is_managed, Layout, NameTemplate, Paths, DEFAULT_NAME_TEMPLATE, DEFAULT_TIMESTAMP_FORMAT,
+};
+use crate::manifest::MANIFEST;
+use crate::merge::Previous;
+use crate::merged::Merged;
+use crate::metrics::Metrics;
+use crate::notify::NotifyOn;
+#[cfg(feature = "s3")]
+use crate::s3::{S3Keys, S3Location, S3Sink};
+use crate::secrets::Secrets;
+use crate::sink::{write_json_to, FsSink, OutputSink, QueuedSink, DEFAULT_WRITE_QUEUE};
+use crate::style::JsonStyle;
+use crate::summary::{Config, Summary};
+use crate::util::{write_json, ReplaceSpecial, Secret};
+use crate::validate::Validation;
+use crate::writer::FileWriter;
 
+mod anomalies;
 mod api;
+mod avatars;
+mod checkpoint;
+mod combined;
+mod csv_export;
+mod diagnose;
+mod diff;
+mod exit_code;
+mod graph;
+mod hook;
+mod layout;
+mod ldif;
+mod manifest;
+mod merge;
+mod merged;
+mod metrics;
+mod notify;
+#[cfg(feature = "parquet")]
+mod parquet_export;
+#[cfg(feature = "s3")]
+mod s3;
+mod schema;
+mod secrets;
+mod sink;
+mod style;
+mod summary;
 mod util;
+mod validate;
+mod vcard;
+mod writer;
+#[cfg(feature = "xlsx")]
+mod xlsx;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(name = "qywx-dumper", bin_name = "qywx-dumper", version, about, long_about = None)]
+#[clap(after_help = EXIT_CODES)]
 struct Cli {
   /// Output directory
   #[arg(short = 'O', long, value_parser, value_name = "DIR")]
@@ -34,363 +104,1597 @@ struct Cli {
   corp_id: Option<String>,
   /// Corporation Secret, every app has one
   #[arg(short = 's', long)]
-  #[arg(env = "WX_CORP_SECRET", value_parser, value_name = "SECRET")]
-  corp_secret: Option<String>,
+  #[arg(
+    env = "WX_CORP_SECRET",
+    hide_env_values = true,
+    value_parser,
+    value_name = "SECRET"
+  )]
+  corp_secret: Option<Secret>,
+  /// Secret of contact sync (通讯录同步), used for departments, members and tags instead of
+  /// the app secret, which may not be permitted to read the whole address book
+  #[arg(long, requires = "corp_id")]
+  #[arg(
+    env = "WX_CONTACT_SECRET",
+    hide_env_values = true,
+    value_parser,
+    value_name = "SECRET"
+  )]
+  contact_secret: Option<Secret>,
   /// Token, requires: (ID and Secret) or TOKEN
   #[arg(short = 't', long)]
-  #[arg(env = "WX_CORP_TOKEN", value_parser, value_name = "SECRET")]
-  corp_token: Option<String>,
+  #[arg(
+    env = "WX_CORP_TOKEN",
+    hide_env_values = true,
+    value_parser,
+    value_name = "SECRET"
+  )]
+  corp_token: Option<Secret>,
+  /// Shell command printing the access token to stdout, run on startup and again when
+  /// the token expires, for tokens minted by another service
+  #[arg(long, value_name = "CMD", conflicts_with_all = ["corp_secret", "corp_token"])]
+  token_command: Option<String>,
+  /// File of corp_id, corp_secret or corp_token, as key=value lines or a JSON object,
+  /// preferred over environment variables but not flags
+  #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+  secret_file: Option<PathBuf>,
+  /// Read the secrets like --secret-file from stdin, or prompt for them on a terminal
+  #[arg(long, value_parser, conflicts_with = "secret_file")]
+  secret_stdin: bool,
   /// Custom user agent, optional
   #[arg(short = 'u', long)]
   user_agent: Option<String>,
-  /// Sending request through a proxy, http, https, socks5 are supported
-  #[arg(short = 'p', long, value_parser, value_name = "URL")]
+  /// File of user agents, one per line, rotated through per request
+  #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+  #[arg(conflicts_with = "user_agent")]
+  user_agent_file: Option<PathBuf>,
+  /// Custom header sent with every request, like "X-Request-ID: 1", can be repeated.
+  /// ${VAR} is replaced by the environment variable
+  #[arg(short = 'H', long, value_parser = parse_header, value_name = "KEY: VALUE")]
+  header: Vec<(HeaderName, HeaderValue)>,
+  /// Sending request through a proxy, http, https, socks5 are supported.
+  /// ${VAR} in any proxy url is replaced by the environment variable
+  #[arg(short = 'p', long, value_parser = parse_proxy, value_name = "URL")]
   proxy: Option<Url>,
+  /// Proxy for http requests only, preferred over --proxy
+  #[arg(long, value_parser = parse_proxy, value_name = "URL")]
+  proxy_http: Option<Url>,
+  /// Proxy for https requests only, preferred over --proxy
+  #[arg(long, value_parser = parse_proxy, value_name = "URL")]
+  proxy_https: Option<Url>,
+  /// Proxy for requests to a host only, preferred over the others, can be repeated
+  #[arg(long, value_parser = parse_host_proxy, value_name = "HOST=URL")]
+  proxy_host: Vec<(String, Url)>,
   /// Proxy username, optional
   #[arg(long, value_parser, alias = "user", value_name = "USER")]
+  #[arg(requires = "proxy_password")]
   proxy_user: Option<String>,
   /// Proxy password, optional
   #[arg(long, value_parser, alias = "password", value_name = "PWD")]
-  proxy_password: Option<String>,
-  /// always overwrite files
+  #[arg(requires = "proxy_user")]
+  proxy_password: Option<Secret>,
+  /// PEM certificate presented to servers requiring mutual TLS, like a gateway in front of
+  /// the API, with its key in --client-key
+  #[arg(long, value_parser, value_name = "PEM", requires = "client_key")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  client_cert: Option<PathBuf>,
+  /// PEM private key of --client-cert, PKCS#8 unless built with rustls
+  #[arg(long, value_parser, value_name = "PEM", requires = "client_cert")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  client_key: Option<PathBuf>,
+  /// PKCS#12 archive of the client certificate and key, instead of --client-cert,
+  /// needs the native-tls feature
+  #[arg(long, value_parser, value_name = "P12", conflicts_with = "client_cert")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  identity: Option<PathBuf>,
+  /// Password of the --identity archive, empty by default
+  #[arg(long, requires = "identity")]
+  #[arg(
+    env = "WX_IDENTITY_PASSWORD",
+    hide_env_values = true,
+    value_parser,
+    value_name = "PWD"
+  )]
+  identity_password: Option<Secret>,
+  /// Skip checking the proxy with a HEAD request to the API host before login
+  #[arg(long, value_parser)]
+  no_preflight: bool,
+  /// Overwrite the files created by this tool in the output directory
   #[arg(short = 'y', long, value_parser, alias = "yes")]
   overwrite: bool,
-  /// Fetch departments members recursively
+  /// Overwrite even if the output directory contains files not created by this tool,
+  /// which are kept anyway
+  #[arg(long, value_parser, requires = "overwrite")]
+  force: bool,
+  /// Fetch departments members recursively, each top-level department is fetched once
+  /// with its descendants and split into the files of every department
   #[arg(short = 'r', long, value_parser, default_value_t = false)]
   recursive: bool,
+  /// Fetch members recursively only for top-level departments, and non-recursively for the rest,
+  /// so a member appears in its own department and its top-level department only
+  #[arg(long, value_parser, conflicts_with = "recursive")]
+  recursive_root_only: bool,
+  /// Add the full name path of each department to members, like `Company/Engineering/Backend`
+  #[arg(long, value_parser)]
+  annotate_departments: bool,
+  /// Trim the contact fields of members, lowercase emails and strip the +86 prefix and
+  /// separators of mobiles, the raw values changed are kept in `_raw`
+  #[arg(long, value_parser)]
+  normalize_contacts: bool,
+  /// Only fetch the first N departments and tags sorted by id, for quick smoke tests
+  #[arg(long, value_parser, value_name = "N", conflicts_with = "diff_against")]
+  limit: Option<usize>,
+  /// Only fetch the departments whose name matches REGEX, case-insensitive unless it starts
+  /// with (?-i)
+  #[arg(long, value_parser = parse_name_filter, value_name = "REGEX")]
+  #[arg(conflicts_with = "diff_against")]
+  department_name_filter: Option<Regex>,
+  /// Only fetch the departments with these ids, separated by commas, and their members
+  #[arg(long = "departments", value_name = "ID", value_delimiter = ',', num_args = 1..)]
+  #[arg(conflicts_with = "diff_against")]
+  department_ids: Option<Vec<u32>>,
+  /// Only fetch the tags whose name matches REGEX, case-insensitive unless it starts with (?-i)
+  #[arg(long, value_parser = parse_name_filter, value_name = "REGEX")]
+  #[arg(conflicts_with = "diff_against")]
+  tag_name_filter: Option<Regex>,
+  /// Only fetch the tags with these ids, separated by commas
+  #[arg(long = "tags", value_name = "ID", value_delimiter = ',', num_args = 1..)]
+  #[arg(conflicts_with = "diff_against")]
+  tag_ids: Option<Vec<u32>>,
+  /// Also write the members of all fetched tags to tags_union.json, each listed once
+  /// with the tags it is in
+  #[arg(long, value_parser, requires = "tag_ids", conflicts_with = "stdout")]
+  tags_union: bool,
+  /// Drop disabled and resigned members from the output, raw responses saved by --save-raw
+  /// are kept in full
+  #[arg(long, value_parser)]
+  active_only: bool,
+  /// Fetch only the user id, name and departments of members from the simple list, which is
+  /// much smaller than the full details for large tenants
+  #[arg(long, value_parser)]
+  #[arg(conflicts_with_all = ["active_only", "lenient", "annotate_departments", "normalize_contacts"])]
+  minimal_members: bool,
+  /// Abort on the first job, department, tag or agent failed to fetch and exit with 1,
+  /// instead of saving what was fetched, for CI where an incomplete dump is useless
+  #[arg(long, value_parser)]
+  fail_fast: bool,
+  /// Skip a department, or a subtree with --recursive, whose members are not fetched within
+  /// DURATION like 2m including retries, listed in departments/_timed_out.json
+  #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+  item_timeout: Option<Duration>,
+  /// Fail the departments whose member list looks truncated by the API, a multiple of 1000
+  /// members without child departments, instead of flagging them in summary.json, and exit
+  /// with 2 if any department, tag or agent is missing
+  #[arg(long, value_parser)]
+  strict_completeness: bool,
+  /// Deserialize members one by one, and save the ones of an unexpected shape to
+  /// <file>.errors.json beside their department instead of failing the whole department
+  #[arg(long, value_parser)]
+  lenient: bool,
   /// Delay for batch requests, in ms
   #[arg(short = 'd', long, value_parser, default_value_t = 200)]
   delay: u64,
+  /// Max retries of a request on transient failures, like network errors or 5xx
+  #[arg(long, value_parser, value_name = "N", default_value_t = DEFAULT_RETRIES)]
+  retries: u32,
+  /// Randomize the backoff between retries, so concurrent requests failed by the same blip
+  /// do not retry at the same instant
+  #[arg(long, value_enum, value_name = "STRATEGY", default_value_t = Jitter::Full)]
+  retry_jitter: Jitter,
+  /// Max idle connections kept per host for reuse, unlimited by default.
+  /// Reusing connections saves a TCP and TLS handshake per request, 0 disables reuse,
+  /// which may help with proxies dropping idle connections
+  #[arg(long, value_parser, value_name = "N")]
+  pool_size: Option<usize>,
+  /// Seconds to pause all requests after hitting the frequency limit (45009)
+  #[arg(long, value_parser, value_name = "SECS")]
+  #[arg(default_value_t = DEFAULT_RATE_LIMIT_COOLDOWN.as_secs())]
+  rate_limit_cooldown: u64,
+  /// Stop sending requests after N requests including retries, to avoid exhausting the daily
+  /// quota, what was fetched is still saved and the dump is marked incomplete in summary.json
+  #[arg(long, value_parser, value_name = "N")]
+  max_requests: Option<u64>,
+  /// Fail a response of the API larger than N bytes instead of reading it into memory,
+  /// against a misbehaving proxy or gateway
+  #[arg(long, value_parser, value_name = "N", default_value_t = DEFAULT_MAX_RESPONSE_BYTES)]
+  max_response_bytes: u64,
+  /// Max requests per second, shared by all jobs, unlimited by default
+  #[arg(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+  qps: Option<u32>,
+  /// Delay for department members requests, in ms, defaults to --delay
+  #[arg(long, value_parser, value_name = "DELAY")]
+  department_delay: Option<u64>,
+  /// Delay for tag members requests, in ms, defaults to --delay
+  #[arg(long, value_parser, value_name = "DELAY")]
+  tag_delay: Option<u64>,
+  /// Max concurrent department members requests, unlimited by default
+  #[arg(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+  department_concurrency: Option<u32>,
+  /// Max concurrent tag members requests, unlimited by default
+  #[arg(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+  tag_concurrency: Option<u32>,
+  /// Max files waiting to be written by the writer thread, fetching pauses while the queue
+  /// is full, so slow storage holds back requests instead of buffering everything in memory
+  #[arg(long, value_parser, value_name = "N", default_value_t = DEFAULT_WRITE_QUEUE)]
+  write_queue: usize,
+  /// Upload the files of the dump to an S3-compatible store at s3://BUCKET/PREFIX instead of
+  /// the output directory, keyed like the files in it, with summary.json and manifest.json.
+  /// Each file is buffered and uploaded whole. Raw responses, avatars, metrics, checkpoints and
+  /// exports like --xlsx are still written to the output directory. Without --s3-access-key
+  /// and --s3-secret-key, the credentials of a role are used: a web identity like IRSA of
+  /// Kubernetes, the container credentials of ECS, or a profile in ~/.aws
+  #[cfg(feature = "s3")]
+  #[arg(long, value_parser = s3::parse_location, value_name = "URL")]
+  #[arg(conflicts_with_all = ["stdout", "merge"])]
+  s3: Option<S3Location>,
+  /// Endpoint of the --s3 store, like http://minio:9000, the one of AWS in --s3-region by
+  /// default
+  #[cfg(feature = "s3")]
+  #[arg(long, env = "AWS_ENDPOINT_URL", value_parser, value_name = "URL")]
+  s3_endpoint: Option<Url>,
+  /// Region the uploads to --s3 are signed for
+  #[cfg(feature = "s3")]
+  #[arg(
+    long,
+    env = "AWS_REGION",
+    value_name = "REGION",
+    default_value = "us-east-1"
+  )]
+  s3_region: String,
+  /// Access key id of the --s3 store
+  #[cfg(feature = "s3")]
+  #[arg(long, env = "AWS_ACCESS_KEY_ID", value_parser, value_name = "ID")]
+  s3_access_key: Option<String>,
+  /// Secret access key of the --s3 store
+  #[cfg(feature = "s3")]
+  #[arg(long, env = "AWS_SECRET_ACCESS_KEY", hide_env_values = true)]
+  #[arg(value_parser, value_name = "KEY")]
+  s3_secret_key: Option<Secret>,
+  /// Session token of temporary --s3-access-key and --s3-secret-key
+  #[cfg(feature = "s3")]
+  #[arg(long, env = "AWS_SESSION_TOKEN", hide_env_values = true)]
+  #[arg(value_parser, value_name = "TOKEN", requires = "s3_access_key")]
+  s3_session_token: Option<Secret>,
+  /// How output files are organized in the output directory
+  #[arg(long, value_enum, default_value_t = Layout::Nested)]
+  layout: Layout,
+  /// Append the start time of the run to top-level files and folders,
+  /// like departments-20240115T0900.json, to keep runs side by side without --overwrite
+  #[arg(long, value_parser)]
+  timestamp_files: bool,
+  /// strftime format of the time appended by --timestamp-files, also telling the stamped files
+  /// apart when clearing the output directory and in --validate
+  #[arg(
+    long,
+    value_parser,
+    value_name = "FORMAT",
+    requires = "timestamp_files"
+  )]
+  #[arg(default_value = DEFAULT_TIMESTAMP_FORMAT)]
+  timestamp_format: String,
+  /// Name of the member file of each department and tag, with placeholders {id}, {name}
+  /// and {parent_id}, which is empty for tags
+  #[arg(
+    long,
+    value_parser,
+    value_name = "TEMPLATE",
+    conflicts_with_all = ["diff_against", "merge"]
+  )]
+  #[arg(default_value = DEFAULT_NAME_TEMPLATE)]
+  name_template: NameTemplate,
+  /// Percent-decode names in file names, for tenants returning them URL-encoded like
+  /// %E9%94%80%E5%94%AE, malformed sequences are kept as is
+  #[arg(long, value_parser)]
+  decode_names: bool,
+  /// Also export all departments, members and tags to a XLSX workbook
+  #[cfg(feature = "xlsx")]
+  #[arg(long, value_parser, value_name = "FILE")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  xlsx: Option<PathBuf>,
+  /// Also export members to a CSV file, with the same columns as the Members sheet of --xlsx
+  #[arg(long, value_parser, value_name = "FILE")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  csv: Option<PathBuf>,
+  /// Prepend the UTF-8 BOM to the CSV file, for spreadsheet apps that guess the encoding
+  #[arg(long, value_parser, requires = "csv")]
+  csv_bom: bool,
+  /// Field delimiter of the CSV file, a single ASCII symbol or `tab`
+  #[arg(long, value_parser = csv_export::parse_delimiter, value_name = "CHAR")]
+  #[arg(default_value = ",", requires = "csv")]
+  csv_delimiter: u8,
+  /// Also export departments, members, tags and tag members to Parquet files in DIR,
+  /// with the same columns as --xlsx
+  #[cfg(feature = "parquet")]
+  #[arg(long, value_parser, value_name = "DIR")]
+  #[arg(value_hint = ValueHint::DirPath)]
+  parquet: Option<PathBuf>,
+  /// Also export departments, members and tags as nodes.csv, and their relationships as
+  /// edges.csv in DIR, for `neo4j-admin database import`
+  #[arg(long, value_parser, value_name = "DIR")]
+  #[arg(value_hint = ValueHint::DirPath)]
+  graph: Option<PathBuf>,
+  /// Also write agents, departments, tags and their members to a single JSON file
+  #[arg(long, value_parser, value_name = "FILE")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  combined: Option<PathBuf>,
+  /// Add the names of the departments and tags visible to each agent to its details
+  #[arg(long, value_parser)]
+  resolve_agent_scopes: bool,
+  /// Also export departments as organizational units and members as inetOrgPerson entries
+  /// to a LDIF file, for importing into a LDAP directory
+  #[arg(long, value_parser, value_name = "FILE", requires = "ldif_base_dn")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  ldif: Option<PathBuf>,
+  /// Base DN of the entries in the LDIF file, like dc=example,dc=com
+  #[arg(long, value_parser, value_name = "DN", requires = "ldif")]
+  ldif_base_dn: Option<String>,
+  /// Also export members as vCards to a VCF file, for importing into an address book
+  #[arg(long, value_parser, value_name = "FILE")]
+  #[arg(value_hint = ValueHint::FilePath)]
+  vcard: Option<PathBuf>,
+  /// Compare members of departments and tags with a previous dump, and save to diff.json
+  #[arg(long, value_parser, value_name = "DIR")]
+  #[arg(value_hint = ValueHint::DirPath)]
+  diff_against: Option<PathBuf>,
+  /// Write departments, members and tags to stdout as one JSON document instead of files,
+  /// logs are always written to stderr
+  #[arg(long, value_parser)]
+  #[arg(conflicts_with_all = ["diff_against", "save_raw", "external_contacts"])]
+  #[arg(conflicts_with_all = ["overwrite", "layout", "timestamp_files"])]
+  stdout: bool,
+  /// Dump again every DURATION like 30m or 2h, each run into a folder named by its time,
+  /// until Ctrl-C which waits for the current run to finish
+  #[arg(long, value_parser = humantime::parse_duration, value_name = "DURATION")]
+  #[arg(conflicts_with_all = ["stdout", "check", "layout"])]
+  interval: Option<Duration>,
+  /// Save the members fetched so far to checkpoint.json every N departments and tags,
+  /// so an interrupted run can be continued with --resume
+  #[arg(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+  #[arg(conflicts_with_all = ["stdout", "interval"])]
+  checkpoint_interval: Option<u32>,
+  /// Dump into the existing output directory, overwriting the files fetched again and keeping
+  /// the others, the departments and tags of the previous run are kept in the summaries
+  #[arg(long, value_parser)]
+  #[arg(conflicts_with_all = ["stdout", "interval", "overwrite", "resume", "timestamp_files"])]
+  merge: bool,
+  /// Continue an interrupted run in the existing output directory, the departments and tags
+  /// in its checkpoint are not fetched again
+  #[arg(long, value_parser, conflicts_with_all = ["stdout", "interval", "overwrite"])]
+  resume: bool,
+  /// Casing of the keys in the output JSON, raw responses saved by --save-raw are kept as is
+  #[arg(long, value_enum, value_name = "STYLE", default_value_t = JsonStyle::Wechat)]
+  #[arg(conflicts_with_all = ["diff_against", "merge"])]
+  json_style: JsonStyle,
+  /// Print the JSON Schema of the saved responses to stdout and exit, no login is needed
+  #[arg(long, value_parser)]
+  emit_schema: bool,
+  /// Fetch external contacts of every member, requires the permission of external contacts
+  #[arg(long, value_parser)]
+  external_contacts: bool,
+  /// Download the avatar of every member to ./avatars, existing ones are skipped
+  #[arg(long, value_parser, conflicts_with_all = ["stdout", "offline"])]
+  avatars: bool,
+  /// Max concurrent avatar downloads
+  #[arg(long, value_parser = clap::value_parser!(u32).range(1..), value_name = "N")]
+  #[arg(default_value_t = 4)]
+  avatar_concurrency: u32,
+  /// Write JSON without indentation, smaller and faster for machine consumers
+  #[arg(long, value_parser)]
+  compact: bool,
+  /// Save raw responses to ./raw in the output directory, for debugging
+  #[arg(long, value_parser)]
+  save_raw: bool,
+  /// Read the raw responses saved by --save-raw from DIR instead of the API, to re-run the output
+  /// with the same flags like --recursive. No credentials are needed
+  #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+  #[arg(conflicts_with_all = ["save_raw", "check"])]
+  offline: Option<PathBuf>,
+  /// Only check the credentials and permissions, without dumping anything
+  #[arg(long, value_parser)]
+  check: bool,
+  /// Check the proxy, DNS, login and every endpoint in order, and print a table of the results,
+  /// exiting with 1 if the essential ones fail
+  #[arg(long, value_parser, conflicts_with_all = ["check", "offline", "save_raw"])]
+  diagnose: bool,
+  /// Check the dump in DIR without the network and exit: parse every JSON file, require a zero
+  /// errcode in the responses, and verify the files against its manifest.json if there is one.
+  /// Prints the bad files and exits with 1 if there are any
+  #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+  #[arg(conflicts_with_all = ["check", "diagnose", "offline", "stream_members"])]
+  validate: Option<PathBuf>,
+  /// Print the members of all departments to stdout as JSON lines, each once, and exit without
+  /// anything else, holding one department at a time in memory for large tenants.
+  /// With --departments, only the members of those departments are fetched, concurrently
+  #[arg(long, value_parser, conflicts_with_all = ["stdout", "check", "diagnose", "interval"])]
+  stream_members: bool,
+  /// Shell command run after a successful dump, with the output folder and the counts of the
+  /// summary in QYWX_OUTPUT_DIR, QYWX_MEMBER_COUNT and so on. Exits with its code if it fails
+  #[arg(long, value_name = "CMD", conflicts_with_all = ["stdout", "check", "diagnose"])]
+  post_hook: Option<String>,
+  /// Run --post-hook after failed dumps too, with QYWX_SUCCESS=0
+  #[arg(long, value_parser, requires = "post_hook")]
+  post_hook_always: bool,
+  /// POST the status, counts, failures, duration and output folder of each run as JSON to URL,
+  /// with a `text` line for chat webhooks like Slack
+  #[arg(long, value_parser = Url::parse, value_name = "URL")]
+  #[arg(conflicts_with_all = ["stdout", "check", "diagnose"])]
+  notify_webhook: Option<Url>,
+  /// Which runs to notify --notify-webhook of
+  #[arg(long, value_enum, value_name = "WHEN", default_value_t = NotifyOn::Always)]
+  #[arg(requires = "notify_webhook")]
+  notify_on: NotifyOn,
+  /// Only print errors, overriding -v and -q, for cron jobs. RUST_LOG is still honored if set
+  #[arg(long, value_parser)]
+  silent: bool,
+  /// Format of the logs, json includes the request id and item of every line
+  #[arg(long, value_enum, value_name = "FORMAT", default_value_t = LogFormat::Text)]
+  log_format: LogFormat,
   #[clap(flatten)]
   verbose: Verbosity<DefaultLevel>,
+  #[command(subcommand)]
+  command: Option<Command>,
+}
+
+/// One-shot lookups printed to stdout instead of a dump, the options above go before them
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+  /// Print the members and departments of a tag, like the tag files of a dump, respecting
+  /// --compact and --json-style
+  Tag {
+    /// Tag id
+    id: u32,
+  },
+}
+
+impl Cli {
+  /// Fill the credentials from --secret-file or --secret-stdin, which take precedence over
+  /// environment variables but not over flags
+  fn apply_secrets(&mut self, matches: &ArgMatches) -> Result<()> {
+    let secrets = match (&self.secret_file, self.secret_stdin) {
+      (Some(path), _) => Secrets::read_file(path)?,
+      (None, true) => Secrets::read_stdin()?,
+      (None, false) => return Ok(()),
+    };
+    let from_flag = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    if let (false, Some(corp_id)) = (from_flag("corp_id"), secrets.corp_id) {
+      self.corp_id = Some(corp_id);
+    }
+    if let (false, Some(corp_secret)) = (from_flag("corp_secret"), secrets.corp_secret) {
+      self.corp_secret = Some(corp_secret);
+    }
+    if let (false, Some(corp_token)) = (from_flag("corp_token"), secrets.corp_token) {
+      self.corp_token = Some(corp_token);
+    }
+    Ok(())
+  }
+
+  /// Check the flag combinations which can not be expressed by clap, exit on conflicts
+  fn validate(&self) {
+    let has_proxy = self.proxy.is_some()
+      || self.proxy_http.is_some()
+      || self.proxy_https.is_some()
+      || !self.proxy_host.is_empty();
+    if self.proxy_user.is_some() && !has_proxy {
+      Cli::command()
+        .error(
+          ErrorKind::MissingRequiredArgument,
+          "--proxy-user and --proxy-password require one of --proxy, --proxy-http, --proxy-https or --proxy-host",
+        )
+        .print()
+        .ok();
+      ExitCode::Generic.exit();
+    }
+    #[cfg(feature = "s3")]
+    if self.s3.is_some() {
+      let message = match (&self.s3_access_key, &self.s3_secret_key) {
+        (Some(_), Some(_)) => None,
+        (None, None) if s3::has_role_credentials() => None,
+        (None, None) => Some(
+          "--s3 requires --s3-access-key and --s3-secret-key, or the credentials of a role through \
+           AWS_WEB_IDENTITY_TOKEN_FILE, ECS or a profile in ~/.aws",
+        ),
+        _ => Some("--s3-access-key and --s3-secret-key must be given together"),
+      };
+      if let Some(message) = message {
+        Cli::command()
+          .error(ErrorKind::MissingRequiredArgument, message)
+          .print()
+          .ok();
+        ExitCode::Auth.exit();
+      }
+    }
+  }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-  let args: Cli = Cli::parse();
-  pretty_env_logger::env_logger::Builder::new()
-    .filter_level(args.verbose.log_level_filter())
-    .init();
+async fn main() {
+  let matches = Cli::command()
+    .try_get_matches()
+    .unwrap_or_else(|err| exit_clap(err));
+  let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|err| exit_clap(err));
+  args.validate();
+  init_logging(&args);
+  if let Err(err) = args.apply_secrets(&matches) {
+    error!("Failed to read secrets: {err:?}");
+    ExitCode::Auth.exit();
+  }
+  if let Err(err) = start(args).await {
+    error!("{err:?}");
+    ExitCode::of(&err).exit();
+  }
+}
+
+/// Print the error or help of clap, and exit with [ExitCode::Generic] for errors instead of 2
+/// of clap, which means a partial dump
+fn exit_clap(err: clap::Error) -> ! {
+  let _ = err.print();
+  match err.use_stderr() {
+    true => ExitCode::Generic.exit(),
+    false => exit(0),
+  }
+}
+
+async fn start(mut args: Cli) -> Result<()> {
   debug!("Args: {args:?}");
+  // one member per line
+  util::set_compact(args.compact || args.stream_members);
+
+  if args.emit_schema {
+    util::to_writer(std::io::stdout().lock(), &schema::schemas())
+      .context("Failed to write to stdout")?;
+    return Ok(());
+  }
+  // after the schema, which always describes the names of the API
+  style::set_json_style(args.json_style);
+
+  if let Some(dir) = &args.validate {
+    let validation = Validation::run(dir, &args.timestamp_format)
+      .with_context(|| format!("Failed to validate {}", dir.to_string_lossy()))?;
+    println!("{validation}");
+    if !validation.passed() {
+      ExitCode::Generic.exit();
+    }
+    return Ok(());
+  }
 
-  if (args.corp_id.is_none() && args.corp_secret.is_none()) && args.corp_token.is_none() {
+  if (args.corp_id.is_none() && args.corp_secret.is_none())
+    && args.corp_token.is_none()
+    && args.token_command.is_none()
+    && args.offline.is_none()
+  {
     error!("For login, you must provide: (ID and Secret) or Token.");
-    exit(1);
+    ExitCode::Auth.exit();
+  }
+
+  let offline = match &args.offline {
+    Some(path) => Some(std::path::absolute(path).context("Failed to resolve offline path")?),
+    None => None,
+  };
+  if let (Some(offline), true) = (&offline, args.overwrite) {
+    let output = std::path::absolute(&args.output).context("Failed to resolve output path")?;
+    if offline.starts_with(output) {
+      error!("The --offline directory is in the output directory, which would be overwritten.");
+      ExitCode::Output.exit();
+    }
+  }
+
+  if args.diagnose {
+    if !diagnose(&args).await {
+      ExitCode::Generic.exit();
+    }
+    return Ok(());
   }
 
-  if args.output.exists() {
-    if args.overwrite {
-      warn!("Overwriting files according to --overwrite option...");
-      if args.output.is_file() {
-        fs::remove_file(&args.output).context("Failed to delete file")?;
-      } else if args.output.is_dir() {
-        fs::remove_dir_all(&args.output).context("Failed to delete directory")?;
+  if args.check {
+    let (wx, login) = connect(&args).await;
+    if let Err(err) = check(&wx, login).await {
+      match ApiError::find(&err) {
+        Some(api_err) => error!("Check failed with {api_err}: {err:?}"),
+        None => error!("Check failed: {err:?}"),
       }
-    } else {
-      error!(
-        "Output path '{}', is already exists, append -y, --yes or --overwrite to overwrite it.",
-        args.output.to_string_lossy()
-      );
-      exit(1);
+      ExitCode::of(&err).exit();
     }
+    info!("Check passed");
+    return Ok(());
   }
 
-  fs::create_dir_all(&args.output).context("Failed to create folder 'output'")?;
+  if let Some(Command::Tag { id }) = args.command {
+    let (mut wx, _) = connect(&args).await;
+    wx.set_offline_dir(args.offline.clone());
+    return print_tag(&wx, id).await;
+  }
 
-  env::set_current_dir(&args.output).context("Failed to set current dir")?;
+  if args.stream_members {
+    let (mut wx, _) = connect(&args).await;
+    wx.set_offline_dir(args.offline.clone());
+    return match &args.department_ids {
+      Some(ids) => print_members(&wx, ids).await,
+      None => stream_members(&wx).await,
+    };
+  }
 
-  let wx = WxClient::new(
-    args.proxy,
-    args.proxy_user,
-    args.proxy_password,
-    args.user_agent,
-  )
-  .await;
+  // read before overwriting, to keep it if this run fails
+  let mut last_success = metrics::read_last_success(&args.output.join(METRICS));
 
-  let wx = match wx {
-    Ok(wx) => wx,
-    Err(err) => {
-      error!("Failed to create WeChat client: {:?}", err);
-      exit(1);
+  if !args.stdout {
+    // every run of --interval has its own folder, so the previous ones are kept,
+    // and --resume and --merge write into the previous run
+    let reuse = args.resume || args.merge;
+    if args.output.exists() && !reuse && (args.overwrite || args.interval.is_none()) {
+      if args.overwrite {
+        warn!("Overwriting files according to --overwrite option...");
+        if args.output.is_file() {
+          if !args.force {
+            error!(
+              "Output path '{}' is a file, append --force to delete it.",
+              args.output.to_string_lossy()
+            );
+            ExitCode::Output.exit();
+          }
+          fs::remove_file(&args.output).context("Failed to delete file")?;
+        } else if args.output.is_dir() {
+          clear_output(&args.output, args.force, &args.timestamp_format)?;
+        }
+      } else {
+        error!(
+          "Output path '{}', is already exists, append -y, --yes or --overwrite to overwrite it.",
+          args.output.to_string_lossy()
+        );
+        ExitCode::Output.exit();
+      }
     }
+
+    fs::create_dir_all(&args.output).context("Failed to create folder 'output'")?;
+  }
+
+  let exports = Exports {
+    #[cfg(feature = "xlsx")]
+    xlsx: absolute(&args.xlsx, "xlsx")?,
+    csv: absolute(&args.csv, "csv")?,
+    #[cfg(feature = "parquet")]
+    parquet: absolute(&args.parquet, "parquet")?,
+    graph: absolute(&args.graph, "graph")?,
+    combined: absolute(&args.combined, "combined")?,
+    vcard: absolute(&args.vcard, "vcard")?,
+    ldif: absolute(&args.ldif, "ldif")?,
+    diff_against: absolute(&args.diff_against, "previous dump")?,
   };
 
-  if args.corp_id.is_some() && args.corp_secret.is_some() {
-    if let Err(err) = wx
-      .login(&*args.corp_id.unwrap(), &*args.corp_secret.unwrap())
-      .await
-    {
-      error!("Failed to login with provided id and secret: {:?}", err);
-      exit(1);
-    };
-    let token = wx.token.read().expect("Lock Posioned");
-    if let Some(token) = token.as_ref() {
-      info!("Get token successfully: {}", token);
-    }
-  } else if args.corp_token.is_some() {
-    let mut token = wx.token.write().unwrap();
-    *token = args.corp_token;
-  } else {
-    error!("For login, you must provide: (ID and Secret) or Token.");
-    exit(1);
+  if let Some(path) = &args.user_agent_file {
+    let path = std::path::absolute(path).context("Failed to resolve user agent file path")?;
+    args.user_agent_file = Some(path);
   }
+  args.client_cert = absolute(&args.client_cert, "client certificate")?;
+  args.client_key = absolute(&args.client_key, "client key")?;
+  args.identity = absolute(&args.identity, "client identity")?;
 
-  let agent_job = {
-    let wx = wx.clone();
-    async move {
-      let agents = wx
-        .get_agent_list()
-        .await
-        .context("Failed to get agent list")?;
-
-      {
-        let agent_to_print = agents
-          .agent_list
-          .iter()
-          .map(|i| format!("{} - {}", i.id, i.name))
-          .join(", ");
-        info!("Agents: {agent_to_print}");
-        let file = File::create("agents.json").context("Failed to create agents.json")?;
-        let mut buf_writer = BufWriter::new(file);
-        buf_writer
-          .write(&*serde_json::to_vec_pretty(&agents).context("Failed to serialize")?)
-          .context("Failed to write json")?;
-      }
+  if !args.stdout {
+    env::set_current_dir(&args.output).context("Failed to set current dir")?;
+  }
 
-      fs::create_dir_all("agents").context("Failed to create folder ./agents")?;
-
-      let mut vec = Vec::new();
-      for x in agents.agent_list {
-        let wx = wx.clone();
-        let handle = spawn(async move {
-          let resp = match wx.get_agent_detail(x.id).await {
-            Ok(resp) => resp,
-            Err(err) => {
-              error!(
-                "Failed to get agent details: {} - {}: {:?}",
-                x.id, x.name, err
-              );
-              return;
-            }
-          };
-          let path = PathBuf::from(format!(
-            "agents/{}",
-            format!("agent-{}-{}.json", x.id, x.name).replace_special_char()
-          ));
-          let file = match File::create(&path) {
-            Ok(file) => file,
-            Err(err) => {
-              error!("Failed to create {}: {err:?}", path.to_string_lossy());
-              return;
-            }
-          };
-          let json = match serde_json::to_vec_pretty(&resp).context("Failed to serialize") {
-            Ok(json) => json,
-            Err(err) => {
-              error!("Failed to serialize json: {err:?}");
-              return;
-            }
-          };
-          let mut buf_writer = BufWriter::new(file);
-          match buf_writer.write(&*json) {
-            Ok(_) => info!(
-              "Successfully save agent details to {}",
-              path.to_string_lossy(),
-            ),
-            Err(err) => error!(
-              "Failed to save agent details to {}: {err:?}",
-              path.to_string_lossy()
-            ),
-          };
-        });
-        vec.push(handle);
-        sleep(Duration::from_millis(args.delay)).await;
+  let (mut wx, _) = connect(&args).await;
+  wx.set_offline_dir(offline);
+
+  if let Some(qps) = args.qps {
+    wx.set_rate_limiter(Arc::new(RateLimiter::new(qps)));
+  }
+
+  let post_hook = args
+    .post_hook
+    .clone()
+    .map(|x| PostHook::new(x, args.post_hook_always));
+
+  let Some(interval) = args.interval else {
+    let outcome = run(&args, &mut wx, &exports, last_success).await;
+    notify_webhook(&args, &wx, &outcome).await;
+    if let Some(hook) = &post_hook {
+      if let Some(status) = run_post_hook(hook, &outcome).await? {
+        if !status.success() {
+          error!("Post hook exited with {status}");
+          exit(status.code().unwrap_or(1));
+        }
       }
-      for x in vec {
-        x.await?;
+    }
+    if outcome?.failed {
+      match wx.stats().budget_exhausted() {
+        true => ExitCode::Quota.exit(),
+        false => ExitCode::Partial.exit(),
       }
+    }
+    return Ok(());
+  };
 
-      let result: Result<()> = Ok(());
-      result
+  let (stop_tx, mut stop) = watch::channel(false);
+  spawn(async move {
+    if signal::ctrl_c().await.is_ok() {
+      warn!("Stopping after the current run, press Ctrl-C again to exit now");
+      let _ = stop_tx.send(true);
+      if signal::ctrl_c().await.is_ok() {
+        exit(130);
+      }
+    }
+  });
+  loop {
+    let outcome = run(&args, &mut wx, &exports, last_success).await;
+    notify_webhook(&args, &wx, &outcome).await;
+    if let Some(hook) = &post_hook {
+      match run_post_hook(hook, &outcome).await {
+        Ok(Some(status)) if !status.success() => error!("Post hook exited with {status}"),
+        Ok(_) => {}
+        Err(err) => error!("{err:?}"),
+      }
+    }
+    match outcome {
+      Ok(x) if !x.failed => {}
+      Ok(_) => warn!("This run has failures, continue with the next one"),
+      Err(err) => error!("This run failed, continue with the next one: {err:?}"),
+    }
+    last_success = metrics::read_last_success(Path::new(METRICS));
+    if *stop.borrow() {
+      break;
     }
+    info!("Next run in {}", humantime::format_duration(interval));
+    select! {
+      _ = sleep(interval) => {}
+      _ = stop.changed() => break,
+    }
+    if let Err(err) = relogin(&args, &wx).await {
+      error!("Failed to login again for the next run: {err:?}");
+    }
+  }
+  info!("Stopped");
+  Ok(())
+}
+
+/// Paths of the files exported besides the output directory, resolved before changing
+/// into it
+struct Exports {
+  #[cfg(feature = "xlsx")]
+  xlsx: Option<PathBuf>,
+  csv: Option<PathBuf>,
+  #[cfg(feature = "parquet")]
+  parquet: Option<PathBuf>,
+  graph: Option<PathBuf>,
+  combined: Option<PathBuf>,
+  vcard: Option<PathBuf>,
+  ldif: Option<PathBuf>,
+  diff_against: Option<PathBuf>,
+}
+
+fn absolute(path: &Option<PathBuf>, name: &str) -> Result<Option<PathBuf>> {
+  match path {
+    Some(path) => Ok(Some(
+      std::path::absolute(path).with_context(|| format!("Failed to resolve {name} path"))?,
+    )),
+    None => Ok(None),
+  }
+}
+
+/// What a finished run did
+struct Outcome {
+  /// Whether anything failed
+  failed: bool,
+  /// Folder of the run, relative to the output directory
+  root: PathBuf,
+  summary: Summary,
+}
+
+/// Absolute folder of a run, the output directory when the run did not finish
+fn run_dir(outcome: &Result<Outcome>) -> Result<PathBuf> {
+  let cwd = env::current_dir().context("Failed to get current dir")?;
+  match outcome {
+    // without the `.` of the flat layouts
+    Ok(x) => Ok(cwd.join(&x.root).components().collect()),
+    Err(_) => Ok(cwd),
+  }
+}
+
+/// Run `hook` after a run
+async fn run_post_hook(hook: &PostHook, outcome: &Result<Outcome>) -> Result<Option<ExitStatus>> {
+  let dir = run_dir(outcome)?;
+  match outcome {
+    Ok(x) => hook.run(&dir, Some(&x.summary), !x.failed).await,
+    Err(_) => hook.run(&dir, None, false).await,
+  }
+}
+
+/// Post the result of a run to --notify-webhook, failures are only logged
+async fn notify_webhook(args: &Cli, wx: &WxClient, outcome: &Result<Outcome>) {
+  let Some(url) = &args.notify_webhook else {
+    return;
+  };
+  let result = match outcome {
+    Ok(x) => Ok((&x.summary, !x.failed)),
+    Err(err) => Err(format!("{err:#}")),
   };
+  let sent = match run_dir(outcome) {
+    Ok(dir) => notify::notify(wx, url, args.notify_on, &dir, result).await,
+    Err(err) => Err(err),
+  };
+  if let Err(err) = sent {
+    error!("Failed to notify the webhook: {err:?}");
+  }
+}
 
-  let department_job = {
-    let wx = wx.clone();
-    async move {
-      let resp = wx
-        .get_all_departments()
-        .await
-        .context("Failed to get departments list")?;
-      info!("Total {} departments to query", resp.departments.len());
-      let file = File::create("departments.json").context("Failed to create departments.json")?;
-      let mut buf_writer = BufWriter::new(file);
-      buf_writer
-        .write(&*serde_json::to_vec_pretty(&resp).context("Failed to serialize")?)
-        .context("Failed to write json")?;
-
-      fs::create_dir_all("departments")?;
-
-      let mut vec = Vec::new();
-      for x in resp.departments {
-        let recursive = args.recursive;
-        let wx = wx.clone();
-        let handle = spawn(async move {
-          let resp = match wx.get_department_members(x.id, recursive).await {
-            Ok(resp) => resp,
-            Err(err) => {
-              error!(
-                "Failed to get the members of department: {} - {}: {:?}",
-                x.id, x.name, err
-              );
-              return;
-            }
-          };
-
-          let path = PathBuf::from(format!(
-            "departments/{}",
-            format!("members-{}-{}.json", x.id, x.name).replace_special_char()
-          ));
-          let file = match File::create(&path) {
-            Ok(file) => file,
-            Err(err) => {
-              error!("Failed to create {}: {err:?}", path.to_string_lossy());
-              return;
-            }
-          };
-          let json = match serde_json::to_vec_pretty(&resp).context("Failed to serialize") {
-            Ok(json) => json,
-            Err(err) => {
-              error!("Failed to serialize json: {err:?}");
-              return;
-            }
-          };
-          let mut buf_writer = BufWriter::new(file);
-          match buf_writer.write(&*json) {
-            Ok(_) => info!(
-              "Successfully save department members to {}, total {}",
-              path.to_string_lossy(),
-              resp.members.len()
-            ),
-            Err(err) => error!(
-              "Failed to save department members to {}: {err:?}",
-              path.to_string_lossy()
-            ),
-          };
-        });
-        vec.push(handle);
-        sleep(Duration::from_millis(args.delay)).await;
+/// Where the files of a dump go, the current directory or the --s3 store
+#[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+async fn output_sink(args: &Cli) -> Result<Arc<dyn OutputSink>> {
+  #[cfg(feature = "s3")]
+  if let Some(location) = &args.s3 {
+    info!("Uploading to s3://{}/{}", location.bucket, location.prefix);
+    let keys = match (&args.s3_access_key, &args.s3_secret_key) {
+      (Some(access_key), Some(secret_key)) => Some(S3Keys {
+        access_key: access_key.clone(),
+        secret_key: secret_key.clone(),
+        session_token: args.s3_session_token.clone(),
+      }),
+      _ => None,
+    };
+    let sink = S3Sink::new(
+      location.clone(),
+      args.s3_endpoint.clone(),
+      args.s3_region.clone(),
+      keys,
+    )
+    .await?;
+    return Ok(Arc::new(sink));
+  }
+  Ok(Arc::new(FsSink::new(".")))
+}
+
+/// Dump everything once into the current directory
+async fn run(
+  args: &Cli,
+  wx: &mut WxClient,
+  exports: &Exports,
+  last_success: Option<i64>,
+) -> Result<Outcome> {
+  let started = Instant::now();
+  wx.reset_stats();
+  let started_at = Local::now();
+  let mut paths = Paths::new(args.layout, started_at);
+  if args.interval.is_some() {
+    paths = paths.with_run_folder(started_at);
+  }
+  if args.timestamp_files {
+    paths = paths.with_timestamp(started_at, &args.timestamp_format)?;
+  }
+  if !args.stdout {
+    fs::create_dir_all(paths.root()).context("Failed to create the output folder")?;
+  }
+
+  if args.save_raw {
+    let raw = paths.top("raw");
+    fs::create_dir_all(&raw).context("Failed to create folder ./raw")?;
+    wx.set_raw_dir(Some(raw));
+  }
+
+  let resume = match args.resume {
+    true => checkpoint::load(Path::new(CHECKPOINT))?,
+    false => None,
+  };
+  if let (true, None) = (args.resume, &resume) {
+    warn!("No checkpoint to resume from, fetching everything");
+  }
+
+  let opts = DumpOptions {
+    agents: !args.stdout,
+    recursive: args.recursive,
+    recursive_root_only: args.recursive_root_only,
+    annotate_departments: args.annotate_departments,
+    normalize_contacts: args.normalize_contacts,
+    limit: args.limit,
+    department_name_filter: args.department_name_filter.clone(),
+    department_ids: args.department_ids.clone(),
+    tag_name_filter: args.tag_name_filter.clone(),
+    tag_ids: args.tag_ids.clone(),
+    active_only: args.active_only,
+    minimal_members: args.minimal_members,
+    lenient: args.lenient,
+    resume: resume.clone().map(Arc::new),
+    fail_fast: args.fail_fast.then(FailFast::default),
+    item_timeout: args.item_timeout,
+    strict_completeness: args.strict_completeness,
+    delay: Duration::from_millis(args.delay),
+    department_delay: args.department_delay.map(Duration::from_millis),
+    tag_delay: args.tag_delay.map(Duration::from_millis),
+    department_concurrency: args.department_concurrency,
+    tag_concurrency: args.tag_concurrency,
+  };
+  let output = match args.stdout {
+    true => None,
+    false => Some(output_sink(args).await?),
+  };
+  let dump = if let Some(output) = &output {
+    let sink = QueuedSink::new(output.clone(), args.write_queue)?;
+    let mut writer = FileWriter::new(paths.clone(), sink);
+    writer.set_resolve_agent_scopes(args.resolve_agent_scopes);
+    writer.set_tags_union(args.tags_union);
+    writer.set_decode_names(args.decode_names);
+    writer.set_name_template(args.name_template.clone());
+    let writer = Arc::new(writer);
+    let checkpointer = (args.checkpoint_interval.is_some() || args.resume).then(|| {
+      let interval = args.checkpoint_interval.map(|i| i as usize);
+      let checkpoint = resume.unwrap_or_default();
+      Arc::new(Checkpointer::new(
+        writer.clone(),
+        PathBuf::from(CHECKPOINT),
+        interval,
+        checkpoint,
+      ))
+    });
+    let observer: Arc<dyn DumpObserver> = match &checkpointer {
+      Some(checkpointer) => checkpointer.clone(),
+      None => writer.clone(),
+    };
+    // read before the listings are overwritten by this run
+    let previous = match args.merge {
+      true => Some(Previous::read(Path::new("."), &paths)?),
+      false => None,
+    };
+    let mut dump = wx.dump_all_with(&opts, observer).await?;
+    if let Some(checkpointer) = checkpointer {
+      let incomplete = !dump.failed_jobs.is_empty() || dump.failed_items() > 0;
+      if let Err(err) = checkpointer.finish(incomplete) {
+        error!("Failed to save checkpoint: {err:?}");
       }
-      for x in vec {
-        x.await?;
+    }
+    if let Some(previous) = previous {
+      previous.merge_into(&mut dump, Path::new("."), &paths)?;
+      writer.rewrite_listings(&dump)?;
+    }
+    if let Err(err) = writer.finish(&dump) {
+      error!("Failed to save dump summary: {err:?}");
+    }
+    dump
+  } else {
+    wx.dump_all(&opts).await?
+  };
+  // failed items only fail the run under --strict-completeness
+  let mut failed =
+    !dump.failed_jobs.is_empty() || (args.strict_completeness && dump.failed_items() > 0);
+  if args.active_only {
+    info!(
+      "Filtered out {} disabled or resigned members",
+      dump.inactive_members.len()
+    );
+  }
+  let no_privilege = dump.simple_members_by_department.len() + dump.no_permission_departments.len();
+  if opts.agents
+    && !dump.failed_jobs.contains(&Job::Agents)
+    && !dump.agents.is_empty()
+    && no_privilege > 0
+    && args.contact_secret.is_none()
+  {
+    warn!(
+      "No permission to get the members of {no_privilege} departments while agents are fetched, \
+       the secret may be of an app, try --contact-secret with the secret of contact sync (通讯录同步)"
+    );
+  }
+  let mut fetch_failures = dump.failed_jobs.len() + dump.failed_items();
+
+  if args.external_contacts && !dump.failed_jobs.contains(&Job::Departments) {
+    let user_ids = dump
+      .members_by_department
+      .values()
+      .flatten()
+      .map(|x| x.user_id.clone())
+      .unique()
+      .collect();
+    let delay = Duration::from_millis(args.delay);
+    if let Err(err) = dump_external_contacts(wx, &paths, user_ids, delay).await {
+      error!("Fetch external contacts job failed: {err:?}");
+      failed = true;
+      fetch_failures += 1;
+    }
+  }
+
+  let mut avatar_bytes = None;
+  if args.avatars && !dump.failed_jobs.contains(&Job::Departments) {
+    let avatars = dump
+      .members_by_department
+      .values()
+      .flatten()
+      .filter(|x| !x.avatar.is_empty())
+      .unique_by(|x| &x.user_id)
+      .map(|x| (x.user_id.clone(), x.avatar.clone()))
+      .collect();
+    match avatars::download_avatars(wx, &paths, avatars, args.avatar_concurrency).await {
+      Ok(bytes) => avatar_bytes = Some(bytes),
+      Err(err) => {
+        error!("Download avatars job failed: {err:?}");
+        failed = true;
+        fetch_failures += 1;
       }
-      let result: Result<()> = Ok(());
-      result
     }
+  }
+
+  if args.stdout {
+    util::to_writer(std::io::stdout().lock(), &Merged::new(&dump))
+      .context("Failed to write to stdout")?;
+  }
+
+  if let Some(previous) = &exports.diff_against {
+    let diff = diff::diff_against(previous, &paths, &dump);
+    let path = paths.top("diff.json");
+    match diff.and_then(|diff| write_json(&path, &diff)) {
+      Ok(_) => info!("Successfully save diff to {}", path.to_string_lossy()),
+      Err(err) => error!("Failed to save diff to {}: {err:?}", path.to_string_lossy()),
+    }
+  }
+
+  #[cfg(feature = "xlsx")]
+  if let Some(path) = &exports.xlsx {
+    match xlsx::write_workbook(path, &dump) {
+      Ok(_) => info!("Successfully save workbook to {}", path.to_string_lossy()),
+      Err(err) => error!(
+        "Failed to save workbook to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    }
+  }
+
+  if let Some(path) = &exports.csv {
+    match csv_export::write_members_csv(path, &dump, args.csv_delimiter, args.csv_bom) {
+      Ok(_) => info!("Successfully save CSV to {}", path.to_string_lossy()),
+      Err(err) => error!("Failed to save CSV to {}: {err:?}", path.to_string_lossy()),
+    }
+  }
+
+  #[cfg(feature = "parquet")]
+  if let Some(dir) = &exports.parquet {
+    match parquet_export::write_parquet(dir, &dump) {
+      Ok(_) => info!(
+        "Successfully save Parquet files to {}",
+        dir.to_string_lossy()
+      ),
+      Err(err) => error!(
+        "Failed to save Parquet files to {}: {err:?}",
+        dir.to_string_lossy()
+      ),
+    }
+  }
+
+  if let Some(dir) = &exports.graph {
+    match graph::write_graph(dir, &dump) {
+      Ok(_) => info!("Successfully save graph to {}", dir.to_string_lossy()),
+      Err(err) => error!("Failed to save graph to {}: {err:?}", dir.to_string_lossy()),
+    }
+  }
+
+  if let Some(path) = &exports.combined {
+    match write_json(path, &Combined::new(&dump)) {
+      Ok(_) => info!(
+        "Successfully save combined dump to {}",
+        path.to_string_lossy()
+      ),
+      Err(err) => error!(
+        "Failed to save combined dump to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    }
+  }
+
+  if let (Some(path), Some(base_dn)) = (&exports.ldif, &args.ldif_base_dn) {
+    match ldif::write_ldif(path, &dump, base_dn) {
+      Ok(_) => info!("Successfully save LDIF to {}", path.to_string_lossy()),
+      Err(err) => error!("Failed to save LDIF to {}: {err:?}", path.to_string_lossy()),
+    }
+  }
+
+  if let Some(path) = &exports.vcard {
+    match vcard::write_vcards(path, &dump) {
+      Ok(_) => info!("Successfully save vCards to {}", path.to_string_lossy()),
+      Err(err) => error!(
+        "Failed to save vCards to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    }
+  }
+
+  let requests = wx.stats().report();
+  for (endpoint, stats) in &requests {
+    info!(
+      "{endpoint}: {} requests, {} succeeded, {} failed, {} retried, p50 {}ms, p95 {}ms",
+      stats.requests, stats.succeeded, stats.failed, stats.retried, stats.p50_ms, stats.p95_ms
+    );
+  }
+
+  if wx.stats().budget_exhausted() {
+    warn!("Request budget of --max-requests exhausted, the dump is incomplete");
+  }
+
+  let members = dump.members_by_department.values().flatten();
+  let summary = Summary {
+    token_expires_at: wx.token_expires_at().map(|i| i.to_rfc3339()),
+    incomplete: wx
+      .stats()
+      .budget_exhausted()
+      .then(|| "request budget exhausted".to_string()),
+    started_at: started_at.to_rfc3339(),
+    finished_at: Local::now().to_rfc3339(),
+    duration_seconds: started.elapsed().as_secs_f64(),
+    departments: dump.departments.len(),
+    members: members.unique_by(|x| &x.user_id).count(),
+    tags: dump.tags.len(),
+    failed_jobs: dump.failed_jobs.iter().map(Job::to_string).collect(),
+    fetch_failures,
+    requests,
+    avatar_bytes,
+    inactive_members_filtered: args.active_only.then_some(dump.inactive_members.len()),
+    shared_member_entries: args.recursive.then(|| dump.shared_member_entries()),
+    possibly_truncated_departments: dump.truncated_departments.clone(),
+    version: env!("CARGO_PKG_VERSION").to_string(),
+    command: summary::redact_args(env::args()),
+    hostname: summary::hostname(),
+    config: config(args),
   };
+  if let Some(output) = &output {
+    let path = paths.top("summary.json");
+    if let Err(err) = write_json_to(&**output, &path, &summary) {
+      error!("Failed to save summary: {err:?}");
+    }
+    let metrics = Metrics {
+      departments: summary.departments,
+      members: summary.members,
+      tags: summary.tags,
+      fetch_failures,
+      duration: started.elapsed(),
+      last_success: match failed {
+        true => last_success,
+        false => Some(Local::now().timestamp()),
+      },
+    };
+    if let Err(err) = metrics::write_metrics(Path::new(METRICS), &metrics) {
+      error!("Failed to save metrics: {err:?}");
+    }
+    // the last step, to cover every other file
+    let dir = paths.top(".");
+    let manifest = output
+      .manifest(&dir, started_at, &args.timestamp_format)
+      .and_then(|x| write_json_to(&**output, &dir.join(MANIFEST), &x));
+    if let Err(err) = manifest {
+      error!("Failed to save manifest: {err:?}");
+    }
+  }
+
+  Ok(Outcome {
+    failed,
+    root: paths.root(),
+    summary,
+  })
+}
+
+/// Print the members of [WxClient::stream_all_members] to stdout, one per line
+async fn stream_members(wx: &WxClient) -> Result<()> {
+  let mut stdout = std::io::stdout().lock();
+  let mut members = pin!(wx.stream_all_members());
+  let mut count = 0;
+  while let Some(member) = members.next().await {
+    util::to_writer(&mut stdout, &member?)
+      .and_then(|_| writeln!(stdout).map_err(serde_json::Error::io))
+      .context("Failed to write to stdout")?;
+    count += 1;
+  }
+  info!("Printed {count} members");
+  Ok(())
+}
+
+/// Print the members of `ids` to stdout like [stream_members], fetched at once
+async fn print_members(wx: &WxClient, ids: &[u32]) -> Result<()> {
+  let members = wx.get_members_for_departments(ids, false).await?;
+  let mut stdout = std::io::stdout().lock();
+  for member in &members {
+    util::to_writer(&mut stdout, member)
+      .and_then(|_| writeln!(stdout).map_err(serde_json::Error::io))
+      .context("Failed to write to stdout")?;
+  }
+  info!("Printed {} members", members.len());
+  Ok(())
+}
+
+/// Print the members of tag `id` to stdout, for the `tag` command
+async fn print_tag(wx: &WxClient, id: u32) -> Result<()> {
+  let mut resp = wx
+    .get_tag_members(id)
+    .await
+    .with_context(|| format!("Failed to get members of tag {id}"))?;
+  resp.sort();
+  let mut stdout = std::io::stdout().lock();
+  util::to_writer(&mut stdout, &resp)
+    .and_then(|_| writeln!(stdout).map_err(serde_json::Error::io))
+    .context("Failed to write to stdout")?;
+  info!(
+    "Printed {} members and {} departments of tag {id} - {}",
+    resp.members.len(),
+    resp.department_list.len(),
+    resp.tag_name
+  );
+  Ok(())
+}
 
-  let tag_job = {
+/// Options of `args` recorded in the summary
+fn config(args: &Cli) -> Config {
+  let name = |x: Option<PossibleValue>| x.map(|x| x.get_name().to_string()).unwrap_or_default();
+  Config {
+    delay_ms: args.delay,
+    department_delay_ms: args.department_delay,
+    tag_delay_ms: args.tag_delay,
+    department_concurrency: args.department_concurrency,
+    tag_concurrency: args.tag_concurrency,
+    retries: args.retries,
+    retry_jitter: name(args.retry_jitter.to_possible_value()),
+    qps: args.qps,
+    max_requests: args.max_requests,
+    layout: name(args.layout.to_possible_value()),
+    json_style: name(args.json_style.to_possible_value()),
+    recursive: args.recursive,
+    recursive_root_only: args.recursive_root_only,
+    active_only: args.active_only,
+    minimal_members: args.minimal_members,
+    lenient: args.lenient,
+    limit: args.limit,
+    department_name_filter: args.department_name_filter.as_ref().map(|x| x.to_string()),
+    tag_name_filter: args.tag_name_filter.as_ref().map(|x| x.to_string()),
+    department_ids: args.department_ids.clone(),
+    tag_ids: args.tag_ids.clone(),
+    offline: args
+      .offline
+      .as_ref()
+      .map(|x| x.to_string_lossy().into_owned()),
+  }
+}
+
+/// Save external contacts of each member to `external_contacts/<user_id>/<external_userid>.json`
+async fn dump_external_contacts(
+  wx: &WxClient,
+  paths: &Paths,
+  user_ids: Vec<String>,
+  delay: Duration,
+) -> Result<()> {
+  info!(
+    "Total {} members to query external contacts",
+    user_ids.len()
+  );
+  let mut vec = Vec::new();
+  for user_id in user_ids {
     let wx = wx.clone();
-    async move {
-      let resp = wx.get_tags().await.context("Failed to get tags list")?;
-      info!("Total {} tags to query", resp.tags.len());
-      let file = File::create("tags.json").context("Failed to create tags.json")?;
-      let mut buf_writer = BufWriter::new(file);
-      buf_writer
-        .write(&*serde_json::to_vec_pretty(&resp).context("Failed to serialize")?)
-        .context("Failed to write json")?;
-
-      fs::create_dir_all("tags")?;
-
-      let txt = Arc::new(RwLock::new(String::from("These tags has no member:\n")));
-
-      let mut vec = Vec::new();
-      for x in resp.tags {
-        let wx = wx.clone();
-        let txt = txt.clone();
-        let handle = spawn(async move {
-          let resp = match wx.get_tag_members(x.id).await {
-            Ok(resp) => resp,
-            Err(err) => {
-              error!(
-                "Failed to get the members of tag: {} - {}: {:?}",
-                x.id, x.name, err
-              );
-              return;
+    let paths = paths.clone();
+    let handle = spawn(async move {
+      let list = match wx.get_external_contact_list(&user_id).await {
+        Ok(list) => list,
+        Err(err) => {
+          match ApiError::find(&err) {
+            Some(ApiError { code, .. }) if *code == ApiError::NO_EXTERNAL_CONTACT_PERMISSION => {
+              warn!("No permission to get the external contacts of member {user_id}, skipped")
             }
-          };
-
-          if resp.members.is_empty() && resp.code == Some(0) {
-            let mut txt = txt.write().unwrap();
-            txt.push_str(&*format!("{} - {}\n", x.id, x.name));
-            return;
+            _ => error!("Failed to get the external contacts of member {user_id}: {err:?}"),
           }
+          return;
+        }
+      };
+      if list.external_user_ids.is_empty() {
+        return;
+      }
 
-          let path = PathBuf::from(format!(
-            "tags/{}",
-            format!("members-{}-{}.json", x.id, x.name).replace_special_char()
-          ));
-          let file = match File::create(&path) {
-            Ok(file) => file,
-            Err(err) => {
-              error!("Failed to create {}: {err:?}", path.to_string_lossy());
-              return;
-            }
-          };
-          let json = match serde_json::to_vec_pretty(&resp).context("Failed to serialize") {
-            Ok(json) => json,
-            Err(err) => {
-              error!("Failed to serialize json: {err:?}");
-              return;
-            }
-          };
-          let mut buf_writer = BufWriter::new(file);
-          match buf_writer.write(&*json) {
-            Ok(_) => info!(
-              "Successfully save tag members to {}, total {}",
-              path.to_string_lossy(),
-              resp.members.len()
-            ),
-            Err(err) => error!(
-              "Failed to save tag members to {}: {err:?}",
-              path.to_string_lossy()
-            ),
-          };
-        });
-        vec.push(handle);
-        sleep(Duration::from_millis(args.delay)).await;
+      let category = format!(
+        "external_contacts/{}",
+        user_id.clone().replace_special_char()
+      );
+      let dir = paths.dir(&category);
+      if let Err(err) = fs::create_dir_all(&dir) {
+        error!("Failed to create {}: {err:?}", dir.to_string_lossy());
+        return;
       }
-      for x in vec {
-        x.await?;
+      for external_user_id in list.external_user_ids {
+        let resp = match wx.get_external_contact_detail(&external_user_id).await {
+          Ok(resp) => resp,
+          Err(err) => {
+            error!("Failed to get the external contact {external_user_id} of {user_id}: {err:?}");
+            continue;
+          }
+        };
+        let path = paths.item(
+          &category,
+          &format!("{external_user_id}.json").replace_special_char(),
+        );
+        if let Err(err) = write_json(&path, &resp) {
+          error!(
+            "Failed to save external contact to {}: {err:?}",
+            path.to_string_lossy()
+          );
+        }
+        sleep(delay).await;
       }
+      info!("Successfully save external contacts of member {user_id}");
+    });
+    vec.push(handle);
+    sleep(delay).await;
+  }
+  for x in vec {
+    if let Err(err) = x.await {
+      error!("Fetch external contacts task panicked: {err:?}");
+    }
+  }
+  Ok(())
+}
 
-      let txt_file = File::create("tags/_empty.txt").context("Failed to create tags/_empty.txt")?;
-      let mut buf_writer = BufWriter::new(txt_file);
-      buf_writer.write_all(txt.read().unwrap().as_bytes())?;
+/// Remove the files and folders created by this tool in `output`, with top-level names stamped
+/// in `timestamp_format`, exit if there are unexpected ones unless `force`
+fn clear_output(output: &Path, force: bool, timestamp_format: &str) -> Result<()> {
+  let mut managed = Vec::new();
+  let mut unexpected = Vec::new();
+  for entry in fs::read_dir(output).context("Failed to read output directory")? {
+    let entry = entry?;
+    match is_managed(&entry.file_name().to_string_lossy(), timestamp_format) {
+      true => managed.push(entry.path()),
+      false => unexpected.push(entry.file_name().to_string_lossy().to_string()),
+    }
+  }
+  if !unexpected.is_empty() {
+    if !force {
+      error!(
+        "Output path '{}' contains files not created by this tool: {}, append --force to overwrite anyway.",
+        output.to_string_lossy(),
+        unexpected.join(", ")
+      );
+      ExitCode::Output.exit();
+    }
+    warn!("Keeping unexpected files: {}", unexpected.join(", "));
+  }
+  for path in managed {
+    if path.is_dir() {
+      fs::remove_dir_all(&path)
+    } else {
+      fs::remove_file(&path)
+    }
+    .with_context(|| format!("Failed to delete {}", path.to_string_lossy()))?;
+  }
+  Ok(())
+}
+
+/// Create the client and login with the provided credentials, exit on failure
+async fn connect(args: &Cli) -> (WxClient, Option<GetTokenResp>) {
+  let (mut wx, proxy) = new_client(args).await;
+
+  let api = Url::parse(API_BASE).expect("API_BASE is a valid url");
+  if let (None, false, Some(url)) = (&args.offline, args.no_preflight, proxy.proxy_for(&api)) {
+    if let Err(err) = wx.preflight().await {
+      let mut url = url.clone();
+      let _ = url.set_password(None);
+      error!("Proxy unreachable: {url}: {err:?}");
+      ExitCode::Generic.exit();
+    }
+  }
+
+  match login(args, &mut wx).await {
+    Ok(login) => (wx, login),
+    Err(err) => {
+      error!("{err:?}");
+      ExitCode::of(&err).exit();
+    }
+  }
+}
+
+/// Create the client with the proxy and request options, exit on failure
+async fn new_client(args: &Cli) -> (WxClient, ProxyConfig) {
+  let proxy = ProxyConfig {
+    all: args.proxy.clone(),
+    http: args.proxy_http.clone(),
+    https: args.proxy_https.clone(),
+    hosts: args.proxy_host.clone(),
+    auth: args
+      .proxy_user
+      .clone()
+      .zip(args.proxy_password.as_ref().map(|i| i.expose().to_string())),
+  };
+  let identity = match (&args.client_cert, &args.client_key, &args.identity) {
+    (Some(cert), Some(key), _) => Some(ClientIdentity::Pem {
+      cert: cert.clone(),
+      key: key.clone(),
+    }),
+    (_, _, Some(path)) => Some(ClientIdentity::Pkcs12 {
+      path: path.clone(),
+      password: args
+        .identity_password
+        .as_ref()
+        .map(|i| i.expose().to_string())
+        .unwrap_or_default(),
+    }),
+    _ => None,
+  };
+  let wx = WxClient::new(
+    &proxy,
+    args.user_agent.clone(),
+    args.header.iter().cloned().collect(),
+    args.pool_size,
+    identity.as_ref(),
+  )
+  .await;
 
-      let result: Result<()> = Ok(());
-      result
+  let mut wx = match wx {
+    Ok(wx) => wx,
+    Err(err) => {
+      error!("Failed to create WeChat client: {:?}", err);
+      ExitCode::Generic.exit();
     }
   };
 
-  let agent_job = spawn(agent_job);
-  let department_job = spawn(department_job);
-  let tag_job = spawn(tag_job);
+  wx.set_retries(args.retries);
+  wx.set_retry_jitter(args.retry_jitter);
+  wx.set_rate_limit_cooldown(Duration::from_secs(args.rate_limit_cooldown));
+  wx.set_max_requests(args.max_requests);
+  wx.set_max_response_bytes(args.max_response_bytes);
+
+  if let Some(path) = &args.user_agent_file {
+    let user_agents = match read_user_agents(path) {
+      Ok(user_agents) => user_agents,
+      Err(err) => {
+        error!("Failed to read user agents: {err:?}");
+        ExitCode::Generic.exit();
+      }
+    };
+    debug!("Rotating {} user agents", user_agents.len());
+    wx.set_user_agents(user_agents);
+  }
+  (wx, proxy)
+}
+
+/// Login with the provided credentials, the response is [None] for a token provided directly
+async fn login(args: &Cli, wx: &mut WxClient) -> Result<Option<GetTokenResp>> {
+  let mut login = None;
+  if args.offline.is_some() {
+    // responses are read from disk, the token is never sent
+    wx.set_token(String::new());
+  } else if let (Some(corp_id), Some(corp_secret)) = (&args.corp_id, &args.corp_secret) {
+    let resp = wx
+      .login(corp_id, corp_secret.expose())
+      .await
+      .context("Failed to login with provided id and secret")?;
+    if let Some(expires_at) = wx.token_expires_at() {
+      info!("Token expires at {}", expires_at.to_rfc3339());
+    }
+    login = Some(resp);
+    info!("Get token successfully");
+  } else if let Some(command) = &args.token_command {
+    wx.set_token_command(Some(command.clone()));
+    wx.refresh_token(None)
+      .await
+      .context("Failed to get token from the token command")?;
+    info!("Get token from the token command successfully");
+  } else if let Some(corp_token) = &args.corp_token {
+    wx.set_token(corp_token.expose().to_string());
+  } else {
+    bail!("For login, you must provide: (ID and Secret) or Token.");
+  }
+  if let (None, Some(corp_id), Some(secret)) = (&args.offline, &args.corp_id, &args.contact_secret)
+  {
+    wx.login_contact(corp_id, secret.expose())
+      .await
+      .context("Failed to login with the contact secret")?;
+    info!("Get contact token successfully");
+  }
+  Ok(login)
+}
+
+/// Check the proxy, DNS, login and every endpoint in order, print the results as a table,
+/// and return whether the essential ones passed
+async fn diagnose(args: &Cli) -> bool {
+  let (mut wx, proxy) = new_client(args).await;
+  let api = Url::parse(API_BASE).expect("API_BASE is a valid url");
+  let proxy = proxy.proxy_for(&api);
+
+  let mut diagnosis = Diagnosis::default();
+  diagnosis.proxy(&wx, proxy).await;
+  diagnosis.dns(proxy.is_some()).await;
+  let login = login(args, &mut wx).await;
+  let logged_in = login.is_ok();
+  diagnosis.token(login);
+  match logged_in {
+    true => diagnosis.endpoints(&wx).await,
+    false => diagnosis.skip_endpoints(),
+  }
+  println!("{diagnosis}");
+  diagnosis.passed()
+}
 
-  if let Err(err) = agent_job.await? {
-    error!("Fetch agent list job failed: {err:?}");
+/// Get the tokens again before another run of --interval, since they expire in hours
+async fn relogin(args: &Cli, wx: &WxClient) -> Result<()> {
+  if args.offline.is_some() {
+    return Ok(());
+  }
+  if let (Some(corp_id), Some(corp_secret)) = (&args.corp_id, &args.corp_secret) {
+    wx.login(corp_id, corp_secret.expose())
+      .await
+      .context("Failed to login with provided id and secret")?;
+  } else if args.token_command.is_some() {
+    wx.refresh_token(None)
+      .await
+      .context("Failed to get token from the token command")?;
   }
+  if let (Some(corp_id), Some(secret)) = (&args.corp_id, &args.contact_secret) {
+    wx.login_contact(corp_id, secret.expose())
+      .await
+      .context("Failed to login with the contact secret")?;
+  }
+  Ok(())
+}
 
-  if let Err(err) = department_job.await? {
-    error!("Fetch department members job failed: {err:?}");
+/// Read non-empty lines of `path` as user agents
+fn read_user_agents(path: &Path) -> Result<Vec<String>> {
+  let text = fs::read_to_string(path)
+    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+  let user_agents: Vec<String> = text
+    .lines()
+    .map(str::trim)
+    .filter(|i| !i.is_empty())
+    .map(String::from)
+    .collect();
+  if user_agents.is_empty() {
+    bail!("No user agent in {}", path.to_string_lossy());
   }
+  Ok(user_agents)
+}
 
-  if let Err(err) = tag_job.await? {
-    error!("Fetch tag members job failed: {err:?}");
+/// Check the credentials work by fetching agents and departments, without writing anything
+async fn check(wx: &WxClient, login: Option<GetTokenResp>) -> Result<()> {
+  match login.and_then(|resp| resp.expires_in) {
+    Some(expires_in) => info!("Token expires in {expires_in}s"),
+    None => info!("Token expiry is unknown, since it is provided directly"),
   }
+  let agents = wx
+    .get_agent_list()
+    .await
+    .context("Failed to get agent list")?;
+  info!("Visible agents: {}", agents.agent_list.len());
+  let departments = wx
+    .get_all_departments()
+    .await
+    .context("Failed to get departments list")?;
+  info!("Visible departments: {}", departments.departments.len());
   Ok(())
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+  Text,
+  /// One JSON object per line, with the spans of the request and the department or tag
+  Json,
+}
+
+fn init_logging(args: &Cli) {
+  let level = match args.silent {
+    true => LevelFilter::Error,
+    false => args.verbose.log_level_filter(),
+  };
+  // still allow debugging a silent run explicitly
+  let filters = env::var("RUST_LOG").ok().filter(|_| args.silent);
+  match args.log_format {
+    LogFormat::Text => {
+      let mut logger = pretty_env_logger::env_logger::Builder::new();
+      logger.filter_level(level);
+      if let Some(filters) = &filters {
+        logger.parse_filters(filters);
+      }
+      logger.init();
+    }
+    LogFormat::Json => {
+      let mut directives = level.as_str().to_lowercase();
+      if let Some(filters) = &filters {
+        directives = format!("{directives},{filters}");
+      }
+      // records of the log crate are forwarded in the current span too
+      tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_env_filter(EnvFilter::new(directives))
+        .with_writer(std::io::stderr)
+        .init();
+    }
+  }
+}
+
 #[cfg(test)]
 fn init_logger(level: &str) {
   if env::var("RUST_LOG").is_err() {
@@ -399,4 +1703,7 @@ fn init_logger(level: &str) {
   pretty_env_logger::init();
 }
 
+/// Metrics file in the output directory, kept at the top for the textfile collector in any layout
+const METRICS: &str = "metrics.prom";
+
 type DefaultLevel = clap_verbosity_flag::InfoLevel;
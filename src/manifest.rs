@@ -0,0 +1,175 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::layout::is_managed;
+
+pub const MANIFEST: &str = "manifest.json";
+
+/// Every file of a dump with its hash, to verify the dump later
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+  pub version: &'static str,
+  pub timestamp: String,
+  pub files: Vec<ManifestFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestFile {
+  /// Relative to the folder of the manifest, separated by `/`
+  pub path: String,
+  pub size: u64,
+  pub sha256: String,
+}
+
+/// The files of a manifest read back, the rest is only informative
+#[derive(Deserialize, Debug)]
+struct ManifestFiles {
+  files: Vec<ManifestFile>,
+}
+
+impl Manifest {
+  /// A manifest of `files` sorted by path
+  pub fn new(mut files: Vec<ManifestFile>, time: DateTime<Local>) -> Manifest {
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Manifest {
+      version: env!("CARGO_PKG_VERSION"),
+      timestamp: time.to_rfc3339(),
+      files,
+    }
+  }
+
+  /// List the files created by this tool in `dir` except the manifest itself, sorted by path,
+  /// see [managed_files]
+  pub fn collect(dir: &Path, time: DateTime<Local>, timestamp_format: &str) -> Result<Manifest> {
+    let files = managed_files(dir, timestamp_format)?
+      .into_iter()
+      .map(|(path, relative)| {
+        let (size, sha256) = hash_file(&path)?;
+        Ok(ManifestFile {
+          path: relative,
+          size,
+          sha256,
+        })
+      })
+      .collect::<Result<_>>()?;
+    Ok(Manifest::new(files, time))
+  }
+
+  /// Check the files listed in `dir/manifest.json` against their sizes and hashes, returning the
+  /// mismatching paths with the reasons, or [None] without a manifest
+  pub fn verify(dir: &Path) -> Result<Option<Vec<(String, String)>>> {
+    let path = dir.join(MANIFEST);
+    if !path.exists() {
+      return Ok(None);
+    }
+    let file =
+      File::open(&path).with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+    let manifest: ManifestFiles = serde_json::from_reader(BufReader::new(file))
+      .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+    let mut mismatches = Vec::new();
+    for file in manifest.files {
+      let path = file
+        .path
+        .split('/')
+        .fold(dir.to_path_buf(), |x, y| x.join(y));
+      let reason = match hash_file(&path) {
+        Err(_) if !path.exists() => "missing, but listed in the manifest".to_string(),
+        Err(err) => format!("{err:#}"),
+        Ok((size, _)) if size != file.size => {
+          format!("size {size} differs from {} in the manifest", file.size)
+        }
+        Ok((_, sha256)) if sha256 != file.sha256 => {
+          "SHA-256 differs from the one in the manifest".to_string()
+        }
+        Ok(_) => continue,
+      };
+      mismatches.push((file.path, reason));
+    }
+    Ok(Some(mismatches))
+  }
+}
+
+/// The files created by this tool in `dir` except the manifest, with their paths relative to
+/// `dir` separated by `/`, sorted by them. Stamped names are told by `timestamp_format`
+pub fn managed_files(dir: &Path, timestamp_format: &str) -> Result<Vec<(PathBuf, String)>> {
+  let mut files = Vec::new();
+  for entry in read_dir(dir)? {
+    let name = entry.file_name().to_string_lossy().to_string();
+    if is_managed(&name, timestamp_format) && name != MANIFEST {
+      collect_files(&entry.path(), &name, &mut files)?;
+    }
+  }
+  files.sort_by(|a, b| a.1.cmp(&b.1));
+  Ok(files)
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+  fs::read_dir(dir)
+    .and_then(|entries| entries.collect())
+    .with_context(|| format!("Failed to read {}", dir.to_string_lossy()))
+}
+
+fn collect_files(path: &Path, relative: &str, files: &mut Vec<(PathBuf, String)>) -> Result<()> {
+  if path.is_dir() {
+    for entry in read_dir(path)? {
+      let name = entry.file_name().to_string_lossy().to_string();
+      collect_files(&entry.path(), &format!("{relative}/{name}"), files)?;
+    }
+  } else {
+    files.push((path.to_path_buf(), relative.to_string()));
+  }
+  Ok(())
+}
+
+/// Size and hex SHA-256 of a file
+fn hash_file(path: &Path) -> Result<(u64, String)> {
+  let mut file =
+    File::open(path).with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+  let mut hasher = Sha256::new();
+  let size = io::copy(&mut file, &mut hasher)
+    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+  Ok((size, hex::encode(hasher.finalize())))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use chrono::Local;
+
+  use crate::layout::DEFAULT_TIMESTAMP_FORMAT;
+  use crate::manifest::{Manifest, ManifestFile};
+  use crate::util::TestDir;
+
+  #[test]
+  fn manifest_test() {
+    let dir = TestDir::new("manifest");
+    fs::create_dir(dir.join("tags")).unwrap();
+    fs::write(dir.join("tags.json"), "abc").unwrap();
+    fs::write(dir.join("tags/_empty.txt"), "").unwrap();
+    fs::write(dir.join("notes.txt"), "not ours").unwrap();
+
+    let manifest = Manifest::collect(&dir, Local::now(), DEFAULT_TIMESTAMP_FORMAT).unwrap();
+    assert_eq!(
+      manifest.files,
+      vec![
+        ManifestFile {
+          path: "tags.json".to_string(),
+          size: 3,
+          sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        },
+        ManifestFile {
+          path: "tags/_empty.txt".to_string(),
+          size: 0,
+          sha256: "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+        },
+      ]
+    );
+  }
+}
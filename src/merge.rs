@@ -0,0 +1,183 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::api::data::{Department, DepartmentMember, SimpleMember, Tag, TagMember};
+use crate::api::dump::{Dump, Job};
+use crate::diff::read_previous;
+use crate::layout::Paths;
+
+/// Departments and tags saved in the output directory by a previous run, read before they are
+/// overwritten, for `--merge`
+#[derive(Debug, Default)]
+pub struct Previous {
+  departments: Vec<Department>,
+  tags: Vec<Tag>,
+}
+
+impl Previous {
+  /// Read `departments.json` and `tags.json` in `root`, missing ones are empty
+  pub fn read(root: &Path, paths: &Paths) -> Result<Previous> {
+    let departments: Option<Value> = read_json(&root.join(paths.top("departments.json")))?;
+    let tags: Option<Value> = read_json(&root.join(paths.top("tags.json")))?;
+    Ok(Previous {
+      departments: listing(departments, "department")?,
+      tags: listing(tags, "taglist")?,
+    })
+  }
+
+  /// Add the departments and tags not fetched in this run to `dump`, with the members saved for
+  /// them before, so the files summarizing the dump cover both runs
+  pub fn merge_into(self, dump: &mut Dump, root: &Path, paths: &Paths) -> Result<()> {
+    if !dump.failed_jobs.contains(&Job::Departments) {
+      let fetched: HashSet<u32> = dump.departments.iter().map(|x| x.id).collect();
+      let mut saved = read_previous(root, paths, "departments")?;
+      let kept: Vec<Department> = self
+        .departments
+        .into_iter()
+        .filter(|x| !fetched.contains(&x.id))
+        .collect();
+      info!("Kept {} departments of the previous run", kept.len());
+      for x in &kept {
+        let Some((_, members)) = saved.remove(&x.id) else {
+          // not saved before, like the ones without permission
+          dump.no_permission_departments.push(x.id);
+          continue;
+        };
+        if let Some(members) = parse::<DepartmentMember>(&members) {
+          let members = members.into_iter().map(Arc::new).collect();
+          dump.members_by_department.insert(x.id, members);
+        } else if let Some(members) = parse::<SimpleMember>(&members) {
+          dump.simple_members_by_department.insert(x.id, members);
+        } else {
+          warn!(
+            "Failed to read the saved members of department: {} - {}, skipped",
+            x.id, x.name
+          );
+        }
+      }
+      dump.departments.extend(kept);
+      dump.departments.sort_by_key(|x| x.id);
+    }
+    if !dump.failed_jobs.contains(&Job::Tags) {
+      let fetched: HashSet<u32> = dump.tags.iter().map(|x| x.id).collect();
+      let mut saved = read_previous(root, paths, "tags")?;
+      let kept: Vec<Tag> = self
+        .tags
+        .into_iter()
+        .filter(|x| !fetched.contains(&x.id))
+        .collect();
+      info!("Kept {} tags of the previous run", kept.len());
+      for x in &kept {
+        // tags without members are not saved
+        let members = match saved.remove(&x.id) {
+          Some((_, members)) => parse::<TagMember>(&members),
+          None => Some(Vec::new()),
+        };
+        match members {
+          Some(members) => {
+            dump.tag_members.insert(x.id, members);
+          }
+          None => warn!(
+            "Failed to read the saved members of tag: {} - {}, skipped",
+            x.id, x.name
+          ),
+        }
+      }
+      dump.tags.extend(kept);
+      dump.tags.sort_by_key(|x| x.id);
+    }
+    Ok(())
+  }
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+  if !path.exists() {
+    return Ok(None);
+  }
+  let file =
+    File::open(path).with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+  let value = serde_json::from_reader(BufReader::new(file))
+    .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+  Ok(Some(value))
+}
+
+/// Items in the `key` array of a saved listing
+fn listing<T: DeserializeOwned>(value: Option<Value>, key: &str) -> Result<Vec<T>> {
+  match value {
+    Some(mut value) => serde_json::from_value(value[key].take())
+      .with_context(|| format!("Failed to read the `{key}` of a previous listing")),
+    None => Ok(Vec::new()),
+  }
+}
+
+/// Members saved before, [None] if any of them is not a `T`
+fn parse<T: DeserializeOwned>(members: &[Value]) -> Option<Vec<T>> {
+  members.iter().map(|x| T::deserialize(x).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use chrono::Local;
+  use serde_json::json;
+
+  use crate::api::dump::Dump;
+  use crate::layout::{Layout, Paths};
+  use crate::merge::Previous;
+  use crate::util::TestDir;
+
+  #[test]
+  fn merge_into_test() {
+    let dir = TestDir::new("merge");
+    fs::create_dir_all(dir.join("tags")).unwrap();
+    fs::create_dir_all(dir.join("departments")).unwrap();
+    let write = |name: &str, value: serde_json::Value| {
+      fs::write(dir.join(name), value.to_string()).unwrap();
+    };
+    write(
+      "departments.json",
+      json!({"department": [
+        {"id": 1, "name": "A", "parentid": 0, "order": 0},
+        {"id": 2, "name": "B", "parentid": 1, "order": 0},
+      ]}),
+    );
+    write(
+      "departments/members-2-B.json",
+      json!({"userlist": [{"userid": "b", "name": "B", "department": [2]}]}),
+    );
+    write(
+      "tags.json",
+      json!({"taglist": [{"tagid": 1, "tagname": "T"}, {"tagid": 2, "tagname": "E"}]}),
+    );
+    write(
+      "tags/members-1-T.json",
+      json!({"userlist": [{"userid": "b", "name": "B"}]}),
+    );
+
+    let paths = Paths::new(Layout::Nested, Local::now());
+    let previous = Previous::read(&dir, &paths).unwrap();
+    let mut dump = Dump {
+      departments: serde_json::from_value(json!([{"id": 1, "name": "A", "order": 0}])).unwrap(),
+      members_by_department: [(1, Vec::new())].into(),
+      ..Default::default()
+    };
+    previous.merge_into(&mut dump, &dir, &paths).unwrap();
+
+    let ids: Vec<u32> = dump.departments.iter().map(|x| x.id).collect();
+    assert_eq!(ids, [1, 2]);
+    assert!(dump.members_by_department[&1].is_empty());
+    assert_eq!(dump.simple_members_by_department[&2][0].user_id, "b");
+    assert_eq!(dump.tags.len(), 2);
+    assert_eq!(dump.tag_members[&1][0].id, "b");
+    assert!(dump.tag_members[&2].is_empty());
+  }
+}
@@ -0,0 +1,50 @@
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::api::data::{Department, DepartmentMember, Tag, TagMember};
+use crate::api::dump::Dump;
+
+/// All departments, members and tags merged into one document
+#[derive(Serialize, Debug)]
+pub struct Merged<'a> {
+  pub departments: &'a [Department],
+  /// Members of all departments, deduplicated by user id
+  pub members: Vec<&'a DepartmentMember>,
+  pub tags: Vec<MergedTag<'a>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MergedTag<'a> {
+  #[serde(flatten)]
+  pub tag: &'a Tag,
+  #[serde(rename = "userlist")]
+  pub members: &'a [TagMember],
+  #[serde(rename = "partylist")]
+  pub departments: &'a [u32],
+}
+
+impl<'a> Merged<'a> {
+  pub fn new(dump: &'a Dump) -> Merged<'a> {
+    let members = dump
+      .members_by_department
+      .values()
+      .flatten()
+      .unique_by(|x| &x.user_id)
+      .map(|x| &**x)
+      .collect();
+    let tags = dump
+      .tags
+      .iter()
+      .map(|tag| MergedTag {
+        tag,
+        members: dump.tag_members.get(&tag.id).map_or(&[][..], |i| &**i),
+        departments: dump.tag_departments.get(&tag.id).map_or(&[][..], |i| &**i),
+      })
+      .collect();
+    Merged {
+      departments: &dump.departments,
+      members,
+      tags,
+    }
+  }
+}
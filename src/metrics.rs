@@ -0,0 +1,116 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+const LAST_SUCCESS: &str = "qywx_last_success_timestamp";
+
+/// Health of a run, written in the node_exporter textfile collector format
+#[derive(Debug, Default)]
+pub struct Metrics {
+  pub departments: usize,
+  pub members: usize,
+  pub tags: usize,
+  /// Failed jobs and items
+  pub fetch_failures: usize,
+  pub duration: Duration,
+  /// Unix timestamp of the last successful run, which is kept from the previous
+  /// metrics file if this run failed
+  pub last_success: Option<i64>,
+}
+
+impl Metrics {
+  pub fn to_prometheus(&self) -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+      // writing into a String never fails
+      let _ = write!(
+        text,
+        "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+      );
+    };
+    metric(
+      "qywx_departments_total",
+      "gauge",
+      "Departments visible in the last run",
+      self.departments.to_string(),
+    );
+    metric(
+      "qywx_members_total",
+      "gauge",
+      "Distinct members fetched in the last run",
+      self.members.to_string(),
+    );
+    metric(
+      "qywx_tags_total",
+      "gauge",
+      "Tags visible in the last run",
+      self.tags.to_string(),
+    );
+    metric(
+      "qywx_fetch_failures_total",
+      "gauge",
+      "Jobs and items failed to fetch in the last run",
+      self.fetch_failures.to_string(),
+    );
+    metric(
+      "qywx_run_duration_seconds",
+      "gauge",
+      "Duration of the last run",
+      format!("{:.3}", self.duration.as_secs_f64()),
+    );
+    if let Some(last_success) = self.last_success {
+      metric(
+        LAST_SUCCESS,
+        "gauge",
+        "Unix timestamp of the last successful run",
+        last_success.to_string(),
+      );
+    }
+    text
+  }
+}
+
+/// Read the last success timestamp from a previous metrics file, if any
+pub fn read_last_success(path: &Path) -> Option<i64> {
+  let text = fs::read_to_string(path).ok()?;
+  text
+    .lines()
+    .find_map(|line| line.strip_prefix(LAST_SUCCESS)?.trim().parse().ok())
+}
+
+pub fn write_metrics(path: &Path, metrics: &Metrics) -> Result<()> {
+  fs::write(path, metrics.to_prometheus())
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use crate::metrics::Metrics;
+
+  #[test]
+  fn to_prometheus_test() {
+    let metrics = Metrics {
+      departments: 3,
+      members: 10,
+      tags: 2,
+      fetch_failures: 1,
+      duration: Duration::from_millis(1500),
+      last_success: Some(1660000000),
+    };
+    let text = metrics.to_prometheus();
+    assert!(text.contains("# TYPE qywx_departments_total gauge\nqywx_departments_total 3\n"));
+    assert!(text.contains("\nqywx_members_total 10\n"));
+    assert!(text.contains("\nqywx_run_duration_seconds 1.500\n"));
+    assert!(text.ends_with("\nqywx_last_success_timestamp 1660000000\n"));
+
+    let metrics = Metrics::default();
+    assert!(!metrics
+      .to_prometheus()
+      .contains("qywx_last_success_timestamp"));
+  }
+}
@@ -0,0 +1,130 @@
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use log::info;
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::api::WxClient;
+use crate::summary::Summary;
+
+/// Which runs are notified by `--notify-webhook`
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NotifyOn {
+  Success,
+  /// Runs with any failure, or aborted ones
+  Failure,
+  #[default]
+  Always,
+}
+
+impl NotifyOn {
+  fn matches(self, success: bool) -> bool {
+    match self {
+      NotifyOn::Success => success,
+      NotifyOn::Failure => !success,
+      NotifyOn::Always => true,
+    }
+  }
+}
+
+/// JSON posted to the webhook, with the fields of `summary.json` if the run finished
+#[derive(Serialize, Debug)]
+struct Notification<'a> {
+  /// One line for chat webhooks like Slack, which only show `text`
+  text: String,
+  status: &'static str,
+  output_dir: String,
+  /// Why the run did not finish
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+  #[serde(flatten)]
+  summary: Option<&'a Summary>,
+}
+
+impl<'a> Notification<'a> {
+  fn new(dir: &Path, result: std::result::Result<(&'a Summary, bool), String>) -> Self {
+    let output_dir = dir.to_string_lossy().into_owned();
+    match result {
+      Ok((summary, success)) => {
+        let status = match success {
+          true => "success",
+          false => "failure",
+        };
+        let text = format!(
+          "qywx-dumper {status}: {} departments, {} members, {} tags, {} failures in {:.1}s, \
+           saved to {output_dir}",
+          summary.departments,
+          summary.members,
+          summary.tags,
+          summary.fetch_failures,
+          summary.duration_seconds
+        );
+        Notification {
+          text,
+          status,
+          output_dir,
+          error: None,
+          summary: Some(summary),
+        }
+      }
+      Err(err) => Notification {
+        text: format!("qywx-dumper failure: {err}"),
+        status: "failure",
+        output_dir,
+        error: Some(err),
+        summary: None,
+      },
+    }
+  }
+}
+
+/// Post a [Notification] of a run into `dir` to `url` if `on` matches, `result` is the summary
+/// and whether the run succeeded, or the error it stopped with
+pub async fn notify(
+  wx: &WxClient,
+  url: &Url,
+  on: NotifyOn,
+  dir: &Path,
+  result: std::result::Result<(&Summary, bool), String>,
+) -> Result<()> {
+  if !on.matches(matches!(result, Ok((_, true)))) {
+    return Ok(());
+  }
+  wx.post_json(url, &Notification::new(dir, result)).await?;
+  info!("Sent notification to the webhook");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::Path;
+
+  use crate::notify::{Notification, NotifyOn};
+  use crate::summary::Summary;
+
+  #[test]
+  fn notification_test() {
+    let summary = Summary {
+      members: 3,
+      failed_jobs: vec!["tags".to_string()],
+      ..Default::default()
+    };
+    let json = serde_json::to_value(Notification::new(Path::new("/out"), Ok((&summary, false))));
+    let json = json.unwrap();
+    assert_eq!(json["status"], "failure");
+    assert_eq!(json["output_dir"], "/out");
+    assert_eq!(json["members"], 3);
+    assert_eq!(json["failed_jobs"][0], "tags");
+    assert!(json.get("error").is_none());
+
+    let json = serde_json::to_value(Notification::new(Path::new("/out"), Err("x".to_string())));
+    let json = json.unwrap();
+    assert_eq!(json["error"], "x");
+    assert!(json.get("members").is_none());
+
+    assert!(NotifyOn::Failure.matches(false));
+    assert!(!NotifyOn::Success.matches(false));
+  }
+}
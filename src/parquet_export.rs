@@ -0,0 +1,190 @@
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arrow_array::builder::{ListBuilder, StringBuilder};
+use arrow_array::types::UInt32Type;
+use arrow_array::{ArrayRef, ListArray, RecordBatch, StringArray, UInt32Array};
+use parquet::arrow::ArrowWriter;
+
+use crate::api::dump::Dump;
+use crate::csv_export::unique_members;
+
+/// Write departments, members, tags and tag members to a Parquet file each in `dir`,
+/// with the same columns as the sheets of the workbook, and arrays as list columns
+pub fn write_parquet(dir: &Path, dump: &Dump) -> Result<()> {
+  fs::create_dir_all(dir)
+    .with_context(|| format!("Failed to create folder {}", dir.to_string_lossy()))?;
+  write_batch(&dir.join("departments.parquet"), departments(dump)?)?;
+  write_batch(&dir.join("members.parquet"), members(dump)?)?;
+  write_batch(&dir.join("tags.parquet"), tags(dump)?)?;
+  write_batch(&dir.join("tag_members.parquet"), tag_members(dump)?)
+}
+
+fn write_batch(path: &Path, batch: RecordBatch) -> Result<()> {
+  let file =
+    File::create(path).with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+  let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+  writer
+    .write(&batch)
+    .and_then(|_| writer.close().map(|_| ()))
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+fn departments(dump: &Dump) -> Result<RecordBatch> {
+  let x = &dump.departments;
+  let mut leaders = ListBuilder::new(StringBuilder::new());
+  for department in x {
+    match &department.department_leader {
+      Some(leader) => leaders.append_value(leader.iter().map(Some)),
+      None => leaders.append_null(),
+    }
+  }
+  let batch = RecordBatch::try_from_iter_with_nullable([
+    ("id", u32s(x.iter().map(|x| x.id)), false),
+    ("name", strings(x.iter().map(|x| &*x.name)), false),
+    ("parent", opt_u32s(x.iter().map(|x| x.parent_id)), true),
+    ("order", u32s(x.iter().map(|x| x.order)), false),
+    (
+      "name_en",
+      opt_strings(x.iter().map(|x| x.name_en.as_deref())),
+      true,
+    ),
+    ("leader", Arc::new(leaders.finish()) as ArrayRef, true),
+  ])?;
+  Ok(batch)
+}
+
+fn members(dump: &Dump) -> Result<RecordBatch> {
+  let rows = unique_members(dump);
+  let x = || rows.iter().map(|(x, _)| *x);
+  let departments = rows.iter().map(|(_, department)| {
+    let department = department.iter().map(|&i| Some(i));
+    Some(department.collect::<Vec<_>>())
+  });
+  let departments = ListArray::from_iter_primitive::<UInt32Type, _, _>(departments);
+  let batch = RecordBatch::try_from_iter_with_nullable([
+    ("user_id", strings(x().map(|x| &*x.user_id)), false),
+    ("name", strings(x().map(|x| &*x.name)), false),
+    ("alias", strings(x().map(|x| &*x.alias)), false),
+    (
+      "english_name",
+      strings(x().map(|x| &*x.english_name)),
+      false,
+    ),
+    ("department", Arc::new(departments) as ArrayRef, false),
+    (
+      "main_department",
+      opt_u32s(x().map(|x| x.main_department)),
+      true,
+    ),
+    ("position", strings(x().map(|x| &*x.position)), false),
+    ("gender", strings(x().map(|x| &*x.gender)), false),
+    ("mobile", strings(x().map(|x| &*x.mobile)), false),
+    ("telephone", strings(x().map(|x| &*x.telephone)), false),
+    ("email", strings(x().map(|x| &*x.email)), false),
+    (
+      "biz_mail",
+      opt_strings(x().map(|x| x.biz_mail.as_deref())),
+      true,
+    ),
+    ("is_leader", u32s(x().map(|x| x.is_leader)), false),
+    ("status", u32s(x().map(|x| x.status)), false),
+    ("enable", u32s(x().map(|x| x.enable)), false),
+    ("avatar", strings(x().map(|x| &*x.avatar)), false),
+    ("qr_code", strings(x().map(|x| &*x.qr_code)), false),
+  ])?;
+  Ok(batch)
+}
+
+fn tags(dump: &Dump) -> Result<RecordBatch> {
+  let x = &dump.tags;
+  let batch = RecordBatch::try_from_iter_with_nullable([
+    ("id", u32s(x.iter().map(|x| x.id)), false),
+    ("name", strings(x.iter().map(|x| &*x.name)), false),
+  ])?;
+  Ok(batch)
+}
+
+fn tag_members(dump: &Dump) -> Result<RecordBatch> {
+  let rows: Vec<_> = dump
+    .tags
+    .iter()
+    .flat_map(|tag| {
+      let members = dump.tag_members.get(&tag.id).into_iter().flatten();
+      members.map(move |member| (tag, member))
+    })
+    .collect();
+  let batch = RecordBatch::try_from_iter_with_nullable([
+    ("tag_id", u32s(rows.iter().map(|(x, _)| x.id)), false),
+    (
+      "tag_name",
+      strings(rows.iter().map(|(x, _)| &*x.name)),
+      false,
+    ),
+    ("user_id", strings(rows.iter().map(|(_, x)| &*x.id)), false),
+    ("name", strings(rows.iter().map(|(_, x)| &*x.name)), false),
+  ])?;
+  Ok(batch)
+}
+
+fn u32s(values: impl Iterator<Item = u32>) -> ArrayRef {
+  Arc::new(UInt32Array::from_iter_values(values))
+}
+
+fn opt_u32s(values: impl Iterator<Item = Option<u32>>) -> ArrayRef {
+  Arc::new(values.collect::<UInt32Array>())
+}
+
+fn strings<'a>(values: impl Iterator<Item = &'a str>) -> ArrayRef {
+  Arc::new(StringArray::from_iter_values(values))
+}
+
+fn opt_strings<'a>(values: impl Iterator<Item = Option<&'a str>>) -> ArrayRef {
+  Arc::new(values.collect::<StringArray>())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs::File;
+
+  use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+  use serde_json::json;
+
+  use crate::api::dump::Dump;
+  use crate::parquet_export::write_parquet;
+  use crate::util::TestDir;
+
+  #[test]
+  fn write_parquet_test() {
+    let dir = TestDir::new("parquet");
+    let dump = Dump {
+      departments: serde_json::from_value(json!([
+        {"id": 1, "name": "Company", "parentid": 0, "order": 1, "department_leader": ["a"]},
+        {"id": 2, "name": "Engineering", "parentid": 1, "order": 1},
+      ]))
+      .unwrap(),
+      tags: serde_json::from_value(json!([{"tagid": 1, "tagname": "A"}])).unwrap(),
+      tag_members: [(
+        1,
+        serde_json::from_value(json!([{"userid": "a", "name": "A"}])).unwrap(),
+      )]
+      .into(),
+      ..Default::default()
+    };
+    write_parquet(&dir, &dump).unwrap();
+
+    let rows = |name: &str| {
+      let file = File::open(dir.join(name)).unwrap();
+      let reader = ParquetRecordBatchReaderBuilder::try_new(file).unwrap();
+      let schema = reader.schema().clone();
+      let rows: usize = reader.build().unwrap().map(|x| x.unwrap().num_rows()).sum();
+      (schema.fields().len(), rows)
+    };
+    assert_eq!(rows("departments.parquet"), (6, 2));
+    assert_eq!(rows("members.parquet"), (17, 0));
+    assert_eq!(rows("tags.parquet"), (2, 1));
+    assert_eq!(rows("tag_members.parquet"), (4, 1));
+  }
+}
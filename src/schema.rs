@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+
+use schemars::schema::RootSchema;
+use schemars::schema_for;
+
+use crate::api::data::{
+  AgentDetail, AgentListResp, DepartmentMembersResp, DepartmentResp, SimpleMembersResp,
+  TagMembersResp, TagsResp,
+};
+
+/// JSON Schema of each response saved to the output files, keyed by type name
+pub fn schemas() -> BTreeMap<&'static str, RootSchema> {
+  BTreeMap::from([
+    ("AgentListResp", schema_for!(AgentListResp)),
+    ("AgentDetail", schema_for!(AgentDetail)),
+    ("DepartmentResp", schema_for!(DepartmentResp)),
+    ("DepartmentMembersResp", schema_for!(DepartmentMembersResp)),
+    ("SimpleMembersResp", schema_for!(SimpleMembersResp)),
+    ("TagsResp", schema_for!(TagsResp)),
+    ("TagMembersResp", schema_for!(TagMembersResp)),
+  ])
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::schema::schemas;
+
+  #[test]
+  fn schemas_test() {
+    let schemas = serde_json::to_value(schemas()).unwrap();
+    let members = &schemas["DepartmentMembersResp"];
+    assert_eq!(members["title"], "DepartmentMembersResp");
+    let member = &members["definitions"]["DepartmentMember"]["properties"];
+    assert!(member["userid"].is_object());
+    assert!(member["isleader"].is_object());
+    assert!(schemas["TagsResp"]["properties"]["taglist"].is_object());
+  }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::util::Secret;
+
+/// Credentials read from a secrets file or stdin
+#[derive(Debug, Default)]
+pub struct Secrets {
+  pub corp_id: Option<String>,
+  pub corp_secret: Option<Secret>,
+  pub corp_token: Option<Secret>,
+}
+
+impl Secrets {
+  /// Parse a JSON object or `key=value` lines, with keys `corp_id`, `corp_secret` and `corp_token`.
+  /// Empty lines and lines starting with `#` are ignored
+  pub fn parse(text: &str) -> Result<Secrets> {
+    let map: HashMap<String, String> = if text.trim_start().starts_with('{') {
+      serde_json::from_str(text).context("Failed to deserialize secrets")?
+    } else {
+      text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+          let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("Expected key=value, found {:?}", line.split('=').next()))?;
+          Ok((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect::<Result<_>>()?
+    };
+    for key in map.keys() {
+      if !["corp_id", "corp_secret", "corp_token"].contains(&&**key) {
+        bail!("Unknown key in secrets: {key}");
+      }
+    }
+    let secret = |key: &str| map.get(key).map(|i| Secret::from(i.clone()));
+    Ok(Secrets {
+      corp_id: map.get("corp_id").cloned(),
+      corp_secret: secret("corp_secret"),
+      corp_token: secret("corp_token"),
+    })
+  }
+
+  pub fn read_file(path: &Path) -> Result<Secrets> {
+    let text = fs::read_to_string(path)
+      .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+    Secrets::parse(&text)
+  }
+
+  /// Prompt for the id and secret on a terminal, otherwise parse the whole stdin like a file
+  pub fn read_stdin() -> Result<Secrets> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+      let mut text = String::new();
+      stdin
+        .lock()
+        .read_to_string(&mut text)
+        .context("Failed to read stdin")?;
+      return Secrets::parse(&text);
+    }
+    let prompt = |name: &str| -> Result<String> {
+      eprint!("{name}: ");
+      io::stderr().flush()?;
+      let mut line = String::new();
+      stdin
+        .lock()
+        .read_line(&mut line)
+        .context("Failed to read stdin")?;
+      Ok(line.trim().to_string())
+    };
+    Ok(Secrets {
+      corp_id: Some(prompt("Corp ID")?),
+      corp_secret: Some(Secret::from(prompt("Corp secret")?)),
+      corp_token: None,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::secrets::Secrets;
+
+  #[test]
+  fn parse_secrets_test() {
+    let secrets = Secrets::parse("# comment\ncorp_id = ww123\n\ncorp_secret=abc=\n").unwrap();
+    assert_eq!(secrets.corp_id.as_deref(), Some("ww123"));
+    assert_eq!(secrets.corp_secret.unwrap().expose(), "abc=");
+    assert!(secrets.corp_token.is_none());
+
+    let secrets = Secrets::parse(r#"{"corp_id": "ww123", "corp_token": "t"}"#).unwrap();
+    assert_eq!(secrets.corp_id.as_deref(), Some("ww123"));
+    assert_eq!(secrets.corp_token.unwrap().expose(), "t");
+
+    assert!(Secrets::parse("corp_id").is_err());
+    assert!(Secrets::parse("corp_pwd=1").is_err());
+  }
+}
@@ -0,0 +1,233 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Local};
+use log::error;
+use serde::Serialize;
+
+use crate::manifest::Manifest;
+use crate::util::{to_writer, write_json};
+
+/// Destination of the output files, addressed by paths relative to the output directory
+pub trait OutputSink: Send + Sync {
+  fn write_json<T: Serialize>(&self, path: &Path, value: &T) -> Result<()>
+  where
+    Self: Sized;
+
+  fn write_text(&self, path: &Path, text: &str) -> Result<()>;
+
+  /// Prepare a folder for the files in it, a no-op for sinks without folders
+  fn create_dir(&self, path: &Path) -> Result<()>;
+
+  /// Called once after every file is written
+  fn finish(&self) -> Result<()>;
+
+  /// The manifest of the files written under the folder `dir`, with paths relative to it and
+  /// top-level names stamped in `timestamp_format`, called after [OutputSink::finish]
+  fn manifest(&self, dir: &Path, time: DateTime<Local>, timestamp_format: &str)
+    -> Result<Manifest>;
+}
+
+/// Write `value` like [OutputSink::write_json] into a sink behind `dyn`
+pub fn write_json_to(sink: &dyn OutputSink, path: &Path, value: &impl Serialize) -> Result<()> {
+  sink.write_text(path, &to_text(path, value)?)
+}
+
+fn to_text(path: &Path, value: &impl Serialize) -> Result<String> {
+  let mut bytes = Vec::new();
+  to_writer(&mut bytes, value)
+    .with_context(|| format!("Failed to serialize {}", path.to_string_lossy()))?;
+  String::from_utf8(bytes).context("JSON is always UTF-8")
+}
+
+/// Write files into a folder on the filesystem
+#[derive(Clone)]
+pub struct FsSink {
+  root: PathBuf,
+}
+
+impl FsSink {
+  pub fn new(root: impl Into<PathBuf>) -> FsSink {
+    FsSink { root: root.into() }
+  }
+}
+
+impl OutputSink for FsSink {
+  fn write_json<T: Serialize>(&self, path: &Path, value: &T) -> Result<()> {
+    write_json(&self.root.join(path), value)
+  }
+
+  fn write_text(&self, path: &Path, text: &str) -> Result<()> {
+    let path = self.root.join(path);
+    fs::write(&path, text).with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+  }
+
+  fn create_dir(&self, path: &Path) -> Result<()> {
+    fs::create_dir_all(self.root.join(path))
+      .with_context(|| format!("Failed to create folder {}", path.to_string_lossy()))
+  }
+
+  fn finish(&self) -> Result<()> {
+    Ok(())
+  }
+
+  fn manifest(
+    &self,
+    dir: &Path,
+    time: DateTime<Local>,
+    timestamp_format: &str,
+  ) -> Result<Manifest> {
+    Manifest::collect(&self.root.join(dir), time, timestamp_format)
+  }
+}
+
+/// Default bound of the queue of [QueuedSink]
+pub const DEFAULT_WRITE_QUEUE: usize = 64;
+
+/// Write files with `inner` on a dedicated thread fed through a bounded queue, so fetching tasks
+/// only serialize the output and never wait on the disk or network, until the queue is full
+pub struct QueuedSink {
+  inner: Arc<dyn OutputSink>,
+  sender: Mutex<Option<SyncSender<(PathBuf, String)>>>,
+  /// Returns how many files failed to write
+  thread: Mutex<Option<JoinHandle<usize>>>,
+}
+
+impl QueuedSink {
+  /// Start the writer thread, with at most `bound` files waiting to be written
+  pub fn new(inner: Arc<dyn OutputSink>, bound: usize) -> Result<QueuedSink> {
+    let (sender, receiver) = mpsc::sync_channel::<(PathBuf, String)>(bound);
+    let sink = inner.clone();
+    let thread = thread::Builder::new()
+      .name("writer".to_string())
+      .spawn(move || {
+        let mut failed = 0;
+        for (path, text) in receiver {
+          if let Err(err) = sink.write_text(&path, &text) {
+            error!("{err:?}");
+            failed += 1;
+          }
+        }
+        failed
+      })
+      .context("Failed to start the writer thread")?;
+    Ok(QueuedSink {
+      inner,
+      sender: Mutex::new(Some(sender)),
+      thread: Mutex::new(Some(thread)),
+    })
+  }
+
+  fn send(&self, path: &Path, text: String) -> Result<()> {
+    let sender = self.sender.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(sender) = sender.clone() else {
+      bail!(
+        "Failed to write {}, the sink is finished",
+        path.to_string_lossy()
+      );
+    };
+    // blocks once the queue is full, which holds back fetching
+    sender.send((path.to_path_buf(), text)).map_err(|_| {
+      anyhow!(
+        "Failed to write {}, the writer thread exited",
+        path.to_string_lossy()
+      )
+    })
+  }
+
+  /// Close the queue and wait for the files in it to be written
+  fn close(&self) -> Result<()> {
+    drop(
+      self
+        .sender
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take(),
+    );
+    let thread = self
+      .thread
+      .lock()
+      .unwrap_or_else(PoisonError::into_inner)
+      .take();
+    let Some(thread) = thread else {
+      return Ok(());
+    };
+    match thread.join() {
+      Ok(0) => Ok(()),
+      Ok(failed) => bail!("Failed to write {failed} files"),
+      Err(_) => bail!("The writer thread panicked"),
+    }
+  }
+}
+
+impl OutputSink for QueuedSink {
+  fn write_json<T: Serialize>(&self, path: &Path, value: &T) -> Result<()> {
+    self.send(path, to_text(path, value)?)
+  }
+
+  fn write_text(&self, path: &Path, text: &str) -> Result<()> {
+    self.send(path, text.to_string())
+  }
+
+  fn create_dir(&self, path: &Path) -> Result<()> {
+    // created right away, so it exists before any file queued after it
+    self.inner.create_dir(path)
+  }
+
+  fn finish(&self) -> Result<()> {
+    self.close()
+  }
+
+  fn manifest(
+    &self,
+    dir: &Path,
+    time: DateTime<Local>,
+    timestamp_format: &str,
+  ) -> Result<Manifest> {
+    self.inner.manifest(dir, time, timestamp_format)
+  }
+}
+
+impl Drop for QueuedSink {
+  fn drop(&mut self) {
+    // still flush the queue when a run fails before finishing
+    if let Err(err) = self.close() {
+      error!("{err:?}");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::sink::{FsSink, OutputSink, QueuedSink};
+  use crate::util::TestDir;
+
+  #[test]
+  fn queued_sink_test() {
+    let dir = TestDir::new("sink");
+    let sink = QueuedSink::new(Arc::new(FsSink::new(&*dir)), 1).unwrap();
+    sink.create_dir("a".as_ref()).unwrap();
+    for i in 0..16 {
+      let path = format!("a/{i}.json");
+      sink.write_json(path.as_ref(), &json!({ "id": i })).unwrap();
+    }
+    sink.write_text("missing/x.txt".as_ref(), "x").unwrap();
+    let err = sink.finish().unwrap_err();
+    assert_eq!(err.to_string(), "Failed to write 1 files");
+    assert!(sink.write_text("b.txt".as_ref(), "b").is_err());
+
+    assert_eq!(fs::read_dir(dir.join("a")).unwrap().count(), 16);
+    let value: serde_json::Value =
+      serde_json::from_slice(&fs::read(dir.join("a/15.json")).unwrap()).unwrap();
+    assert_eq!(value["id"], 15);
+  }
+}
@@ -0,0 +1,539 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use serde::ser::{
+  self, SerializeMap, SerializeSeq, SerializeStruct, SerializeStructVariant, SerializeTuple,
+  SerializeTupleStruct, SerializeTupleVariant,
+};
+use serde::{Serialize, Serializer};
+
+/// Casing of the keys in the output JSON
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JsonStyle {
+  /// Keep the names of the WeChat API, like `errcode` and `parentid`
+  #[default]
+  Wechat,
+  /// `errCode`, `parentId`
+  Camel,
+  /// `err_code`, `parent_id`
+  Snake,
+}
+
+/// Names of the WeChat API made of several words without separators, with their snake case
+const COMPOUNDS: [(&str, &str); 18] = [
+  ("errcode", "err_code"),
+  ("errmsg", "err_msg"),
+  ("agentid", "agent_id"),
+  ("agentlist", "agent_list"),
+  ("userid", "user_id"),
+  ("userlist", "user_list"),
+  ("parentid", "parent_id"),
+  ("partyid", "party_id"),
+  ("partylist", "party_list"),
+  ("allow_partys", "allow_parties"),
+  ("tagid", "tag_id"),
+  ("taglist", "tag_list"),
+  ("tagname", "tag_name"),
+  ("isleader", "is_leader"),
+  ("isreportenter", "is_report_enter"),
+  ("createtime", "create_time"),
+  ("unionid", "union_id"),
+  ("extattr", "ext_attr"),
+];
+
+static JSON_STYLE: OnceLock<JsonStyle> = OnceLock::new();
+
+/// Rename the keys of the JSON written from now on, can only be set once
+pub fn set_json_style(style: JsonStyle) {
+  let _ = JSON_STYLE.set(style);
+}
+
+pub fn json_style() -> JsonStyle {
+  JSON_STYLE.get().copied().unwrap_or_default()
+}
+
+impl JsonStyle {
+  pub fn rename(self, key: &str) -> Cow<'_, str> {
+    let snake = || {
+      COMPOUNDS
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map_or(key, |(_, snake)| *snake)
+    };
+    match self {
+      JsonStyle::Wechat => Cow::Borrowed(key),
+      JsonStyle::Snake => Cow::Borrowed(snake()),
+      JsonStyle::Camel => {
+        let mut result = String::with_capacity(key.len());
+        let mut upper = false;
+        for char in snake().chars() {
+          match char {
+            // keep a leading underscore, like `_comment`
+            '_' if !result.is_empty() => upper = true,
+            _ if upper => {
+              result.extend(char.to_uppercase());
+              upper = false;
+            }
+            _ => result.push(char),
+          }
+        }
+        Cow::Owned(result)
+      }
+    }
+  }
+}
+
+/// Serialize `value` with the field names of structs renamed by `style`.
+/// Keys of maps like user ids are data and kept as is, except the maps of unknown length,
+/// which are structs with flattened fields
+pub struct Styled<'a, T: ?Sized> {
+  pub value: &'a T,
+  pub style: JsonStyle,
+}
+
+impl<T: Serialize + ?Sized> Serialize for Styled<'_, T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self.style {
+      JsonStyle::Wechat => self.value.serialize(serializer),
+      style => self.value.serialize(StyledSerializer {
+        inner: serializer,
+        style,
+      }),
+    }
+  }
+}
+
+struct StyledSerializer<S> {
+  inner: S,
+  style: JsonStyle,
+}
+
+impl<S> StyledSerializer<S> {
+  fn styled<'a, T: ?Sized>(&self, value: &'a T) -> Styled<'a, T> {
+    Styled {
+      value,
+      style: self.style,
+    }
+  }
+}
+
+/// Renames nothing itself but passes the style down to every element
+struct Compound<C> {
+  inner: C,
+  style: JsonStyle,
+  rename_keys: bool,
+}
+
+impl<C> Compound<C> {
+  fn styled<'a, T: ?Sized>(&self, value: &'a T) -> Styled<'a, T> {
+    Styled {
+      value,
+      style: self.style,
+    }
+  }
+}
+
+impl<S: Serializer> Serializer for StyledSerializer<S> {
+  type Ok = S::Ok;
+  type Error = S::Error;
+  type SerializeSeq = Compound<S::SerializeSeq>;
+  type SerializeTuple = Compound<S::SerializeTuple>;
+  type SerializeTupleStruct = Compound<S::SerializeTupleStruct>;
+  type SerializeTupleVariant = Compound<S::SerializeTupleVariant>;
+  type SerializeMap = Compound<S::SerializeMap>;
+  type SerializeStruct = Compound<S::SerializeMap>;
+  type SerializeStructVariant = Compound<S::SerializeStructVariant>;
+
+  fn serialize_bool(self, v: bool) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_bool(v)
+  }
+
+  fn serialize_i8(self, v: i8) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_i8(v)
+  }
+
+  fn serialize_i16(self, v: i16) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_i16(v)
+  }
+
+  fn serialize_i32(self, v: i32) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_i32(v)
+  }
+
+  fn serialize_i64(self, v: i64) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_i64(v)
+  }
+
+  fn serialize_u8(self, v: u8) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_u8(v)
+  }
+
+  fn serialize_u16(self, v: u16) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_u16(v)
+  }
+
+  fn serialize_u32(self, v: u32) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_u32(v)
+  }
+
+  fn serialize_u64(self, v: u64) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_u64(v)
+  }
+
+  fn serialize_f32(self, v: f32) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_f32(v)
+  }
+
+  fn serialize_f64(self, v: f64) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_f64(v)
+  }
+
+  fn serialize_char(self, v: char) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_char(v)
+  }
+
+  fn serialize_str(self, v: &str) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_str(v)
+  }
+
+  fn serialize_bytes(self, v: &[u8]) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_bytes(v)
+  }
+
+  fn serialize_none(self) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_none()
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<S::Ok, S::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_some(&value)
+  }
+
+  fn serialize_unit(self) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_unit()
+  }
+
+  fn serialize_unit_struct(self, name: &'static str) -> Result<S::Ok, S::Error> {
+    self.inner.serialize_unit_struct(name)
+  }
+
+  fn serialize_unit_variant(
+    self,
+    name: &'static str,
+    variant_index: u32,
+    variant: &'static str,
+  ) -> Result<S::Ok, S::Error> {
+    self
+      .inner
+      .serialize_unit_variant(name, variant_index, variant)
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(
+    self,
+    name: &'static str,
+    value: &T,
+  ) -> Result<S::Ok, S::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_newtype_struct(name, &value)
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    name: &'static str,
+    variant_index: u32,
+    variant: &'static str,
+    value: &T,
+  ) -> Result<S::Ok, S::Error> {
+    let value = self.styled(value);
+    self
+      .inner
+      .serialize_newtype_variant(name, variant_index, variant, &value)
+  }
+
+  fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, S::Error> {
+    let style = self.style;
+    Ok(Compound {
+      inner: self.inner.serialize_seq(len)?,
+      style,
+      rename_keys: false,
+    })
+  }
+
+  fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, S::Error> {
+    let style = self.style;
+    Ok(Compound {
+      inner: self.inner.serialize_tuple(len)?,
+      style,
+      rename_keys: false,
+    })
+  }
+
+  fn serialize_tuple_struct(
+    self,
+    name: &'static str,
+    len: usize,
+  ) -> Result<Self::SerializeTupleStruct, S::Error> {
+    let style = self.style;
+    Ok(Compound {
+      inner: self.inner.serialize_tuple_struct(name, len)?,
+      style,
+      rename_keys: false,
+    })
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    name: &'static str,
+    variant_index: u32,
+    variant: &'static str,
+    len: usize,
+  ) -> Result<Self::SerializeTupleVariant, S::Error> {
+    let style = self.style;
+    Ok(Compound {
+      inner: self
+        .inner
+        .serialize_tuple_variant(name, variant_index, variant, len)?,
+      style,
+      rename_keys: false,
+    })
+  }
+
+  fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, S::Error> {
+    let style = self.style;
+    Ok(Compound {
+      inner: self.inner.serialize_map(len)?,
+      style,
+      // structs with flattened fields are serialized as maps of unknown length
+      rename_keys: len.is_none(),
+    })
+  }
+
+  fn serialize_struct(
+    self,
+    _name: &'static str,
+    len: usize,
+  ) -> Result<Self::SerializeStruct, S::Error> {
+    // field names of a struct are static, so write it as a map to rename them
+    let style = self.style;
+    Ok(Compound {
+      inner: self.inner.serialize_map(Some(len))?,
+      style,
+      rename_keys: true,
+    })
+  }
+
+  fn serialize_struct_variant(
+    self,
+    name: &'static str,
+    variant_index: u32,
+    variant: &'static str,
+    len: usize,
+  ) -> Result<Self::SerializeStructVariant, S::Error> {
+    let style = self.style;
+    Ok(Compound {
+      inner: self
+        .inner
+        .serialize_struct_variant(name, variant_index, variant, len)?,
+      style,
+      rename_keys: false,
+    })
+  }
+}
+
+impl<C: SerializeSeq> SerializeSeq for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_element(&value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+impl<C: SerializeTuple> SerializeTuple for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_element(&value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+impl<C: SerializeTupleStruct> SerializeTupleStruct for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_field(&value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+impl<C: SerializeTupleVariant> SerializeTupleVariant for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_field(&value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+impl<C: SerializeMap> SerializeMap for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), C::Error> {
+    if !self.rename_keys {
+      return self.inner.serialize_key(key);
+    }
+    match serde_json::to_value(key).map_err(ser::Error::custom)? {
+      serde_json::Value::String(key) => self.inner.serialize_key(&self.style.rename(&key)),
+      _ => self.inner.serialize_key(key),
+    }
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_value(&value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+impl<C: SerializeMap> SerializeStruct for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    key: &'static str,
+    value: &T,
+  ) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_entry(&self.style.rename(key), &value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+impl<C: SerializeStructVariant> SerializeStructVariant for Compound<C> {
+  type Ok = C::Ok;
+  type Error = C::Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    key: &'static str,
+    value: &T,
+  ) -> Result<(), C::Error> {
+    let value = self.styled(value);
+    self.inner.serialize_field(key, &value)
+  }
+
+  fn end(self) -> Result<C::Ok, C::Error> {
+    self.inner.end()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use serde::Serialize;
+  use serde_json::json;
+
+  use crate::style::{JsonStyle, Styled};
+
+  #[derive(Serialize)]
+  struct Member {
+    #[serde(rename = "userid")]
+    user_id: &'static str,
+    is_leader_in_dept: Vec<u32>,
+    extattr: BTreeMap<&'static str, u32>,
+  }
+
+  #[derive(Serialize)]
+  struct Resp {
+    errcode: i32,
+    userlist: Vec<Member>,
+  }
+
+  #[derive(Serialize)]
+  struct Flattened {
+    #[serde(flatten)]
+    resp: Resp,
+    by_user: BTreeMap<&'static str, u32>,
+  }
+
+  #[test]
+  fn rename_test() {
+    assert_eq!(JsonStyle::Snake.rename("parentid"), "parent_id");
+    assert_eq!(JsonStyle::Camel.rename("parentid"), "parentId");
+    assert_eq!(
+      JsonStyle::Camel.rename("is_leader_in_dept"),
+      "isLeaderInDept"
+    );
+    assert_eq!(JsonStyle::Camel.rename("_comment"), "_comment");
+    assert_eq!(JsonStyle::Wechat.rename("parentid"), "parentid");
+  }
+
+  #[test]
+  fn styled_test() {
+    let value = Flattened {
+      resp: Resp {
+        errcode: 0,
+        userlist: vec![Member {
+          user_id: "zhang_san",
+          is_leader_in_dept: vec![1],
+          extattr: BTreeMap::from([("work_id", 1)]),
+        }],
+      },
+      by_user: BTreeMap::from([("zhang_san", 1)]),
+    };
+    let styled = |style| {
+      serde_json::to_value(Styled {
+        value: &value,
+        style,
+      })
+      .unwrap()
+    };
+    assert_eq!(
+      styled(JsonStyle::Camel),
+      json!({
+        "errCode": 0,
+        "userList": [{"userId": "zhang_san", "isLeaderInDept": [1], "extAttr": {"work_id": 1}}],
+        "byUser": {"zhang_san": 1},
+      })
+    );
+    assert_eq!(
+      styled(JsonStyle::Wechat),
+      json!({
+        "errcode": 0,
+        "userlist": [{"userid": "zhang_san", "is_leader_in_dept": [1], "extattr": {"work_id": 1}}],
+        "by_user": {"zhang_san": 1},
+      })
+    );
+  }
+}
@@ -1,25 +1,259 @@
+use std::env;
+use std::fmt::{self, Debug, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use crate::style::{json_style, Styled};
+
 pub trait ReplaceSpecial {
   fn replace_special_char(self) -> String;
 }
 
 impl ReplaceSpecial for String {
+  /// Replace characters not allowed in file names with `-`, collapsing runs of the replacements
+  /// but keeping the `-` already in the name, and strip control characters, including C0, DEL
+  /// and C1
   fn replace_special_char(self) -> String {
     const SPECIALS: [char; 9] = ['?', '*', ':', '"', '<', '>', '\\', '/', '|'];
-    #[rustfmt::skip]
-    const NON_PRINTABLE: [char; 32] = [
-      0u8 as char, 1u8 as char, 2u8 as char, 3u8 as char, 4u8 as char, 5u8 as char, 6u8 as char,
-      7u8 as char, 8u8 as char, 9u8 as char, 10u8 as char, 11u8 as char, 12u8 as char, 13u8 as char,
-      14u8 as char, 15u8 as char, 16u8 as char, 17u8 as char, 18u8 as char, 19u8 as char, 20u8 as char,
-      21u8 as char, 22u8 as char, 23u8 as char, 24u8 as char, 25u8 as char, 26u8 as char, 27u8 as char,
-      28u8 as char, 29u8 as char, 30u8 as char, 31u8 as char,
-    ];
-
-    let remove_special = SPECIALS
-      .into_iter()
-      .fold(self, |acc, i| acc.replace(i, "-"));
-
-    NON_PRINTABLE
-      .into_iter()
-      .fold(remove_special, |acc, i| acc.replace(i, ""))
+
+    let mut result = String::with_capacity(self.len());
+    // whether the last pushed character is a replacement
+    let mut replaced = false;
+    for char in self.chars() {
+      if char.is_control() {
+        continue;
+      }
+      if !SPECIALS.contains(&char) {
+        result.push(char);
+        replaced = false;
+      } else if !replaced {
+        result.push('-');
+        replaced = true;
+      }
+    }
+    result
+  }
+}
+
+/// Replace every `${VAR}` in `s` with the environment variable, which must be set,
+/// `$${` is kept as a literal `${`
+pub fn expand_env(s: &str) -> Result<String> {
+  let mut result = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(start) = rest.find("${") {
+    if rest[..start].ends_with('$') {
+      result.push_str(&rest[..start - 1]);
+      result.push_str("${");
+      rest = &rest[start + 2..];
+      continue;
+    }
+    result.push_str(&rest[..start]);
+    let name_start = start + 2;
+    let Some(len) = rest[name_start..].find('}') else {
+      bail!("Unclosed ${{ in {s:?}");
+    };
+    let name = &rest[name_start..name_start + len];
+    if name.is_empty() {
+      bail!("Empty ${{}} in {s:?}");
+    }
+    let value = env::var(name).with_context(|| {
+      format!("Environment variable {name} referenced by ${{{name}}} is not set")
+    })?;
+    result.push_str(&value);
+    rest = &rest[name_start + len + 1..];
+  }
+  result.push_str(rest);
+  Ok(result)
+}
+
+/// Decode `%XX` sequences in `s`, like `%E9%94%80%E5%94%AE` of a URL-encoded name.
+/// Malformed sequences like `%G1`, and bytes which are not valid UTF-8, are kept as is
+pub fn percent_decode(s: &str) -> String {
+  let mut result = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(start) = rest.find('%') {
+    result.push_str(&rest[..start]);
+    rest = &rest[start..];
+    let mut bytes = Vec::new();
+    while let Some(byte) = hex_byte(&rest[bytes.len() * 3..]) {
+      bytes.push(byte);
+    }
+    if bytes.is_empty() {
+      result.push('%');
+      rest = &rest[1..];
+      continue;
+    }
+    let len = bytes.len() * 3;
+    // every byte is 3 characters, so the valid part maps back to the text
+    let valid = match std::str::from_utf8(&bytes) {
+      Ok(text) => text,
+      Err(err) => std::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or_default(),
+    };
+    result.push_str(valid);
+    result.push_str(&rest[valid.len() * 3..len]);
+    rest = &rest[len..];
+  }
+  result.push_str(rest);
+  result
+}
+
+/// The byte of a leading `%XX` in `s`
+fn hex_byte(s: &str) -> Option<u8> {
+  let hex = s.strip_prefix('%')?.get(..2)?;
+  if !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+    return None;
+  }
+  u8::from_str_radix(hex, 16).ok()
+}
+
+static COMPACT: AtomicBool = AtomicBool::new(false);
+
+/// Write JSON without indentation from now on, for machine consumers
+pub fn set_compact(compact: bool) {
+  COMPACT.store(compact, Ordering::Relaxed);
+}
+
+/// Serialize `value` as JSON into `writer`, pretty unless [set_compact],
+/// with keys renamed by [set_json_style]
+pub fn to_writer<W: Write, T: Serialize>(writer: W, value: &T) -> serde_json::Result<()> {
+  let value = Styled {
+    value,
+    style: json_style(),
+  };
+  match COMPACT.load(Ordering::Relaxed) {
+    true => serde_json::to_writer(writer, &value),
+    false => serde_json::to_writer_pretty(writer, &value),
+  }
+}
+
+/// Serialize `value` as JSON and save it to `path`, streaming into the file
+/// instead of buffering the whole output in memory
+pub fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+  let file =
+    File::create(path).with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+  let mut buf_writer = BufWriter::new(file);
+  to_writer(&mut buf_writer, value)
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))?;
+  // flush explicitly, since errors are ignored when dropping
+  buf_writer
+    .flush()
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+/// A credential, which is masked in [Debug] output to keep it out of logs
+#[derive(Clone, Default)]
+pub struct Secret(String);
+
+impl Secret {
+  pub fn expose(&self) -> &str {
+    &self.0
+  }
+}
+
+impl FromStr for Secret {
+  type Err = std::convert::Infallible;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(Secret(s.to_string()))
+  }
+}
+
+impl From<String> for Secret {
+  fn from(s: String) -> Self {
+    Secret(s)
+  }
+}
+
+impl Debug for Secret {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_str("***")
+  }
+}
+
+/// An empty folder of a test under the temporary directory, removed when dropped even if the
+/// test fails
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+  /// Named by `name` and the process, left over ones of an earlier run are removed first
+  pub fn new(name: &str) -> TestDir {
+    let dir = env::temp_dir().join(format!("qywx-dumper-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    TestDir(dir)
+  }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+  type Target = Path;
+
+  fn deref(&self) -> &Path {
+    &self.0
+  }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use crate::util::{expand_env, percent_decode, ReplaceSpecial, Secret};
+
+  #[test]
+  fn replace_special_char_test() {
+    let name = "a\u{7f}b\u{85}c\u{1}d".to_string();
+    assert_eq!(name.replace_special_char(), "abcd");
+    let name = "members-1-R&D: <Team>/*?.json".to_string();
+    assert_eq!(name.replace_special_char(), "members-1-R&D- -Team-.json");
+    let name = "members-2-A--B/-C.json".to_string();
+    assert_eq!(name.replace_special_char(), "members-2-A--B--C.json");
+  }
+
+  #[test]
+  fn percent_decode_test() {
+    assert_eq!(percent_decode("%E9%94%80%E5%94%AE"), "销售");
+    assert_eq!(percent_decode("R%26D%20Team"), "R&D Team");
+    assert_eq!(percent_decode("100% sure"), "100% sure");
+    assert_eq!(percent_decode("%G1%4"), "%G1%4");
+    assert_eq!(percent_decode("%E9%94"), "%E9%94");
+    assert_eq!(percent_decode("%41%E9%94x"), "A%E9%94x");
+    assert_eq!(percent_decode("%%41"), "%A");
+    assert_eq!(percent_decode("销%"), "销%");
+  }
+
+  #[test]
+  fn expand_env_test() {
+    env::set_var("QYWX_DUMPER_TEST_PROXY_HOST", "proxy.internal");
+    assert_eq!(
+      expand_env("http://${QYWX_DUMPER_TEST_PROXY_HOST}:3128").unwrap(),
+      "http://proxy.internal:3128"
+    );
+    assert_eq!(expand_env("a$${b}$c").unwrap(), "a${b}$c");
+    let err = expand_env("${QYWX_DUMPER_TEST_UNSET}").unwrap_err();
+    assert!(err.to_string().contains("QYWX_DUMPER_TEST_UNSET"), "{err}");
+    assert!(expand_env("${QYWX_DUMPER_TEST_PROXY_HOST").is_err());
+  }
+
+  #[test]
+  fn secret_debug_test() {
+    let secret: Secret = "my-secret".parse().unwrap();
+    assert_eq!(format!("{secret:?}"), "***");
+    assert_eq!(format!("{:?}", Some(secret.clone())), "Some(***)");
+    assert_eq!(secret.expose(), "my-secret");
   }
 }
@@ -0,0 +1,217 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use itertools::Itertools;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::api::data::{
+  AgentDetail, AgentListResp, DepartmentMembersResp, DepartmentResp, SimpleMembersResp,
+  TagMembersResp, TagsResp,
+};
+use crate::manifest::{managed_files, Manifest};
+use crate::style::JsonStyle;
+
+#[derive(Debug, PartialEq, Eq)]
+struct BadFile {
+  /// Relative to the validated folder, separated by `/`
+  path: String,
+  reason: String,
+}
+
+/// Results of `--validate`, checking a dump offline without trusting the run that wrote it
+#[derive(Debug, Default)]
+pub struct Validation {
+  /// JSON files parsed
+  checked: usize,
+  /// Whether there is a `manifest.json` to verify against
+  manifest: bool,
+  /// A file may be bad for more than one reason
+  bad: Vec<BadFile>,
+}
+
+impl Validation {
+  /// Parse every JSON file created by this tool in `dir`, with top-level names stamped in
+  /// `timestamp_format`, and verify the files against its manifest if there is one
+  pub fn run(dir: &Path, timestamp_format: &str) -> Result<Validation> {
+    let mut validation = Validation::default();
+    for (path, relative) in managed_files(dir, timestamp_format)? {
+      if !relative.ends_with(".json") {
+        continue;
+      }
+      validation.checked += 1;
+      // saved before deserializing, so the errors of the API are kept as they were
+      let raw = relative
+        .split('/')
+        .any(|x| x == "raw" || x.starts_with("raw-"));
+      if let Err(reason) = check_json(&path, !raw) {
+        validation.bad.push(BadFile {
+          path: relative,
+          reason,
+        });
+      }
+    }
+    if let Some(mismatches) = Manifest::verify(dir)? {
+      validation.manifest = true;
+      for (path, reason) in mismatches {
+        validation.bad.push(BadFile { path, reason });
+      }
+    }
+    Ok(validation)
+  }
+
+  pub fn passed(&self) -> bool {
+    self.bad.is_empty()
+  }
+
+  fn bad_files(&self) -> usize {
+    self.bad.iter().map(|x| &x.path).unique().count()
+  }
+}
+
+impl Display for Validation {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    for x in &self.bad {
+      writeln!(f, "BAD  {}: {}", x.path, x.reason.replace('\n', " "))?;
+    }
+    if !self.bad.is_empty() {
+      writeln!(f)?;
+    }
+    writeln!(f, "Parsed {} JSON files", self.checked)?;
+    match self.manifest {
+      true => writeln!(f, "Verified the files against manifest.json")?,
+      false => writeln!(f, "No manifest.json to verify the files against")?,
+    }
+    match self.passed() {
+      true => write!(f, "Validation passed"),
+      false => write!(f, "Validation failed, {} bad files", self.bad_files()),
+    }
+  }
+}
+
+/// Parse the JSON file at `path`, requiring a zero `errcode` in the responses of the API if
+/// `check_code`, and the shape of their types, returning why it is bad
+fn check_json(path: &Path, check_code: bool) -> std::result::Result<(), String> {
+  let json = fs::read(path).map_err(|err| format!("Failed to read: {err}"))?;
+  let value: Value = serde_json::from_slice(&json).map_err(|err| format!("Invalid JSON: {err}"))?;
+  let Value::Object(object) = &value else {
+    return Ok(());
+  };
+  // the key is renamed by --json-style
+  let code = JsonStyle::value_variants()
+    .iter()
+    .find_map(|x| object.get(&*x.rename("errcode")));
+  // the agent list without permission is saved with its errcode and a note why
+  let denied = object.contains_key("note");
+  match code {
+    None => return Ok(()),
+    Some(code) if check_code && !denied && code.as_i64() != Some(0) => {
+      return Err(format!("errcode is {code}"))
+    }
+    Some(_) => {}
+  }
+  // the types only read the names of the API
+  match object.contains_key("errcode") {
+    true => check_type(value),
+    false => Ok(()),
+  }
+}
+
+/// Deserialize a response into the type told by its list, others are left as parsed
+fn check_type(value: Value) -> std::result::Result<(), String> {
+  fn parse<T: DeserializeOwned>(value: Value, name: &str) -> std::result::Result<(), String> {
+    match serde_json::from_value::<T>(value) {
+      Ok(_) => Ok(()),
+      Err(err) => Err(format!("Not a valid {name}: {err}")),
+    }
+  }
+  let has = |key| value.get(key).is_some();
+  if has("tagname") {
+    parse::<TagMembersResp>(value, "tag")
+  } else if has("userlist") {
+    // the simple lists of --minimal-members or without permission have no details
+    match value["userlist"][0].get("status") {
+      Some(_) => parse::<DepartmentMembersResp>(value, "member list"),
+      None => parse::<SimpleMembersResp>(value, "simple member list"),
+    }
+  } else if has("department") {
+    parse::<DepartmentResp>(value, "department list")
+  } else if has("taglist") {
+    parse::<TagsResp>(value, "tag list")
+  } else if has("agentlist") {
+    parse::<AgentListResp>(value, "agent list")
+  } else if has("agentid") {
+    parse::<AgentDetail>(value, "agent")
+  } else {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use chrono::Local;
+
+  use crate::layout::DEFAULT_TIMESTAMP_FORMAT;
+  use crate::manifest::{Manifest, MANIFEST};
+  use crate::util::{write_json, TestDir};
+  use crate::validate::{BadFile, Validation};
+
+  #[test]
+  fn validation_test() {
+    let dir = TestDir::new("validate");
+    fs::create_dir(dir.join("tags")).unwrap();
+    let tags = r#"{"errcode":0,"errmsg":"ok","taglist":[{"tagid":1,"tagname":"Ops"}]}"#;
+    fs::write(dir.join("tags.json"), tags).unwrap();
+    let tag = r#"{"errcode":0,"errmsg":"ok","userlist":[],"partylist":[],"tagname":"Ops"}"#;
+    fs::write(dir.join("tags/1-Ops.json"), tag).unwrap();
+    fs::write(dir.join("summary.json"), r#"{"members":0}"#).unwrap();
+    fs::write(dir.join("notes.json"), "not ours").unwrap();
+    fs::create_dir(dir.join("raw")).unwrap();
+    let raw = r#"{"errcode":60011,"errmsg":"no privilege"}"#;
+    fs::write(dir.join("raw/user-list-2-0.json"), raw).unwrap();
+    let agents = r#"{"errcode":60011,"errmsg":"no privilege","agentlist":[],"note":"denied"}"#;
+    fs::write(dir.join("agents.json"), agents).unwrap();
+    let manifest = Manifest::collect(&dir, Local::now(), DEFAULT_TIMESTAMP_FORMAT).unwrap();
+    write_json(&dir.join(MANIFEST), &manifest).unwrap();
+    let validation = Validation::run(&dir, DEFAULT_TIMESTAMP_FORMAT).unwrap();
+    assert!(validation.passed(), "{validation}");
+    assert_eq!(validation.checked, 5);
+
+    let denied = r#"{"errcode":60011,"errmsg":"no privilege","taglist":[]}"#;
+    fs::write(dir.join("tags.json"), denied).unwrap();
+    fs::write(dir.join("tags/1-Ops.json"), r#"{"errcode":0,"tagname":1}"#).unwrap();
+    fs::write(dir.join("summary.json"), r#"{"members":"#).unwrap();
+    let validation = Validation::run(&dir, DEFAULT_TIMESTAMP_FORMAT).unwrap();
+    assert!(!validation.passed());
+    let bad: Vec<&str> = validation.bad.iter().map(|x| x.path.as_str()).collect();
+    assert_eq!(
+      bad,
+      [
+        "summary.json",
+        "tags.json",
+        "tags/1-Ops.json",
+        "summary.json",
+        "tags.json",
+        "tags/1-Ops.json"
+      ]
+    );
+    assert_eq!(
+      validation.bad[1],
+      BadFile {
+        path: "tags.json".to_string(),
+        reason: "errcode is 60011".to_string(),
+      }
+    );
+    assert!(validation.bad[0].reason.starts_with("Invalid JSON"));
+    assert!(validation.bad[2].reason.starts_with("Not a valid tag"));
+    assert!(validation.bad[3].reason.contains("manifest"));
+    assert!(validation
+      .to_string()
+      .ends_with("Validation failed, 3 bad files"));
+  }
+}
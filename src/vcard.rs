@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+
+use crate::api::data::{Department, DepartmentMember};
+use crate::api::dump::{ancestors, Dump};
+
+/// Max octets of a line before folding, see RFC 2425
+const LINE_OCTETS: usize = 75;
+
+/// Write a vCard 3.0 for every member, once each, with the path of the main department in `ORG`
+pub fn write_vcards(path: &Path, dump: &Dump) -> Result<()> {
+  fs::write(path, to_vcards(dump))
+    .with_context(|| format!("Failed to write {}", path.to_string_lossy()))
+}
+
+fn to_vcards(dump: &Dump) -> String {
+  let by_id: HashMap<u32, &Department> = dump.departments.iter().map(|x| (x.id, x)).collect();
+  let members = dump
+    .members_by_department
+    .values()
+    .flatten()
+    .unique_by(|x| &x.user_id);
+  let mut text = String::new();
+  for member in members {
+    let org = member
+      .main_department
+      .iter()
+      .chain(&member.department)
+      .find_map(|id| by_id.get(id))
+      .map(|department| {
+        let chain = ancestors(&by_id, department);
+        chain.iter().rev().map(|x| escape(&x.name)).join(";")
+      });
+    vcard(&mut text, member, org);
+  }
+  text
+}
+
+fn vcard(text: &mut String, member: &DepartmentMember, org: Option<String>) {
+  let biz_mail = member.biz_mail.as_deref().unwrap_or_default();
+  let mut line = |name: &str, value: String| {
+    for (i, folded) in fold(&format!("{name}:{value}")).into_iter().enumerate() {
+      if i > 0 {
+        text.push(' ');
+      }
+      text.push_str(folded);
+      text.push_str("\r\n");
+    }
+  };
+  line("BEGIN", "VCARD".to_string());
+  line("VERSION", "3.0".to_string());
+  line("UID", escape(&member.user_id));
+  line("FN", escape(&member.name));
+  line("N", format!("{};;;;", escape(&member.name)));
+  let fields = [
+    ("TEL;TYPE=CELL", &*member.mobile),
+    ("TEL;TYPE=WORK", &member.telephone),
+    ("EMAIL;TYPE=INTERNET", &member.email),
+    ("EMAIL;TYPE=INTERNET,WORK", biz_mail),
+    ("TITLE", &member.position),
+  ];
+  for (name, value) in fields {
+    if !value.is_empty() {
+      line(name, escape(value));
+    }
+  }
+  if let Some(org) = org {
+    line("ORG", org);
+  }
+  line("END", "VCARD".to_string());
+}
+
+/// Escape a text value, see RFC 2426
+fn escape(value: &str) -> String {
+  let mut escaped = String::with_capacity(value.len());
+  for c in value.chars() {
+    match c {
+      '\\' | ',' | ';' => {
+        escaped.push('\\');
+        escaped.push(c);
+      }
+      '\n' => escaped.push_str("\\n"),
+      '\r' => {}
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+/// Split a line into parts of at most [LINE_OCTETS] octets, without splitting a character,
+/// the continuations take one octet less for the leading space
+fn fold(line: &str) -> Vec<&str> {
+  let mut parts = Vec::new();
+  let mut rest = line;
+  let mut max = LINE_OCTETS;
+  while rest.len() > max {
+    let mut end = max;
+    while !rest.is_char_boundary(end) {
+      end -= 1;
+    }
+    parts.push(&rest[..end]);
+    rest = &rest[end..];
+    max = LINE_OCTETS - 1;
+  }
+  parts.push(rest);
+  parts
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+  use std::sync::Arc;
+
+  use serde_json::json;
+
+  use crate::api::data::{Department, DepartmentMember};
+  use crate::api::dump::Dump;
+  use crate::vcard::{escape, fold, to_vcards};
+
+  #[test]
+  fn to_vcards_test() {
+    let member = DepartmentMember::test(
+      "zhangsan",
+      json!({
+        "name": "Zhang, San", "department": [2], "main_department": 2,
+        "mobile": "13800000000", "email": "zs@example.com",
+      }),
+    );
+    let department = |id, name: &str, parent_id| Department {
+      id,
+      name: name.to_string(),
+      parent_id: Some(parent_id),
+      order: 0,
+      name_en: None,
+      department_leader: None,
+    };
+    let dump = Dump {
+      departments: vec![department(1, "Company", 0), department(2, "R&D", 1)],
+      members_by_department: BTreeMap::from([(1, vec![Arc::new(member)])]),
+      ..Default::default()
+    };
+    assert_eq!(
+      to_vcards(&dump),
+      "BEGIN:VCARD\r\n\
+       VERSION:3.0\r\n\
+       UID:zhangsan\r\n\
+       FN:Zhang\\, San\r\n\
+       N:Zhang\\, San;;;;\r\n\
+       TEL;TYPE=CELL:13800000000\r\n\
+       EMAIL;TYPE=INTERNET:zs@example.com\r\n\
+       ORG:Company;R&D\r\n\
+       END:VCARD\r\n"
+    );
+  }
+
+  #[test]
+  fn escape_test() {
+    assert_eq!(escape("a;b,c\\d\r\ne"), r"a\;b\,c\\d\ne");
+  }
+
+  #[test]
+  fn fold_test() {
+    let line = format!("FN:{}", "张".repeat(30));
+    let parts = fold(&line);
+    assert_eq!(parts.concat(), line);
+    assert!(parts.iter().all(|x| x.len() <= 75));
+    assert_eq!(parts[0].len(), 75);
+    assert_eq!(fold("FN:a"), ["FN:a"]);
+  }
+}
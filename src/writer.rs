@@ -0,0 +1,439 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+
+use anyhow::Result;
+use log::{error, info};
+use serde::Serialize;
+
+use crate::anomalies::Anomalies;
+use crate::api::data::{
+  AgentBasic, AgentDetail, AgentListResp, ApiError, Department, DepartmentMembersResp,
+  DepartmentResp, Rejected, SimpleMembersResp, Tag, TagMembersResp, TagsResp,
+};
+use crate::api::dump::{Dump, DumpObserver, Job};
+use crate::layout::{NameTemplate, Paths};
+use crate::sink::{FsSink, OutputSink};
+use crate::util::{percent_decode, ReplaceSpecial};
+
+/// Save every fetched response to the output directory
+pub struct FileWriter<S: OutputSink = FsSink> {
+  paths: Paths,
+  sink: S,
+  /// Tags fetched without any member, in the order they finished
+  empty_tags: Mutex<Vec<Tag>>,
+  resolve_agent_scopes: bool,
+  tags_union: bool,
+  decode_names: bool,
+  name_template: NameTemplate,
+}
+
+impl<S: OutputSink> DumpObserver for FileWriter<S> {
+  fn on_agents(&self, resp: &AgentListResp) -> Result<()> {
+    self.sink.write_json(&self.paths.top("agents.json"), resp)?;
+    self.sink.create_dir(&self.paths.dir("agents"))
+  }
+
+  fn on_agents_denied(&self, err: &ApiError) -> Result<()> {
+    let resp = AgentListResp {
+      code: Some(err.code),
+      msg: Some(err.msg.clone()),
+      agent_list: Vec::new(),
+    };
+    let denied = Denied {
+      resp: &resp,
+      note: "No permission to get the agent list, grant the app the permission or use \
+             a secret with it to include agents",
+    };
+    self
+      .sink
+      .write_json(&self.paths.top("agents.json"), &denied)
+  }
+
+  fn on_agent_detail(&self, agent: &AgentBasic, resp: &AgentDetail) {
+    let path = self.agent_path(agent);
+    match self.sink.write_json(&path, resp) {
+      Ok(_) => info!(
+        "Successfully save agent details to {}",
+        path.to_string_lossy(),
+      ),
+      Err(err) => error!(
+        "Failed to save agent details to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    };
+  }
+
+  fn on_departments(&self, resp: &DepartmentResp) -> Result<()> {
+    self
+      .sink
+      .write_json(&self.paths.top("departments.json"), resp)?;
+    self.sink.create_dir(&self.paths.dir("departments"))
+  }
+
+  fn on_department_members(&self, department: &Department, resp: &DepartmentMembersResp) {
+    let path = self.department_path(department);
+    match self.sink.write_json(&path, resp) {
+      Ok(_) => info!(
+        "Successfully save department members to {}, total {}",
+        path.to_string_lossy(),
+        resp.members.len()
+      ),
+      Err(err) => error!(
+        "Failed to save department members to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    };
+  }
+
+  fn on_rejected_members(&self, department: &Department, rejected: &[Rejected]) {
+    let path = self
+      .department_path(department)
+      .with_extension("errors.json");
+    match self.sink.write_json(&path, &rejected) {
+      Ok(_) => info!(
+        "Saved the members failed to deserialize to {}",
+        path.to_string_lossy()
+      ),
+      Err(err) => error!(
+        "Failed to save the members failed to deserialize to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    };
+  }
+
+  fn on_department_simple_members(&self, department: &Department, resp: &SimpleMembersResp) {
+    let path = self.department_path(department);
+    let degraded = Degraded {
+      resp,
+      degraded: "user/simplelist",
+    };
+    match self.sink.write_json(&path, &degraded) {
+      Ok(_) => info!(
+        "Successfully save simple department members to {}, total {}",
+        path.to_string_lossy(),
+        resp.members.len()
+      ),
+      Err(err) => error!(
+        "Failed to save simple department members to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    };
+  }
+
+  fn on_tags(&self, resp: &TagsResp) -> Result<()> {
+    self.sink.write_json(&self.paths.top("tags.json"), resp)?;
+    self.sink.create_dir(&self.paths.dir("tags"))
+  }
+
+  fn on_tag_members(&self, tag: &Tag, resp: &TagMembersResp) {
+    if resp.members.is_empty() && resp.department_list.is_empty() {
+      self
+        .empty_tags
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(tag.clone());
+      return;
+    }
+    let name = self.file_name(&tag.name);
+    let name = self.name_template.render(tag.id, &name, None);
+    let path = self.paths.item("tags", &name);
+    match self.sink.write_json(&path, resp) {
+      Ok(_) => info!(
+        "Successfully save tag members to {}, total {}",
+        path.to_string_lossy(),
+        resp.members.len()
+      ),
+      Err(err) => error!(
+        "Failed to save tag members to {}: {err:?}",
+        path.to_string_lossy()
+      ),
+    };
+  }
+}
+
+/// Members saved from the simple list, with only user id, name and departments
+#[derive(Serialize)]
+struct Degraded<'a> {
+  #[serde(flatten)]
+  resp: &'a SimpleMembersResp,
+  /// The endpoint fetched instead of `user/list`
+  degraded: &'static str,
+}
+
+/// An empty agent list with why it is empty
+#[derive(Serialize)]
+struct Denied<'a> {
+  #[serde(flatten)]
+  resp: &'a AgentListResp,
+  note: &'static str,
+}
+
+/// Agent details with the visible departments and tags resolved to names
+#[derive(Serialize)]
+struct ResolvedAgent<'a> {
+  #[serde(flatten)]
+  detail: &'a AgentDetail,
+  allow_party_names: Vec<Named<'a>>,
+  allow_tag_names: Vec<Named<'a>>,
+}
+
+/// An id with its name, [None] if not found in the fetched list
+#[derive(Serialize)]
+struct Named<'a> {
+  id: u32,
+  name: Option<&'a str>,
+}
+
+fn resolve<'a>(ids: &[u32], names: &HashMap<u32, &'a str>) -> Vec<Named<'a>> {
+  ids
+    .iter()
+    .map(|&id| Named {
+      id,
+      name: names.get(&id).copied(),
+    })
+    .collect()
+}
+
+impl<S: OutputSink> FileWriter<S> {
+  pub fn new(paths: Paths, sink: S) -> FileWriter<S> {
+    FileWriter {
+      paths,
+      sink,
+      empty_tags: Mutex::new(Vec::new()),
+      resolve_agent_scopes: false,
+      tags_union: false,
+      decode_names: false,
+      name_template: NameTemplate::default(),
+    }
+  }
+
+  /// Rewrite agent details with the names of visible departments and tags in [FileWriter::finish],
+  /// which are only known after every job finished
+  pub fn set_resolve_agent_scopes(&mut self, resolve: bool) {
+    self.resolve_agent_scopes = resolve;
+  }
+
+  /// Write the members of all tags deduplicated to `tags_union.json` in [FileWriter::finish]
+  pub fn set_tags_union(&mut self, union: bool) {
+    self.tags_union = union;
+  }
+
+  /// Percent-decode the names of departments, tags and agents in file names, for tenants
+  /// returning them URL-encoded
+  pub fn set_decode_names(&mut self, decode: bool) {
+    self.decode_names = decode;
+  }
+
+  /// Name of a department, tag or agent in its file name
+  fn file_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+    match self.decode_names {
+      true => Cow::Owned(percent_decode(name)),
+      false => Cow::Borrowed(name),
+    }
+  }
+
+  /// Name the member files of departments and tags with `template`
+  pub fn set_name_template(&mut self, template: NameTemplate) {
+    self.name_template = template;
+  }
+
+  fn department_path(&self, department: &Department) -> PathBuf {
+    let (id, parent_id) = (department.id, department.parent_id);
+    let name = self.file_name(&department.name);
+    let name = self.name_template.render(id, &name, parent_id);
+    self.paths.item("departments", &name)
+  }
+
+  fn agent_path(&self, agent: &AgentBasic) -> PathBuf {
+    self.paths.item(
+      "agents",
+      &format!("agent-{}-{}.json", agent.id, self.file_name(&agent.name)).replace_special_char(),
+    )
+  }
+
+  /// Write `departments.json` and `tags.json` again with every department and tag in `dump`,
+  /// after merging with a previous run
+  pub fn rewrite_listings(&self, dump: &Dump) -> Result<()> {
+    if !dump.failed_jobs.contains(&Job::Departments) {
+      let resp = DepartmentResp {
+        code: Some(0),
+        msg: Some("ok".to_string()),
+        departments: dump.departments.clone(),
+      };
+      self
+        .sink
+        .write_json(&self.paths.top("departments.json"), &resp)?;
+    }
+    if !dump.failed_jobs.contains(&Job::Tags) {
+      let resp = TagsResp {
+        code: Some(0),
+        msg: Some("ok".to_string()),
+        tags: dump.tags.clone(),
+      };
+      self.sink.write_json(&self.paths.top("tags.json"), &resp)?;
+    }
+    Ok(())
+  }
+
+  /// Write the files summarizing a finished dump
+  pub fn finish(&self, dump: &Dump) -> Result<()> {
+    if !dump.failed_jobs.contains(&Job::Departments) {
+      let path = self.paths.top("departments_with_counts.json");
+      self.sink.write_json(&path, &dump.department_counts())?;
+      let anomalies = Anomalies::find(dump);
+      self
+        .sink
+        .write_json(&self.paths.top("anomalies.json"), &anomalies)?;
+      let no_permission: Vec<&Department> = dump
+        .departments
+        .iter()
+        .filter(|x| dump.no_permission_departments.contains(&x.id))
+        .collect();
+      let path = self.paths.item("departments", "_no_permission.json");
+      self.sink.write_json(&path, &no_permission)?;
+      let timed_out: Vec<&Department> = dump
+        .departments
+        .iter()
+        .filter(|x| dump.timed_out_departments.contains(&x.id))
+        .collect();
+      if !timed_out.is_empty() {
+        let path = self.paths.item("departments", "_timed_out.json");
+        self.sink.write_json(&path, &timed_out)?;
+      }
+    }
+    if !dump.failed_jobs.contains(&Job::Tags) {
+      let mut empty_tags = self
+        .empty_tags
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+      empty_tags.sort_by_key(|x| x.id);
+      let mut txt = String::from("These tags has no member:\n");
+      for x in empty_tags.iter() {
+        txt.push_str(&format!("{} - {}\n", x.id, x.name));
+      }
+      let path = self.paths.item("tags", "_empty.txt");
+      self.sink.write_text(&path, &txt)?;
+      let path = self.paths.item("tags", "_empty.json");
+      self.sink.write_json(&path, &*empty_tags)?;
+      let path = self.paths.top("tag_members_by_user.json");
+      self.sink.write_json(&path, &dump.tags_by_user())?;
+      if self.tags_union {
+        let path = self.paths.top("tags_union.json");
+        self.sink.write_json(&path, &dump.tag_union())?;
+      }
+    }
+    if self.resolve_agent_scopes {
+      self.write_resolved_agents(dump)?;
+    }
+    self.sink.finish()
+  }
+
+  fn write_resolved_agents(&self, dump: &Dump) -> Result<()> {
+    let departments: HashMap<u32, &str> =
+      dump.departments.iter().map(|x| (x.id, &*x.name)).collect();
+    let tags: HashMap<u32, &str> = dump.tags.iter().map(|x| (x.id, &*x.name)).collect();
+    for agent in &dump.agents {
+      let Some(detail) = dump.agent_details.get(&agent.id) else {
+        continue;
+      };
+      let parties = detail.allow_parties.as_ref().map(|x| &*x.party_id);
+      let tag_ids = detail.allow_tags.as_ref().map(|x| &*x.tag_id);
+      let resolved = ResolvedAgent {
+        detail,
+        allow_party_names: resolve(parties.unwrap_or_default(), &departments),
+        allow_tag_names: resolve(tag_ids.unwrap_or_default(), &tags),
+      };
+      self.sink.write_json(&self.agent_path(agent), &resolved)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+  use std::path::Path;
+
+  use chrono::Local;
+  use serde_json::json;
+
+  use crate::api::data::{DepartmentMembersResp, DepartmentResp, TagMembersResp, TagsResp};
+  use crate::api::dump::{Dump, DumpObserver};
+  use crate::layout::{Layout, Paths};
+  use crate::sink::FsSink;
+  use crate::util::TestDir;
+  use crate::writer::FileWriter;
+
+  fn files(dir: &Path, root: &Path, result: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+      let path = entry.unwrap().path();
+      if path.is_dir() {
+        files(&path, root, result);
+      } else {
+        let relative = path.strip_prefix(root).unwrap();
+        result.push(relative.to_string_lossy().replace('\\', "/"));
+      }
+    }
+  }
+
+  #[test]
+  fn fs_sink_layout_test() {
+    let dir = TestDir::new("writer");
+    let writer = FileWriter::new(Paths::new(Layout::Nested, Local::now()), FsSink::new(&*dir));
+
+    let departments: DepartmentResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok",
+      "department": [{"id": 1, "name": "Company", "parentid": 0, "order": 1}],
+    }))
+    .unwrap();
+    writer.on_departments(&departments).unwrap();
+    let members: DepartmentMembersResp =
+      serde_json::from_value(json!({"errcode": 0, "errmsg": "ok", "userlist": []})).unwrap();
+    writer.on_department_members(&departments.departments[0], &members);
+
+    let tags: TagsResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok",
+      "taglist": [{"tagid": 1, "tagname": "A"}, {"tagid": 2, "tagname": "B"}],
+    }))
+    .unwrap();
+    writer.on_tags(&tags).unwrap();
+    let tag_members: TagMembersResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok", "tagname": "A", "userlist": [], "partylist": [1],
+    }))
+    .unwrap();
+    writer.on_tag_members(&tags.tags[0], &tag_members);
+    let empty: TagMembersResp = serde_json::from_value(json!({
+      "errcode": 0, "errmsg": "ok", "tagname": "B", "userlist": [], "partylist": [],
+    }))
+    .unwrap();
+    writer.on_tag_members(&tags.tags[1], &empty);
+
+    let dump = Dump {
+      departments: departments.departments.clone(),
+      tags: tags.tags.clone(),
+      ..Default::default()
+    };
+    writer.finish(&dump).unwrap();
+
+    let mut result = Vec::new();
+    files(&dir, &dir, &mut result);
+    result.sort();
+    assert_eq!(
+      result,
+      [
+        "anomalies.json",
+        "departments.json",
+        "departments/_no_permission.json",
+        "departments/members-1-Company.json",
+        "departments_with_counts.json",
+        "tag_members_by_user.json",
+        "tags.json",
+        "tags/_empty.json",
+        "tags/_empty.txt",
+        "tags/members-1-A.json",
+      ]
+    );
+  }
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use rust_xlsxwriter::{Format, Workbook, Worksheet};
+
+use crate::api::dump::Dump;
+use crate::csv_export::{unique_members, MEMBER_HEADERS};
+
+const DEPARTMENT_HEADERS: [&str; 6] = ["id", "name", "parent", "order", "name_en", "leader"];
+const TAG_HEADERS: [&str; 2] = ["id", "name"];
+const TAG_MEMBER_HEADERS: [&str; 4] = ["tag_id", "tag_name", "user_id", "name"];
+
+/// Write a workbook with sheets: Departments, Members, Tags and TagMembers
+pub fn write_workbook(path: &Path, dump: &Dump) -> Result<()> {
+  let mut workbook = Workbook::new();
+
+  {
+    let sheet = new_sheet(&mut workbook, "Departments", &DEPARTMENT_HEADERS)?;
+    for (row, x) in (1..).zip(&dump.departments) {
+      sheet.write(row, 0, x.id)?;
+      sheet.write(row, 1, &x.name)?;
+      if let Some(parent_id) = x.parent_id {
+        sheet.write(row, 2, parent_id)?;
+      }
+      sheet.write(row, 3, x.order)?;
+      if let Some(name_en) = &x.name_en {
+        sheet.write(row, 4, name_en)?;
+      }
+      if let Some(department_leader) = &x.department_leader {
+        sheet.write(row, 5, department_leader.join(","))?;
+      }
+    }
+    sheet.autofit();
+  }
+
+  {
+    let sheet = new_sheet(&mut workbook, "Members", &MEMBER_HEADERS)?;
+    for (row, (x, department)) in (1..).zip(unique_members(dump)) {
+      sheet.write_row(
+        row,
+        0,
+        [
+          &x.user_id,
+          &x.name,
+          &x.alias,
+          &x.english_name,
+          &department.iter().join(","),
+        ],
+      )?;
+      if let Some(main_department) = x.main_department {
+        sheet.write(row, 5, main_department)?;
+      }
+      sheet.write_row(
+        row,
+        6,
+        [
+          &x.position,
+          &x.gender,
+          &x.mobile,
+          &x.telephone,
+          &x.email,
+          x.biz_mail.as_deref().unwrap_or_default(),
+        ],
+      )?;
+      sheet.write_row(row, 12, [x.is_leader, x.status, x.enable])?;
+      sheet.write_row(row, 15, [&x.avatar, &x.qr_code])?;
+    }
+    sheet.autofit();
+  }
+
+  {
+    let sheet = new_sheet(&mut workbook, "Tags", &TAG_HEADERS)?;
+    for (row, x) in (1..).zip(&dump.tags) {
+      sheet.write(row, 0, x.id)?;
+      sheet.write(row, 1, &x.name)?;
+    }
+    sheet.autofit();
+  }
+
+  {
+    let sheet = new_sheet(&mut workbook, "TagMembers", &TAG_MEMBER_HEADERS)?;
+    let rows = dump.tags.iter().flat_map(|tag| {
+      let members = dump.tag_members.get(&tag.id).into_iter().flatten();
+      members.map(move |member| (tag, member))
+    });
+    for (row, (tag, member)) in (1..).zip(rows) {
+      sheet.write(row, 0, tag.id)?;
+      sheet.write_row(row, 1, [&tag.name, &member.id, &member.name])?;
+    }
+    sheet.autofit();
+  }
+
+  workbook
+    .save(path)
+    .with_context(|| format!("Failed to save {}", path.to_string_lossy()))
+}
+
+fn new_sheet<'a>(
+  workbook: &'a mut Workbook,
+  name: &str,
+  headers: &[&str],
+) -> Result<&'a mut Worksheet> {
+  let bold = Format::new().set_bold();
+  let sheet = workbook.add_worksheet();
+  sheet.set_name(name)?;
+  sheet.write_row_with_format(0, 0, headers.iter().copied(), &bold)?;
+  sheet.set_freeze_panes(1, 0)?;
+  Ok(sheet)
+}